@@ -0,0 +1,116 @@
+/*
+ * C interface of the ID My Bee crop engine (libidmybee), built with `cargo build --release --lib`.
+ *
+ * Functions returning an IdMyBeeStatus take an optional `error_message`: when it is not NULL, it
+ * receives NULL on success and a message on failure, to free with idmybee_string_free.
+ * The settings are read from the same config files as idmybee_cli.
+ *
+ * An engine keeps its marker detector between the images. It must not be used by several
+ * threads at the same time, create one engine per thread.
+ *
+ *     IdMyBeeEngine *engine = NULL;
+ *     IdMyBeeResult *result = NULL;
+ *     char *error = NULL;
+ *     if (idmybee_engine_new(NULL, "forewing", &engine, &error) != IDMYBEE_OK) { ... }
+ *     if (idmybee_crop_file(engine, "photo.jpg", &result, &error) == IDMYBEE_ERROR_MARKERS_NOT_FOUND) { ... }
+ *     idmybee_result_free(result);
+ *     idmybee_engine_free(engine);
+ */
+#ifndef IDMYBEE_H
+#define IDMYBEE_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Returned by idmybee_abi_version, incremented when a declaration changes incompatibly */
+#define IDMYBEE_ABI_VERSION 1
+
+/* Status codes, the same as the exit codes of idmybee_cli */
+typedef int32_t IdMyBeeStatus;
+#define IDMYBEE_OK 0
+#define IDMYBEE_ERROR_OTHER 1
+#define IDMYBEE_ERROR_INVALID_INPUT 2 /* wrong argument, setting or preset */
+#define IDMYBEE_ERROR_READ 3 /* input missing or not decodable */
+#define IDMYBEE_ERROR_MARKERS_NOT_FOUND 4 /* less than the 4 markers needed for the correction */
+#define IDMYBEE_ERROR_INVALID_SIDECAR 5
+#define IDMYBEE_ERROR_WRITE 6 /* crop could not be encoded */
+
+typedef struct IdMyBeeEngine IdMyBeeEngine;
+typedef struct IdMyBeeResult IdMyBeeResult;
+
+/* Pixels of a crop, valid until the result is freed */
+typedef struct IdMyBeeImage {
+    const uint8_t *data;
+    int32_t width;
+    int32_t height;
+    int32_t channels; /* those of the input: 1 (gray), 3 (BGR) or 4 (BGRA) */
+    int32_t bytes_per_channel; /* depth of the input: 1 for 8 bit images, 2 for 16 bit */
+    size_t stride; /* bytes between the starts of two rows */
+} IdMyBeeImage;
+
+uint32_t idmybee_abi_version(void);
+/* Static string, e.g. "1.1.0" */
+const char *idmybee_version(void);
+
+/*
+ * Engine with the settings of the config files. `config_path` (may be NULL) is read after the
+ * other config files like --config, `preset` (may be NULL) names a [preset.<name>] section.
+ */
+IdMyBeeStatus idmybee_engine_new(const char *config_path, const char *preset, IdMyBeeEngine **engine,
+                                 char **error_message);
+/*
+ * Overrides a single setting, validated like the config files. Keys: out_x, out_y, zoom,
+ * crop_window ("x y width height"), interpolation, format, jpeg_quality, jpeg_subsampling,
+ * png_compression, tiff_compression.
+ */
+IdMyBeeStatus idmybee_engine_set(IdMyBeeEngine *engine, const char *key, const char *value, char **error_message);
+/* Accepts NULL */
+void idmybee_engine_free(IdMyBeeEngine *engine);
+
+/* Crops an image file, its EXIF orientation is applied. `*result` is NULL on failure. */
+IdMyBeeStatus idmybee_crop_file(const IdMyBeeEngine *engine, const char *path, IdMyBeeResult **result,
+                                char **error_message);
+/* Same as idmybee_crop_file for an encoded image (JPEG, PNG, TIFF...) in memory */
+IdMyBeeStatus idmybee_crop_buffer(const IdMyBeeEngine *engine, const uint8_t *data, size_t length,
+                                  IdMyBeeResult **result, char **error_message);
+
+IdMyBeeStatus idmybee_result_image(const IdMyBeeResult *result, IdMyBeeImage *image, char **error_message);
+/*
+ * Encodes the crop in `format` (e.g. "png", "jpg", "tif"). When `format` is NULL, the format of
+ * the settings is used, or the one of the input. Free `*data` with idmybee_buffer_free.
+ */
+IdMyBeeStatus idmybee_result_encode(const IdMyBeeResult *result, const char *format, uint8_t **data, size_t *length,
+                                    char **error_message);
+/* Output scale in pixels per millimeter */
+void idmybee_result_px_per_mm(const IdMyBeeResult *result, double *x, double *y);
+/* Perspective transform from the working image to the corrected image, 3 x 3 row major */
+void idmybee_result_homography(const IdMyBeeResult *result, double homography[9]);
+/* Outer corners of markers #0 to #3 in pixels of the input, x0 y0 x1 y1 ... */
+void idmybee_result_source_points(const IdMyBeeResult *result, float points[8]);
+size_t idmybee_result_marker_count(const IdMyBeeResult *result);
+/* Id and corners (x0 y0 ... x3 y3, in pixels of the input, `corners` may be NULL) of a marker */
+IdMyBeeStatus idmybee_result_marker(const IdMyBeeResult *result, size_t index, int32_t *id, float corners[8],
+                                    char **error_message);
+/* Variance of the Laplacian of the crop */
+double idmybee_result_sharpness(const IdMyBeeResult *result);
+/* Shortest side over longest side of the least square marker, NaN without markers */
+double idmybee_result_confidence(const IdMyBeeResult *result);
+/* Specimen ID of the QR code on the card, NULL when none was read, free with idmybee_string_free */
+char *idmybee_result_card_id(const IdMyBeeResult *result);
+/* Every field as JSON (same layout as idmybee_server), free with idmybee_string_free */
+char *idmybee_result_json(const IdMyBeeResult *result);
+/* Accepts NULL */
+void idmybee_result_free(IdMyBeeResult *result);
+
+void idmybee_string_free(char *string);
+void idmybee_buffer_free(uint8_t *data, size_t length);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* IDMYBEE_H */
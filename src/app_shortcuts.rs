@@ -1,6 +1,8 @@
 use configparser::ini::Ini;
 use egui::Key;
-use ucfirst::ucfirst;
+
+use idmybee::config_schema::parse_shortcut;
+
 pub struct AppShortcuts {
     pub crop_image: (Key, String),
     pub decrease_zoom: (Key, String),
@@ -13,27 +15,24 @@ pub struct AppShortcuts {
 }
 
 impl AppShortcuts {
-    fn conf_to_key(config: &Ini, key_name: &str, default_key: &str) -> (Key, String) {
-        let key_str = ucfirst(
-            &config
-                .get("shortcuts", key_name)
-                .unwrap_or(String::from(default_key)),
-        );
-
-        let k: Key = serde_json::from_str(&format!("\"{}\"", key_str)).unwrap();
-        (k, key_str)
+    /// Invalid keys were already reported by the config validation, they fall back to the default
+    fn conf_to_key(config: &Ini, key_name: &str, default_key: Key) -> (Key, String) {
+        config
+            .get("shortcuts", key_name)
+            .and_then(|key_str| parse_shortcut(&key_str))
+            .unwrap_or((default_key, format!("{:?}", default_key)))
     }
 
     pub fn new(config: &Ini) -> AppShortcuts {
         AppShortcuts {
-            next_file: AppShortcuts::conf_to_key(config, "nex_file", "S"),
-            previous_file: AppShortcuts::conf_to_key(config, "previous_file", "Z"),
-            increase_zoom: AppShortcuts::conf_to_key(config, "increase_zoom", "D"),
-            decrease_zoom: AppShortcuts::conf_to_key(config, "decrease_zoom", "Q"),
-            crop_image: AppShortcuts::conf_to_key(config, "crop_image", "Space"),
-            select_input_dir: AppShortcuts::conf_to_key(config, "select_input_dir", "F"),
-            select_output_dir: AppShortcuts::conf_to_key(config, "select_output_dir", "V"),
-            save_crop_image: AppShortcuts::conf_to_key(config, "save_crop_image", "R"),
+            next_file: AppShortcuts::conf_to_key(config, "next_file", Key::S),
+            previous_file: AppShortcuts::conf_to_key(config, "previous_file", Key::Z),
+            increase_zoom: AppShortcuts::conf_to_key(config, "increase_zoom", Key::D),
+            decrease_zoom: AppShortcuts::conf_to_key(config, "decrease_zoom", Key::Q),
+            crop_image: AppShortcuts::conf_to_key(config, "crop_image", Key::Space),
+            select_input_dir: AppShortcuts::conf_to_key(config, "select_input_dir", Key::F),
+            select_output_dir: AppShortcuts::conf_to_key(config, "select_output_dir", Key::V),
+            save_crop_image: AppShortcuts::conf_to_key(config, "save_crop_image", Key::R),
         }
     }
 }
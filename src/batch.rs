@@ -0,0 +1,257 @@
+use anyhow::Result;
+use std::{
+    any::Any,
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Condvar, Mutex,
+    },
+};
+
+use crate::report::{ImageRecord, RecordStatus};
+
+fn has_allowed_extension(path: &Path, extensions: &[&str]) -> bool {
+    extensions.contains(
+        &path
+            .extension()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_lowercase()
+            .as_str(),
+    )
+}
+
+fn collect_dir(
+    dir: &Path,
+    recursive: bool,
+    extensions: &[&str],
+    inputs: &mut Vec<PathBuf>,
+) -> Result<()> {
+    let mut entries = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .collect::<Vec<PathBuf>>();
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            if recursive {
+                collect_dir(&path, recursive, extensions, inputs)?;
+            }
+        } else if path.is_file() && has_allowed_extension(&path, extensions) {
+            inputs.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Expands input arguments into a sorted list of files. An argument can be a file (always kept),
+/// a directory (files with an allowed extension, subdirectories only if `recursive`) or a glob
+/// pattern such as `photos/**/*.jpg`.
+pub fn collect_inputs(
+    args: &[PathBuf],
+    recursive: bool,
+    extensions: &[&str],
+) -> Result<Vec<PathBuf>> {
+    let mut inputs = Vec::new();
+    for path in args {
+        if path.is_file() {
+            inputs.push(path.clone());
+        } else if path.is_dir() {
+            collect_dir(path, recursive, extensions, &mut inputs)?;
+        } else if let Some(pattern) = path.to_str().filter(|arg| arg.contains(['*', '?', '['])) {
+            let mut matched = false;
+            for entry in glob::glob(pattern)? {
+                let entry = entry?;
+                if entry.is_dir() && recursive {
+                    collect_dir(&entry, recursive, extensions, &mut inputs)?;
+                } else if entry.is_file() && has_allowed_extension(&entry, extensions) {
+                    inputs.push(entry);
+                } else {
+                    continue;
+                }
+                matched = true;
+            }
+            if !matched {
+                log::warn!("No image matched the pattern {pattern:?}");
+            }
+        } else {
+            return Err(anyhow::anyhow!(
+                "Input {path:?} is neither a file, a directory nor a glob pattern"
+            ));
+        }
+    }
+
+    // Same file given twice (e.g. directory and pattern) is processed once
+    let mut seen = std::collections::HashSet::new();
+    inputs.retain(|path| seen.insert(dunce::canonicalize(path).unwrap_or(path.clone())));
+    Ok(inputs)
+}
+
+/// Results of a batch run, printed once every input was processed
+#[derive(Default)]
+pub struct BatchSummary {
+    pub succeeded: Vec<PathBuf>,
+    pub skipped: Vec<PathBuf>,
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+impl BatchSummary {
+    pub fn add(&mut self, record: &ImageRecord) {
+        let input = PathBuf::from(&record.input);
+        match record.status {
+            RecordStatus::Processed => self.succeeded.push(input),
+            RecordStatus::Skipped => self.skipped.push(input),
+            RecordStatus::Failed => {
+                let reason = record.error_message.clone().unwrap_or_default();
+                self.failed.push((input, reason));
+            }
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.succeeded.len() + self.skipped.len() + self.failed.len()
+    }
+
+    pub fn print(&self) {
+        log::info!(
+            "Processed {} image(s): {} succeeded, {} skipped (already up to date), {} failed",
+            self.total(),
+            self.succeeded.len(),
+            self.skipped.len(),
+            self.failed.len()
+        );
+        for (input, reason) in self.failed.iter() {
+            // First line only, the full message was logged when the error occurred
+            log::info!(
+                "  {:?}: {}",
+                input,
+                reason.lines().next().unwrap_or_default()
+            );
+        }
+    }
+}
+
+/// Estimated memory needed to process an image: the decoded image, its upscaled/gray copies
+/// and the warped image are all alive at the same time
+pub fn estimate_image_memory(path: &Path) -> u64 {
+    match image::image_dimensions(path) {
+        Ok((width, height)) => width as u64 * height as u64 * 3 * 4,
+        // Unknown header, compressed files are rarely more than 8 times smaller than decoded
+        Err(_) => std::fs::metadata(path)
+            .map(|m| m.len() * 8 * 4)
+            .unwrap_or(0),
+    }
+}
+
+/// Limits the memory used by the images processed at the same time. An image larger than the
+/// whole budget is still processed, but alone.
+pub struct MemoryBudget {
+    limit: u64,
+    used: Mutex<u64>,
+    released: Condvar,
+}
+
+pub struct MemoryReservation<'a> {
+    budget: &'a MemoryBudget,
+    amount: u64,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: u64) -> Self {
+        MemoryBudget {
+            limit,
+            used: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    pub fn reserve(&self, amount: u64) -> MemoryReservation<'_> {
+        let mut used = self.used.lock().unwrap();
+        while *used > 0 && *used + amount > self.limit {
+            used = self.released.wait(used).unwrap();
+        }
+        *used += amount;
+        MemoryReservation {
+            budget: self,
+            amount,
+        }
+    }
+}
+
+impl Drop for MemoryReservation<'_> {
+    fn drop(&mut self) {
+        let mut used = self.budget.used.lock().unwrap();
+        *used -= self.amount;
+        self.budget.released.notify_all();
+    }
+}
+
+/// Message of a caught panic, its payload is a `&str` or a `String` when it comes from `panic!`
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| String::from("unknown panic"))
+}
+
+/// Runs `process` on every input with `jobs` worker threads, with the index of the input in
+/// `inputs`, and calls `emit` with each record as soon as it is done. An input whose processing
+/// panics gets a failed record instead of stopping the batch. The records keep the order of
+/// `inputs` whatever the scheduling.
+pub fn run_batch<F, E>(
+    inputs: &[PathBuf],
+    jobs: usize,
+    memory_budget: &MemoryBudget,
+    process: F,
+    emit: E,
+) -> Vec<ImageRecord>
+where
+    F: Fn(usize, &Path) -> ImageRecord + Sync,
+    E: Fn(&ImageRecord) + Sync,
+{
+    let next_input = AtomicUsize::new(0);
+    let records: Mutex<Vec<Option<ImageRecord>>> =
+        Mutex::new((0..inputs.len()).map(|_| None).collect());
+
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, inputs.len().max(1)) {
+            scope.spawn(|| loop {
+                let index = next_input.fetch_add(1, Ordering::SeqCst);
+                let Some(input_path) = inputs.get(index) else {
+                    break;
+                };
+                let _reservation = memory_budget.reserve(estimate_image_memory(input_path));
+                log::info!(
+                    "[{}/{}] Processing {:?}",
+                    index + 1,
+                    inputs.len(),
+                    input_path
+                );
+                // OpenCV and the image decoders may panic on unexpected inputs
+                let processed =
+                    catch_unwind(AssertUnwindSafe(|| process(index, input_path.as_path())));
+                let record = processed.unwrap_or_else(|payload| {
+                    let err = anyhow::anyhow!(
+                        "Unexpected error (panic): {}",
+                        panic_message(payload.as_ref())
+                    );
+                    log::error!("Failed to process {:?}: {}", input_path, err);
+                    ImageRecord::failed(input_path, &err)
+                });
+                emit(&record);
+                records.lock().unwrap()[index] = Some(record);
+            });
+        }
+    });
+
+    // Every index is filled once the scope has joined all workers
+    records
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|record| record.expect("input left unprocessed by the workers"))
+        .collect()
+}
@@ -0,0 +1,305 @@
+use anyhow::Result;
+use base64::Engine;
+use opencv::{
+    core::{Mat, Size, Vector, CV_16U, CV_8U},
+    imgcodecs, imgproc,
+    prelude::*,
+};
+use std::{collections::BTreeMap, fmt::Write as _, path::Path};
+
+use crate::report::{duplicate_card_ids, ImageRecord, RecordStatus};
+use idmybee::image_io::{encode_image, read_image};
+
+/// Longest side of the thumbnails embedded in the HTML report
+const THUMBNAIL_SIZE: i32 = 160;
+
+const CSV_HEADER: &str =
+    "input,card_id,status,error_kind,error_message,marker_count,px_per_mm_x,px_per_mm_y,sharpness,confidence,outputs,duration_ms";
+
+const HTML_STYLE: &str = "body { font-family: sans-serif; margin: 2em; color: #222; }
+table { border-collapse: collapse; margin-bottom: 2em; }
+th, td { border: 1px solid #ccc; padding: 4px 8px; text-align: left; vertical-align: top; }
+th { background: #f0f0f0; }
+td.number { text-align: right; }
+img { max-width: 160px; max-height: 160px; }
+.processed { color: #1a7f37; }
+.skipped { color: #777; }
+.failed { color: #c62828; }";
+
+fn status_name(status: RecordStatus) -> &'static str {
+    match status {
+        RecordStatus::Processed => "processed",
+        RecordStatus::Skipped => "skipped",
+        RecordStatus::Failed => "failed",
+    }
+}
+
+fn optional_number(value: Option<f64>, decimals: usize) -> String {
+    value
+        .map(|value| format!("{value:.decimals$}"))
+        .unwrap_or_default()
+}
+
+fn csv_field(value: &str) -> String {
+    match value.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value.to_string(),
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Skipped inputs count as successes, they were processed by a previous run
+fn success_rate(succeeded: usize, total: usize) -> String {
+    match total {
+        0 => String::from("-"),
+        _ => format!("{:.1} %", 100. * succeeded as f64 / total as f64),
+    }
+}
+
+/// One row per input, the outputs of several zoom levels are separated by ';'
+pub fn write_csv(records: &[ImageRecord], path: &Path) -> Result<()> {
+    let mut csv = format!("{CSV_HEADER}\n");
+    for record in records.iter() {
+        let px_per_mm = record.px_per_mm.first();
+        let fields = [
+            record.input.clone(),
+            record.card_id.clone().unwrap_or_default(),
+            status_name(record.status).to_string(),
+            record
+                .error_kind
+                .map(|kind| kind.to_string())
+                .unwrap_or_default(),
+            record.error_message.clone().unwrap_or_default(),
+            record.marker_ids.len().to_string(),
+            optional_number(px_per_mm.map(|[x, _]| *x), 3),
+            optional_number(px_per_mm.map(|[_, y]| *y), 3),
+            optional_number(record.sharpness, 1),
+            optional_number(record.confidence, 3),
+            record.outputs.join(";"),
+            record.duration_ms.to_string(),
+        ];
+        let row: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+    std::fs::write(path, csv)?;
+    Ok(())
+}
+
+fn encode_thumbnail(img: &Mat) -> Result<Vec<u8>> {
+    let mut img_8u = Mat::default();
+    match img.depth() {
+        CV_8U => img_8u = img.clone(),
+        CV_16U => img.convert_to(&mut img_8u, CV_8U, 1. / 256., 0.)?,
+        // Floating point images are expected between 0 and 1
+        _ => img.convert_to(&mut img_8u, CV_8U, 255., 0.)?,
+    }
+    let size = img_8u.size()?;
+    let scale = f64::min(
+        1.,
+        THUMBNAIL_SIZE as f64 / size.width.max(size.height).max(1) as f64,
+    );
+    let mut thumbnail = Mat::default();
+    imgproc::resize(
+        &img_8u,
+        &mut thumbnail,
+        Size::default(),
+        scale,
+        scale,
+        imgproc::INTER_AREA,
+    )?;
+    encode_image(
+        &thumbnail,
+        "jpg",
+        &Vector::from_slice(&[imgcodecs::IMWRITE_JPEG_QUALITY, 80]),
+    )
+}
+
+/// Thumbnail as a JPEG data URI, `None` when the file is not a readable image (e.g. a sidecar)
+fn thumbnail_data_uri(path: &Path) -> Option<String> {
+    let (img, _) = read_image(path).ok()?;
+    let buffer = encode_thumbnail(&img).ok()?;
+    Some(format!(
+        "data:image/jpeg;base64,{}",
+        base64::engine::general_purpose::STANDARD.encode(buffer)
+    ))
+}
+
+fn thumbnail_cell(path: Option<&str>) -> String {
+    match path.and_then(|path| thumbnail_data_uri(Path::new(path))) {
+        Some(data_uri) => format!("<td><img src=\"{data_uri}\" alt=\"\"></td>"),
+        None => String::from("<td></td>"),
+    }
+}
+
+/// Self-contained page (thumbnails are embedded) with the overall and per folder success
+/// rates, the failures grouped by error kind and a row per input
+pub fn write_html(records: &[ImageRecord], path: &Path) -> Result<()> {
+    let is_success = |record: &ImageRecord| record.status != RecordStatus::Failed;
+    let succeeded = records.iter().filter(|record| is_success(record)).count();
+    let count = |status: RecordStatus| {
+        records
+            .iter()
+            .filter(|record| record.status == status)
+            .count()
+    };
+
+    let mut html = String::new();
+    writeln!(
+        html,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">"
+    )?;
+    writeln!(
+        html,
+        "<title>ID My Bee batch report</title>\n<style>\n{HTML_STYLE}\n</style>\n</head>\n<body>"
+    )?;
+    writeln!(html, "<h1>ID My Bee batch report</h1>")?;
+    writeln!(
+        html,
+        "<p>Generated on {} by idmybee {}</p>",
+        chrono::Local::now().format("%Y-%m-%d %H:%M"),
+        env!("CARGO_PKG_VERSION")
+    )?;
+
+    writeln!(html, "<h2>Summary</h2>\n<table>")?;
+    writeln!(
+        html,
+        "<tr><th>Images</th><td class=\"number\">{}</td></tr>",
+        records.len()
+    )?;
+    writeln!(
+        html,
+        "<tr><th>Processed</th><td class=\"number\">{}</td></tr>",
+        count(RecordStatus::Processed)
+    )?;
+    writeln!(
+        html,
+        "<tr><th>Skipped (up to date)</th><td class=\"number\">{}</td></tr>",
+        count(RecordStatus::Skipped)
+    )?;
+    writeln!(
+        html,
+        "<tr><th>Failed</th><td class=\"number\">{}</td></tr>",
+        count(RecordStatus::Failed)
+    )?;
+    writeln!(
+        html,
+        "<tr><th>Success rate</th><td class=\"number\">{}</td></tr>\n</table>",
+        success_rate(succeeded, records.len())
+    )?;
+
+    let mut folders: BTreeMap<String, (usize, usize)> = BTreeMap::new();
+    for record in records.iter() {
+        let folder = match Path::new(&record.input).parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.display().to_string(),
+            _ => String::from("."),
+        };
+        let (folder_succeeded, folder_total) = folders.entry(folder).or_default();
+        *folder_succeeded += is_success(record) as usize;
+        *folder_total += 1;
+    }
+    writeln!(html, "<h2>Folders</h2>\n<table>")?;
+    writeln!(
+        html,
+        "<tr><th>Folder</th><th>Images</th><th>Failed</th><th>Success rate</th></tr>"
+    )?;
+    for (folder, (folder_succeeded, folder_total)) in folders.iter() {
+        writeln!(
+            html,
+            "<tr><td>{}</td><td class=\"number\">{}</td><td class=\"number\">{}</td><td class=\"number\">{}</td></tr>",
+            escape_html(folder),
+            folder_total,
+            folder_total - folder_succeeded,
+            success_rate(*folder_succeeded, *folder_total)
+        )?;
+    }
+    writeln!(html, "</table>")?;
+
+    let mut failures: BTreeMap<String, Vec<&ImageRecord>> = BTreeMap::new();
+    for record in records.iter().filter(|record| !is_success(record)) {
+        let kind = record
+            .error_kind
+            .map(|kind| kind.to_string())
+            .unwrap_or(String::from("other"));
+        failures.entry(kind).or_default().push(record);
+    }
+    let duplicates = duplicate_card_ids(records);
+    if !duplicates.is_empty() {
+        writeln!(html, "<h2>Duplicate card IDs</h2>\n<ul>")?;
+        for (card_id, inputs) in duplicates.iter() {
+            let inputs: Vec<String> = inputs.iter().map(|input| escape_html(input)).collect();
+            writeln!(
+                html,
+                "<li>{}: {}</li>",
+                escape_html(card_id),
+                inputs.join(", ")
+            )?;
+        }
+        writeln!(html, "</ul>")?;
+    }
+
+    if !failures.is_empty() {
+        writeln!(html, "<h2>Failures</h2>")?;
+        for (kind, failed) in failures.iter() {
+            writeln!(
+                html,
+                "<h3>{} ({})</h3>\n<ul>",
+                escape_html(kind),
+                failed.len()
+            )?;
+            for record in failed.iter() {
+                let message = record.error_message.as_deref().unwrap_or_default();
+                writeln!(
+                    html,
+                    "<li>{}: {}</li>",
+                    escape_html(&record.input),
+                    // First line only, the next ones are hints shared by every failure of the kind
+                    escape_html(message.lines().next().unwrap_or_default())
+                )?;
+            }
+            writeln!(html, "</ul>")?;
+        }
+    }
+
+    writeln!(html, "<h2>Images</h2>\n<table>")?;
+    writeln!(
+        html,
+        "<tr><th>Before</th><th>After</th><th>Input</th><th>Card ID</th><th>Status</th><th>Markers</th><th>px/mm</th><th>Sharpness</th><th>Confidence</th><th>Outputs</th></tr>"
+    )?;
+    for record in records.iter() {
+        let status = status_name(record.status);
+        let status_text = match record.error_kind {
+            Some(kind) => format!("{status} ({kind})"),
+            None => status.to_string(),
+        };
+        let px_per_mm = record
+            .px_per_mm
+            .first()
+            .map(|[x, y]| format!("{x:.2} x {y:.2}"))
+            .unwrap_or_default();
+        writeln!(
+            html,
+            "<tr>{}{}<td>{}</td><td>{}</td><td class=\"{status}\">{}</td><td class=\"number\">{}</td><td class=\"number\">{}</td><td class=\"number\">{}</td><td class=\"number\">{}</td><td>{}</td></tr>",
+            thumbnail_cell(Some(record.input.as_str())),
+            thumbnail_cell(record.outputs.first().map(String::as_str)),
+            escape_html(&record.input),
+            escape_html(record.card_id.as_deref().unwrap_or_default()),
+            escape_html(&status_text),
+            record.marker_ids.len(),
+            px_per_mm,
+            optional_number(record.sharpness, 1),
+            optional_number(record.confidence, 3),
+            record.outputs.iter().map(|output| escape_html(output)).collect::<Vec<_>>().join("<br>")
+        )?;
+    }
+    writeln!(html, "</table>\n</body>\n</html>")?;
+
+    std::fs::write(path, html)?;
+    Ok(())
+}
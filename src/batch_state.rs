@@ -0,0 +1,130 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::UNIX_EPOCH,
+};
+
+use idmybee::sidecar::CropSidecar;
+
+pub const DEFAULT_STATE_FILENAME: &str = ".idmybee_state.ndjson";
+
+/// How an input is recognised as unchanged since its last processing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputKey {
+    SizeAndMtime,
+    ContentHash,
+}
+
+/// One successfully processed input, a line of the state file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StateEntry {
+    pub input_path: String,
+    pub input_key: String,
+    pub params_fingerprint: String,
+    pub outputs: Vec<String>,
+    pub timestamp: String,
+}
+
+/// State of a batch run, kept in an append-only NDJSON file: every processed image adds a line
+/// as soon as it is done, so an interrupted run loses nothing and the last line of an input wins.
+pub struct BatchState {
+    path: PathBuf,
+    input_key: InputKey,
+    entries: HashMap<String, StateEntry>,
+    file: Mutex<File>,
+}
+
+impl BatchState {
+    pub fn open(path: &Path, input_key: InputKey) -> Result<Self> {
+        let mut entries = HashMap::new();
+        if path.is_file() {
+            for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                // A line cut by an interruption is ignored, its image will be processed again
+                match serde_json::from_str::<StateEntry>(&line) {
+                    Ok(entry) => {
+                        entries.insert(entry.input_path.clone(), entry);
+                    }
+                    Err(err) => log::warn!("Ignoring line {} of {:?}: {}", i + 1, path, err),
+                }
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(BatchState {
+            path: path.to_path_buf(),
+            input_key,
+            entries,
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn state_key(input_path: &Path) -> String {
+        dunce::canonicalize(input_path)
+            .unwrap_or(input_path.to_path_buf())
+            .display()
+            .to_string()
+    }
+
+    pub fn input_key(&self, input_path: &Path) -> Result<String> {
+        match self.input_key {
+            InputKey::SizeAndMtime => {
+                let metadata = std::fs::metadata(input_path)?;
+                let mtime = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+                Ok(format!("{}:{}", metadata.len(), mtime.as_nanos()))
+            }
+            InputKey::ContentHash => CropSidecar::hash_file(input_path),
+        }
+    }
+
+    /// Whether the input was already processed with the same parameters and its outputs still exist
+    pub fn is_done(&self, input_path: &Path, params_fingerprint: &str) -> bool {
+        let Some(entry) = self.entries.get(&BatchState::state_key(input_path)) else {
+            return false;
+        };
+        entry.params_fingerprint == params_fingerprint
+            && self
+                .input_key(input_path)
+                .is_ok_and(|key| key == entry.input_key)
+            && entry
+                .outputs
+                .iter()
+                .all(|output| Path::new(output).is_file())
+    }
+
+    pub fn record(
+        &self,
+        input_path: &Path,
+        params_fingerprint: &str,
+        outputs: &[String],
+    ) -> Result<()> {
+        let entry = StateEntry {
+            input_path: BatchState::state_key(input_path),
+            input_key: self.input_key(input_path)?,
+            params_fingerprint: String::from(params_fingerprint),
+            outputs: outputs.to_vec(),
+            timestamp: chrono::Local::now().to_rfc3339(),
+        };
+        let mut file = self.file.lock().unwrap();
+        writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+/// Short hash identifying a set of processing parameters
+pub fn fingerprint(params: &str) -> String {
+    format!("{:x}", Sha256::digest(params.as_bytes()))[..16].to_string()
+}
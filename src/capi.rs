@@ -0,0 +1,508 @@
+//! C ABI of the crop engine, declared in `include/idmybee.h` where the contract of each function
+//! is documented. Functions return the exit codes of idmybee_cli as status and never unwind
+//! into the caller.
+#![allow(clippy::missing_safety_doc)]
+
+use anyhow::Result;
+use opencv::{core::Mat, objdetect::ArucoDetector, prelude::*};
+use serde::Serialize;
+use std::{
+    ffi::{c_char, CStr, CString},
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::PathBuf,
+    ptr,
+};
+
+use crate::crop::{crop_image, CropParameters, CropResult};
+use crate::encoding::EncodingOptions;
+use crate::image_io::{decode_image, encode_image, guess_extension, path_extension, read_image};
+use crate::marker_utils::marker_processing::*;
+use crate::pipeline_error::{error_kind, kind_error, ErrorKind, WithErrorKind};
+use crate::settings::Settings;
+
+/// Incremented when a declaration of `include/idmybee.h` changes incompatibly
+pub const IDMYBEE_ABI_VERSION: u32 = 1;
+pub const IDMYBEE_OK: i32 = 0;
+
+/// Settings and marker detector, one per thread
+pub struct IdMyBeeEngine {
+    settings: Settings,
+    detector: ArucoDetector,
+}
+
+pub struct IdMyBeeResult {
+    crop: CropResult,
+    homography: [[f64; 3]; 3],
+    parameters: CropParameters,
+    encoding: EncodingOptions,
+    /// Format of the input, encodes the crop when neither the caller nor the settings give one
+    input_extension: String,
+    input_size: [i32; 2],
+    px_per_mm: [f64; 2],
+    sharpness: f64,
+    confidence: Option<f64>,
+}
+
+/// Pixels of a crop, valid until the result is freed
+#[repr(C)]
+pub struct IdMyBeeImage {
+    pub data: *const u8,
+    pub width: i32,
+    pub height: i32,
+    /// Those of the input: 1 (gray), 3 (BGR) or 4 (BGRA)
+    pub channels: i32,
+    /// Depth of the input: 1 for 8 bit images, 2 for 16 bit
+    pub bytes_per_channel: i32,
+    /// Bytes between the starts of two rows
+    pub stride: usize,
+}
+
+#[derive(Serialize)]
+struct ResultJson<'a> {
+    marker_ids: &'a [i32],
+    marker_corners: &'a [[[f32; 2]; 4]],
+    card_id: Option<&'a str>,
+    source_points: [[f32; 2]; 4],
+    homography: [[f64; 3]; 3],
+    out_size: [i32; 2],
+    zoom: f32,
+    crop_window: [i32; 4],
+    interpolation: String,
+    px_per_mm: [f64; 2],
+    diagnostics: DiagnosticsJson,
+}
+
+#[derive(Serialize)]
+struct DiagnosticsJson {
+    input_size: [i32; 2],
+    working_scale: [f64; 2],
+    markers_found: usize,
+    rejected_candidates: usize,
+    confidence: Option<f64>,
+    sharpness: f64,
+}
+
+/// Runs `call`, writes the message of a failure in `error_message` when it is not NULL and
+/// returns the status
+unsafe fn ffi_call(error_message: *mut *mut c_char, call: impl FnOnce() -> Result<()>) -> i32 {
+    if !error_message.is_null() {
+        *error_message = ptr::null_mut();
+    }
+    let result = catch_unwind(AssertUnwindSafe(call))
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("Unexpected panic in the crop engine")));
+    match result {
+        Ok(()) => IDMYBEE_OK,
+        Err(err) => {
+            if !error_message.is_null() {
+                *error_message = c_string(&err.to_string());
+            }
+            error_kind(&err).exit_code()
+        }
+    }
+}
+
+fn c_string(value: &str) -> *mut c_char {
+    CString::new(value.replace('\0', " "))
+        .unwrap_or_default()
+        .into_raw()
+}
+
+unsafe fn reference<'a, T>(pointer: *const T, name: &str) -> Result<&'a T> {
+    pointer
+        .as_ref()
+        .ok_or_else(|| kind_error(ErrorKind::InvalidInput, format!("{name} is NULL")))
+}
+
+unsafe fn out_reference<'a, T>(pointer: *mut T, name: &str) -> Result<&'a mut T> {
+    pointer
+        .as_mut()
+        .ok_or_else(|| kind_error(ErrorKind::InvalidInput, format!("{name} is NULL")))
+}
+
+/// `None` for NULL
+unsafe fn str_arg<'a>(value: *const c_char, name: &str) -> Result<Option<&'a str>> {
+    if value.is_null() {
+        return Ok(None);
+    }
+    CStr::from_ptr(value).to_str().map(Some).map_err(|_| {
+        kind_error(
+            ErrorKind::InvalidInput,
+            format!("{name} is not valid UTF-8"),
+        )
+    })
+}
+
+/// `None` for NULL, paths are taken as raw bytes where the platform allows it
+#[cfg_attr(unix, allow(unused_variables))]
+unsafe fn path_arg(value: *const c_char, name: &str) -> Result<Option<PathBuf>> {
+    if value.is_null() {
+        return Ok(None);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Ok(Some(PathBuf::from(std::ffi::OsStr::from_bytes(
+            CStr::from_ptr(value).to_bytes(),
+        ))))
+    }
+    #[cfg(not(unix))]
+    {
+        Ok(str_arg(value, name)?.map(PathBuf::from))
+    }
+}
+
+fn crop_result(
+    engine: &IdMyBeeEngine,
+    img: Mat,
+    input_extension: String,
+) -> Result<Box<IdMyBeeResult>> {
+    let settings = &engine.settings;
+    let input_size = img.size()?;
+    let parameters = CropParameters {
+        out_size: settings.out_size,
+        zoom: settings.zoom,
+        crop_window: settings.crop_window,
+        interpolation: settings.interpolation,
+    };
+    let crop = crop_image(&engine.detector, img, &parameters)?;
+    let (px_per_mm_x, px_per_mm_y) = settings
+        .card_layout
+        .px_per_mm(&settings.out_size, settings.zoom);
+    Ok(Box::new(IdMyBeeResult {
+        homography: matrix_to_array(&crop.homography)?,
+        sharpness: sharpness(&crop.image)?,
+        confidence: markers_confidence(&crop.marker_corners),
+        crop,
+        parameters,
+        encoding: settings.encoding.clone(),
+        input_extension,
+        input_size: [input_size.width, input_size.height],
+        px_per_mm: [px_per_mm_x, px_per_mm_y],
+    }))
+}
+
+#[no_mangle]
+pub extern "C" fn idmybee_abi_version() -> u32 {
+    IDMYBEE_ABI_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn idmybee_version() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn idmybee_engine_new(
+    config_path: *const c_char,
+    preset: *const c_char,
+    engine: *mut *mut IdMyBeeEngine,
+    error_message: *mut *mut c_char,
+) -> i32 {
+    ffi_call(error_message, || {
+        let engine = out_reference(engine, "engine")?;
+        *engine = ptr::null_mut();
+        let config_path = path_arg(config_path, "config_path")?;
+        let mut settings =
+            Settings::load(config_path.as_deref()).with_kind(ErrorKind::InvalidInput)?;
+        settings.log_issues();
+        if let Some(preset) = str_arg(preset, "preset")? {
+            settings = settings
+                .with_preset(preset)
+                .with_kind(ErrorKind::InvalidInput)?;
+        }
+        let detector = new_marker_detector()?;
+        *engine = Box::into_raw(Box::new(IdMyBeeEngine { settings, detector }));
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn idmybee_engine_set(
+    engine: *mut IdMyBeeEngine,
+    key: *const c_char,
+    value: *const c_char,
+    error_message: *mut *mut c_char,
+) -> i32 {
+    ffi_call(error_message, || {
+        let engine = out_reference(engine, "engine")?;
+        let key = str_arg(key, "key")?
+            .ok_or_else(|| kind_error(ErrorKind::InvalidInput, "key is NULL"))?;
+        let value = str_arg(value, "value")?
+            .ok_or_else(|| kind_error(ErrorKind::InvalidInput, "value is NULL"))?;
+        engine
+            .settings
+            .set_parameter(key, value)
+            .with_kind(ErrorKind::InvalidInput)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn idmybee_engine_free(engine: *mut IdMyBeeEngine) {
+    if !engine.is_null() {
+        drop(Box::from_raw(engine));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn idmybee_crop_file(
+    engine: *const IdMyBeeEngine,
+    path: *const c_char,
+    result: *mut *mut IdMyBeeResult,
+    error_message: *mut *mut c_char,
+) -> i32 {
+    ffi_call(error_message, || {
+        let result = out_reference(result, "result")?;
+        *result = ptr::null_mut();
+        let engine = reference(engine, "engine")?;
+        let path = path_arg(path, "path")?
+            .ok_or_else(|| kind_error(ErrorKind::InvalidInput, "path is NULL"))?;
+        let (img, _) = read_image(&path).with_kind(ErrorKind::Read)?;
+        *result = Box::into_raw(crop_result(engine, img, path_extension(&path))?);
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn idmybee_crop_buffer(
+    engine: *const IdMyBeeEngine,
+    data: *const u8,
+    length: usize,
+    result: *mut *mut IdMyBeeResult,
+    error_message: *mut *mut c_char,
+) -> i32 {
+    ffi_call(error_message, || {
+        let result = out_reference(result, "result")?;
+        *result = ptr::null_mut();
+        let engine = reference(engine, "engine")?;
+        if data.is_null() || length == 0 {
+            return Err(kind_error(
+                ErrorKind::InvalidInput,
+                "The image buffer is empty",
+            ));
+        }
+        let bytes = std::slice::from_raw_parts(data, length);
+        let (img, _) = decode_image(bytes).with_kind(ErrorKind::Read)?;
+        let input_extension = guess_extension(bytes).unwrap_or(String::from("png"));
+        *result = Box::into_raw(crop_result(engine, img, input_extension)?);
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn idmybee_result_image(
+    result: *const IdMyBeeResult,
+    image: *mut IdMyBeeImage,
+    error_message: *mut *mut c_char,
+) -> i32 {
+    ffi_call(error_message, || {
+        let result = reference(result, "result")?;
+        let image = out_reference(image, "image")?;
+        let crop = &result.crop.image;
+        *image = IdMyBeeImage {
+            data: crop.data(),
+            width: crop.cols(),
+            height: crop.rows(),
+            channels: crop.channels(),
+            bytes_per_channel: crop.elem_size1()? as i32,
+            stride: crop.step1(0)? * crop.elem_size1()?,
+        };
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn idmybee_result_encode(
+    result: *const IdMyBeeResult,
+    format: *const c_char,
+    data: *mut *mut u8,
+    length: *mut usize,
+    error_message: *mut *mut c_char,
+) -> i32 {
+    ffi_call(error_message, || {
+        let result = reference(result, "result")?;
+        let data = out_reference(data, "data")?;
+        let length = out_reference(length, "length")?;
+        *data = ptr::null_mut();
+        *length = 0;
+        let extension = match str_arg(format, "format")? {
+            Some(format) => format.trim_start_matches('.').to_lowercase(),
+            None => result
+                .encoding
+                .format
+                .clone()
+                .unwrap_or(result.input_extension.clone()),
+        };
+        let params = result.encoding.imwrite_params(&extension);
+        let bytes =
+            encode_image(&result.crop.image, &extension, &params).with_kind(ErrorKind::Write)?;
+        *length = bytes.len();
+        *data = Box::into_raw(bytes.into_boxed_slice()) as *mut u8;
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn idmybee_result_px_per_mm(
+    result: *const IdMyBeeResult,
+    x: *mut f64,
+    y: *mut f64,
+) {
+    if let (Some(result), Some(x), Some(y)) = (result.as_ref(), x.as_mut(), y.as_mut()) {
+        [*x, *y] = result.px_per_mm;
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn idmybee_result_homography(
+    result: *const IdMyBeeResult,
+    homography: *mut f64,
+) {
+    if let Some(result) = result.as_ref() {
+        if !homography.is_null() {
+            let values: Vec<f64> = result.homography.iter().flatten().copied().collect();
+            ptr::copy_nonoverlapping(values.as_ptr(), homography, values.len());
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn idmybee_result_source_points(
+    result: *const IdMyBeeResult,
+    points: *mut f32,
+) {
+    if let Some(result) = result.as_ref() {
+        if !points.is_null() {
+            let values: Vec<f32> = result
+                .crop
+                .source_points
+                .iter()
+                .flatten()
+                .copied()
+                .collect();
+            ptr::copy_nonoverlapping(values.as_ptr(), points, values.len());
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn idmybee_result_marker_count(result: *const IdMyBeeResult) -> usize {
+    result
+        .as_ref()
+        .map_or(0, |result| result.crop.marker_ids.len())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn idmybee_result_marker(
+    result: *const IdMyBeeResult,
+    index: usize,
+    id: *mut i32,
+    corners: *mut f32,
+    error_message: *mut *mut c_char,
+) -> i32 {
+    ffi_call(error_message, || {
+        let result = reference(result, "result")?;
+        let (Some(marker_id), Some(marker_corners)) = (
+            result.crop.marker_ids.get(index),
+            result.crop.marker_corners.get(index),
+        ) else {
+            return Err(kind_error(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Marker index {index} out of range, {} markers were found",
+                    result.crop.marker_ids.len()
+                ),
+            ));
+        };
+        *out_reference(id, "id")? = *marker_id;
+        if !corners.is_null() {
+            let values: Vec<f32> = marker_corners.iter().flatten().copied().collect();
+            ptr::copy_nonoverlapping(values.as_ptr(), corners, values.len());
+        }
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn idmybee_result_sharpness(result: *const IdMyBeeResult) -> f64 {
+    result.as_ref().map_or(f64::NAN, |result| result.sharpness)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn idmybee_result_confidence(result: *const IdMyBeeResult) -> f64 {
+    result
+        .as_ref()
+        .and_then(|result| result.confidence)
+        .unwrap_or(f64::NAN)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn idmybee_result_card_id(result: *const IdMyBeeResult) -> *mut c_char {
+    match result
+        .as_ref()
+        .and_then(|result| result.crop.card_id.as_ref())
+    {
+        Some(card_id) => c_string(card_id),
+        None => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn idmybee_result_json(result: *const IdMyBeeResult) -> *mut c_char {
+    let Some(result) = result.as_ref() else {
+        return ptr::null_mut();
+    };
+    let crop_window = result.crop.crop_window;
+    let json = ResultJson {
+        marker_ids: &result.crop.marker_ids,
+        marker_corners: &result.crop.marker_corners,
+        card_id: result.crop.card_id.as_deref(),
+        source_points: result.crop.source_points,
+        homography: result.homography,
+        out_size: [
+            result.parameters.out_size.width,
+            result.parameters.out_size.height,
+        ],
+        zoom: result.parameters.zoom,
+        crop_window: [
+            crop_window.x,
+            crop_window.y,
+            crop_window.width,
+            crop_window.height,
+        ],
+        interpolation: result.parameters.interpolation.to_string(),
+        px_per_mm: result.px_per_mm,
+        diagnostics: DiagnosticsJson {
+            input_size: result.input_size,
+            working_scale: [result.crop.working_scale.0, result.crop.working_scale.1],
+            markers_found: result.crop.marker_ids.len(),
+            rejected_candidates: result.crop.rejected_count,
+            confidence: result.confidence,
+            sharpness: result.sharpness,
+        },
+    };
+    match serde_json::to_string(&json) {
+        Ok(json) => c_string(&json),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn idmybee_result_free(result: *mut IdMyBeeResult) {
+    if !result.is_null() {
+        drop(Box::from_raw(result));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn idmybee_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn idmybee_buffer_free(data: *mut u8, length: usize) {
+    if !data.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(data, length)));
+    }
+}
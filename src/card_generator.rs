@@ -0,0 +1,457 @@
+use opencv::{
+    core::{Mat, Rect, Scalar, CV_8UC1},
+    objdetect::{
+        generate_image_marker, get_predefined_dictionary, PredefinedDictionaryType, QRCodeEncoder,
+        QRCodeEncoder_Params,
+    },
+    prelude::*,
+};
+
+use idmybee::card_layout::CardLayout;
+
+/// Printable card with markers #0 to #3 in the corners of the reference rectangle of `layout`,
+/// each marker oriented so that the corner kept by `parse_markers` is the outer one. The image is
+/// meant to be printed at `dpi` without any scaling.
+pub fn render_card(layout: &CardLayout, dpi: f64, margin_mm: f64) -> Result<Mat, opencv::Error> {
+    let px_per_mm = dpi / 25.4;
+    let to_px = |mm: f64| (mm * px_per_mm).round() as i32;
+
+    let mut card = Mat::new_rows_cols_with_default(
+        to_px(layout.ref_height_mm + 2. * margin_mm),
+        to_px(layout.ref_width_mm + 2. * margin_mm),
+        CV_8UC1,
+        Scalar::all(255.),
+    )?;
+    let dictionary = get_predefined_dictionary(PredefinedDictionaryType::DICT_4X4_50)?;
+    for (id, rect) in marker_rects(layout, dpi, margin_mm).iter().enumerate() {
+        let mut marker = Mat::default();
+        generate_image_marker(&dictionary, id as i32, rect.width, &mut marker, 1)?;
+        // The roi shares the card data, copying the marker into it draws it on the card
+        let mut roi = Mat::roi(&card, *rect)?;
+        marker.copy_to(&mut roi)?;
+    }
+    Ok(card)
+}
+
+/// Pixels covered by markers #0 to #3 on the card of `render_card`
+pub fn marker_rects(layout: &CardLayout, dpi: f64, margin_mm: f64) -> [Rect; 4] {
+    let px_per_mm = dpi / 25.4;
+    let to_px = |mm: f64| (mm * px_per_mm).round() as i32;
+    let marker_px = to_px(layout.marker_size_mm);
+    let right_mm = margin_mm + layout.ref_width_mm - layout.marker_size_mm;
+    let bottom_mm = margin_mm + layout.ref_height_mm - layout.marker_size_mm;
+    [
+        (margin_mm, margin_mm),
+        (right_mm, margin_mm),
+        (right_mm, bottom_mm),
+        (margin_mm, bottom_mm),
+    ]
+    .map(|(x_mm, y_mm)| Rect::new(to_px(x_mm), to_px(y_mm), marker_px, marker_px))
+}
+
+/// Height of the specimen ID band printed under the card, QR code and text
+pub const ID_FIELD_MM: f64 = 10.;
+/// A4 portrait, in millimeters
+pub const A4_MM: (f64, f64) = (210., 297.);
+const SHEET_MARGIN_MM: f64 = 10.;
+/// Space between tiled cards, the crop marks are drawn in it
+const CARD_GAP_MM: f64 = 6.;
+const LABEL_SIZE_MM: f64 = 1.2;
+const MM_TO_PT: f64 = 72. / 25.4;
+
+/// Vector element of a printable card, coordinates in millimeters from the top left corner
+#[derive(Debug, Clone, PartialEq)]
+pub enum Shape {
+    /// Filled in black
+    Rect {
+        x: f64,
+        y: f64,
+        width: f64,
+        height: f64,
+    },
+    Line {
+        x1: f64,
+        y1: f64,
+        x2: f64,
+        y2: f64,
+        width: f64,
+    },
+    /// `y` is the baseline
+    Text {
+        x: f64,
+        y: f64,
+        size: f64,
+        text: String,
+    },
+}
+
+impl Shape {
+    fn translated(&self, dx: f64, dy: f64) -> Shape {
+        match self {
+            Shape::Rect {
+                x,
+                y,
+                width,
+                height,
+            } => Shape::Rect {
+                x: x + dx,
+                y: y + dy,
+                width: *width,
+                height: *height,
+            },
+            Shape::Line {
+                x1,
+                y1,
+                x2,
+                y2,
+                width,
+            } => Shape::Line {
+                x1: x1 + dx,
+                y1: y1 + dy,
+                x2: x2 + dx,
+                y2: y2 + dy,
+                width: *width,
+            },
+            Shape::Text { x, y, size, text } => Shape::Text {
+                x: x + dx,
+                y: y + dy,
+                size: *size,
+                text: text.clone(),
+            },
+        }
+    }
+}
+
+/// Card or sheet at its physical size, black on white
+#[derive(Debug, Clone, PartialEq)]
+pub struct Drawing {
+    pub width_mm: f64,
+    pub height_mm: f64,
+    pub shapes: Vec<Shape>,
+}
+
+/// Black cells of a binary image (black is 0) as rectangles of `cell_mm`
+fn cell_rects(bits: &Mat, x: f64, y: f64, cell_mm: f64) -> Result<Vec<Shape>, opencv::Error> {
+    let mut shapes = vec![];
+    for row in 0..bits.rows() {
+        for col in 0..bits.cols() {
+            if *bits.at_2d::<u8>(row, col)? < 128 {
+                shapes.push(Shape::Rect {
+                    x: x + col as f64 * cell_mm,
+                    y: y + row as f64 * cell_mm,
+                    width: cell_mm,
+                    height: cell_mm,
+                });
+            }
+        }
+    }
+    Ok(shapes)
+}
+
+/// Vector version of `render_card` for SVG and PDF: the same markers drawn cell by cell from the
+/// OpenCV dictionary, their labels, a 1 mm checker strip between markers #0 and #1 and, when
+/// `id_field` is set, a band under the card with the specimen ID and its QR code (empty to write
+/// the ID by hand).
+pub fn card_drawing(
+    layout: &CardLayout,
+    margin_mm: f64,
+    id_field: Option<&str>,
+) -> Result<Drawing, opencv::Error> {
+    let dictionary = get_predefined_dictionary(PredefinedDictionaryType::DICT_4X4_50)?;
+    let marker_mm = layout.marker_size_mm;
+    let right_mm = margin_mm + layout.ref_width_mm - marker_mm;
+    let bottom_mm = margin_mm + layout.ref_height_mm - marker_mm;
+    let origins_mm = [
+        (margin_mm, margin_mm),
+        (right_mm, margin_mm),
+        (right_mm, bottom_mm),
+        (margin_mm, bottom_mm),
+    ];
+    let mut shapes = vec![];
+
+    for (id, (x_mm, y_mm)) in origins_mm.iter().enumerate() {
+        // One pixel per cell: the 4 x 4 bits of the dictionary and the black border
+        let cells = 4 + 2;
+        let mut bits = Mat::default();
+        generate_image_marker(&dictionary, id as i32, cells, &mut bits, 1)?;
+        shapes.extend(cell_rects(&bits, *x_mm, *y_mm, marker_mm / cells as f64)?);
+        // Labels in the margin, above the top markers and under the bottom ones
+        let label_y = match id {
+            0 | 1 => y_mm - 0.4,
+            _ => y_mm + marker_mm + 0.4 + LABEL_SIZE_MM,
+        };
+        shapes.push(Shape::Text {
+            x: *x_mm,
+            y: label_y,
+            size: LABEL_SIZE_MM,
+            text: format!("#{id}"),
+        });
+    }
+
+    // Two rows of 1 mm squares to check the print scale and the crops
+    let strip_x = margin_mm + marker_mm + 1.;
+    let strip_y = margin_mm + (marker_mm - 2.) / 2.;
+    let squares = (layout.ref_width_mm - 2. * marker_mm - 2.).floor().max(0.) as usize;
+    for i in 0..squares {
+        for row in 0..2 {
+            if (i + row) % 2 == 0 {
+                shapes.push(Shape::Rect {
+                    x: strip_x + i as f64,
+                    y: strip_y + row as f64,
+                    width: 1.,
+                    height: 1.,
+                });
+            }
+        }
+    }
+
+    let card_height = layout.ref_height_mm + 2. * margin_mm;
+    let mut height_mm = card_height;
+    if let Some(specimen_id) = id_field {
+        height_mm += ID_FIELD_MM;
+        let band_y = card_height;
+        let text_size = 2.5;
+        match specimen_id.is_empty() {
+            true => {
+                shapes.push(Shape::Text {
+                    x: margin_mm,
+                    y: band_y + 6.,
+                    size: text_size,
+                    text: String::from("ID:"),
+                });
+                shapes.push(Shape::Line {
+                    x1: margin_mm + 5.,
+                    y1: band_y + 6.5,
+                    x2: margin_mm + layout.ref_width_mm,
+                    y2: band_y + 6.5,
+                    width: 0.1,
+                });
+            }
+            false => {
+                let qr_mm = ID_FIELD_MM - 2.;
+                let mut qr = Mat::default();
+                QRCodeEncoder::create(&QRCodeEncoder_Params::default()?)?
+                    .encode(specimen_id, &mut qr)?;
+                shapes.extend(cell_rects(
+                    &qr,
+                    margin_mm,
+                    band_y + 1.,
+                    qr_mm / qr.cols().max(1) as f64,
+                )?);
+                shapes.push(Shape::Text {
+                    x: margin_mm + qr_mm + 1.,
+                    y: band_y + 6.,
+                    size: text_size,
+                    text: specimen_id.to_string(),
+                });
+            }
+        }
+    }
+
+    Ok(Drawing {
+        width_mm: layout.ref_width_mm + 2. * margin_mm,
+        height_mm,
+        shapes,
+    })
+}
+
+/// Columns and rows of cards of `card_mm` fitting on a sheet of `sheet_mm`
+pub fn sheet_grid(card_mm: (f64, f64), sheet_mm: (f64, f64)) -> (usize, usize) {
+    let fit = |card: f64, sheet: f64| {
+        ((sheet - 2. * SHEET_MARGIN_MM + CARD_GAP_MM) / (card + CARD_GAP_MM))
+            .floor()
+            .max(0.) as usize
+    };
+    (fit(card_mm.0, sheet_mm.0), fit(card_mm.1, sheet_mm.1))
+}
+
+/// Cards laid out in rows on sheets of `sheet_mm`, as many sheets as needed, with crop marks
+/// around each card
+pub fn tile_cards(cards: &[Drawing], sheet_mm: (f64, f64)) -> anyhow::Result<Vec<Drawing>> {
+    let (sheet_width, sheet_height) = sheet_mm;
+    let card_width = cards.iter().map(|card| card.width_mm).fold(0., f64::max);
+    let card_height = cards.iter().map(|card| card.height_mm).fold(0., f64::max);
+    let (columns, rows) = sheet_grid((card_width, card_height), sheet_mm);
+    if columns == 0 || rows == 0 {
+        return Err(anyhow::anyhow!(
+            "A card of {card_width:.1} x {card_height:.1} mm does not fit on a sheet of {sheet_width} x {sheet_height} mm"
+        ));
+    }
+
+    let mut sheets = vec![];
+    for sheet_cards in cards.chunks(columns * rows) {
+        let mut shapes = vec![];
+        for (i, card) in sheet_cards.iter().enumerate() {
+            let x = SHEET_MARGIN_MM + (i % columns) as f64 * (card_width + CARD_GAP_MM);
+            let y = SHEET_MARGIN_MM + (i / columns) as f64 * (card_height + CARD_GAP_MM);
+            shapes.extend(card.shapes.iter().map(|shape| shape.translated(x, y)));
+            // Marks 1 mm away from each corner, in line with the card edges
+            for (corner_x, corner_y, dx, dy) in [
+                (x, y, -1., -1.),
+                (x + card.width_mm, y, 1., -1.),
+                (x + card.width_mm, y + card.height_mm, 1., 1.),
+                (x, y + card.height_mm, -1., 1.),
+            ] {
+                shapes.push(Shape::Line {
+                    x1: corner_x + dx,
+                    y1: corner_y,
+                    x2: corner_x + 2.5 * dx,
+                    y2: corner_y,
+                    width: 0.1,
+                });
+                shapes.push(Shape::Line {
+                    x1: corner_x,
+                    y1: corner_y + dy,
+                    x2: corner_x,
+                    y2: corner_y + 2.5 * dy,
+                    width: 0.1,
+                });
+            }
+        }
+        sheets.push(Drawing {
+            width_mm: sheet_width,
+            height_mm: sheet_height,
+            shapes,
+        });
+    }
+    Ok(sheets)
+}
+
+impl Drawing {
+    /// SVG in millimeters, printed at 100 % it has the physical size of the card
+    pub fn to_svg(&self) -> String {
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}mm\" height=\"{h}mm\" viewBox=\"0 0 {w} {h}\">\n\
+             <rect width=\"{w}\" height=\"{h}\" fill=\"white\"/>\n<g shape-rendering=\"crispEdges\">\n",
+            w = self.width_mm,
+            h = self.height_mm
+        );
+        for shape in self.shapes.iter() {
+            let element = match shape {
+                Shape::Rect { x, y, width, height } => {
+                    format!("<rect x=\"{x:.4}\" y=\"{y:.4}\" width=\"{width:.4}\" height=\"{height:.4}\"/>")
+                }
+                Shape::Line { x1, y1, x2, y2, width } => format!(
+                    "<line x1=\"{x1:.4}\" y1=\"{y1:.4}\" x2=\"{x2:.4}\" y2=\"{y2:.4}\" stroke=\"black\" stroke-width=\"{width}\"/>"
+                ),
+                Shape::Text { x, y, size, text } => {
+                    let text = text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+                    format!("<text x=\"{x:.4}\" y=\"{y:.4}\" font-size=\"{size}\" font-family=\"Helvetica, Arial, sans-serif\">{text}</text>")
+                }
+            };
+            svg.push_str(&element);
+            svg.push('\n');
+        }
+        svg.push_str("</g>\n</svg>\n");
+        svg
+    }
+
+    /// PDF page content drawn in millimeters from the top left corner
+    fn pdf_content(&self) -> String {
+        let mut content = format!(
+            "q {MM_TO_PT:.6} 0 0 {:.6} 0 {:.4} cm 0 g 0 G\n",
+            -MM_TO_PT,
+            self.height_mm * MM_TO_PT
+        );
+        for shape in self.shapes.iter() {
+            match shape {
+                Shape::Rect {
+                    x,
+                    y,
+                    width,
+                    height,
+                } => content.push_str(&format!("{x:.4} {y:.4} {width:.4} {height:.4} re f\n")),
+                Shape::Line {
+                    x1,
+                    y1,
+                    x2,
+                    y2,
+                    width,
+                } => content.push_str(&format!(
+                    "{width} w {x1:.4} {y1:.4} m {x2:.4} {y2:.4} l S\n"
+                )),
+                Shape::Text { x, y, size, text } => {
+                    // Standard font, WinAnsi only
+                    let text: String = text
+                        .chars()
+                        .map(|c| {
+                            if c.is_ascii() && !c.is_ascii_control() {
+                                c
+                            } else {
+                                '?'
+                            }
+                        })
+                        .collect();
+                    let text = text
+                        .replace('\\', "\\\\")
+                        .replace('(', "\\(")
+                        .replace(')', "\\)");
+                    // The text matrix flips the glyphs back up
+                    content.push_str(&format!(
+                        "BT /F1 1 Tf {size} 0 0 {} {x:.4} {y:.4} Tm ({text}) Tj ET\n",
+                        -size
+                    ));
+                }
+            }
+        }
+        content.push_str("Q\n");
+        content
+    }
+}
+
+/// PDF with one page per drawing, each page at the physical size of its drawing
+pub fn drawings_to_pdf(pages: &[Drawing]) -> Vec<u8> {
+    // Objects 1 to 3 are the catalog, the page tree and the font, then a page and its content
+    // for each drawing
+    let mut objects: Vec<String> = vec![
+        String::from("<< /Type /Catalog /Pages 2 0 R >>"),
+        format!(
+            "<< /Type /Pages /Kids [{}] /Count {} >>",
+            (0..pages.len())
+                .map(|i| format!("{} 0 R", 4 + 2 * i))
+                .collect::<Vec<_>>()
+                .join(" "),
+            pages.len()
+        ),
+        String::from(
+            "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica /Encoding /WinAnsiEncoding >>",
+        ),
+    ];
+    for (i, page) in pages.iter().enumerate() {
+        let content = page.pdf_content();
+        objects.push(format!(
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {:.4} {:.4}] /Resources << /Font << /F1 3 0 R >> >> /Contents {} 0 R >>",
+            page.width_mm * MM_TO_PT,
+            page.height_mm * MM_TO_PT,
+            5 + 2 * i
+        ));
+        objects.push(format!(
+            "<< /Length {} >>\nstream\n{}endstream",
+            content.len(),
+            content
+        ));
+    }
+
+    let mut pdf = b"%PDF-1.4\n".to_vec();
+    let mut offsets = vec![];
+    for (i, object) in objects.iter().enumerate() {
+        offsets.push(pdf.len());
+        pdf.extend_from_slice(format!("{} 0 obj\n{}\nendobj\n", i + 1, object).as_bytes());
+    }
+    let xref_offset = pdf.len();
+    pdf.extend_from_slice(
+        format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).as_bytes(),
+    );
+    for offset in offsets {
+        pdf.extend_from_slice(format!("{offset:010} 00000 n \n").as_bytes());
+    }
+    pdf.extend_from_slice(
+        format!(
+            "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+            objects.len() + 1,
+            xref_offset
+        )
+        .as_bytes(),
+    );
+    pdf
+}
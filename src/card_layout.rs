@@ -0,0 +1,62 @@
+use configparser::ini::Ini;
+use opencv::core::Size;
+
+/// Physical dimensions of the ID My Bee card, in millimeters. The reference rectangle is the
+/// one formed by the outer corners of markers #0 to #3, i.e. the points kept by `parse_markers`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CardLayout {
+    pub name: String,
+    pub ref_width_mm: f64,
+    pub ref_height_mm: f64,
+    pub marker_size_mm: f64,
+}
+
+impl Default for CardLayout {
+    fn default() -> Self {
+        // Measured on ressources/card/test_card_v4.png (13 px for the 1 mm checker squares)
+        CardLayout {
+            name: String::from("test_card_v4"),
+            ref_width_mm: 21.7,
+            ref_height_mm: 20.8,
+            marker_size_mm: 3.2,
+        }
+    }
+}
+
+impl CardLayout {
+    pub fn from_config(config: &Ini) -> Self {
+        let default = CardLayout::default();
+        CardLayout {
+            name: config.get("card", "name").unwrap_or(default.name),
+            ref_width_mm: config
+                .getfloat("card", "ref_width_mm")
+                .unwrap_or(None)
+                .unwrap_or(default.ref_width_mm),
+            ref_height_mm: config
+                .getfloat("card", "ref_height_mm")
+                .unwrap_or(None)
+                .unwrap_or(default.ref_height_mm),
+            marker_size_mm: config
+                .getfloat("card", "marker_size_mm")
+                .unwrap_or(None)
+                .unwrap_or(default.marker_size_mm),
+        }
+    }
+
+    /// Horizontal and vertical scales of a crop, `correct_image` maps the reference rectangle
+    /// to `out_size * zoom` pixels.
+    pub fn px_per_mm(&self, out_size: &Size, zoom: f32) -> (f64, f64) {
+        (
+            out_size.width as f64 * zoom as f64 / self.ref_width_mm,
+            out_size.height as f64 * zoom as f64 / self.ref_height_mm,
+        )
+    }
+
+    /// Output dimensions giving `px_per_mm` at `zoom`, inverse of `px_per_mm`
+    pub fn out_size(&self, px_per_mm: f64, zoom: f32) -> Size {
+        Size::new(
+            (px_per_mm * self.ref_width_mm / zoom as f64).round() as i32,
+            (px_per_mm * self.ref_height_mm / zoom as f64).round() as i32,
+        )
+    }
+}
@@ -0,0 +1,206 @@
+//! 'card' command
+use anyhow::Result;
+use argparse::{ArgumentParser, List, Store, StoreTrue};
+use opencv::core::Vector;
+
+use idmybee::command_line::init_logger;
+use idmybee::image_io::{path_extension, write_image};
+use idmybee::pipeline_error::{kind_error, ErrorKind, WithErrorKind};
+
+use crate::card_generator::{
+    card_drawing, drawings_to_pdf, render_card, sheet_grid, tile_cards, A4_MM,
+};
+use crate::{arg_path, load_settings, parse_or_exit, settings_help, write_output};
+
+pub fn run_card(mut args: Vec<String>) -> Result<()> {
+    let mut output_path = String::from("idmybee_card.png");
+    let mut dpi: f64 = 600.;
+    let mut margin_mm: f64 = 2.;
+    let settings = load_settings(&mut args, false)?;
+    let mut card_layout = settings.card_layout.clone();
+    let mut card_mm = vec![card_layout.ref_width_mm, card_layout.ref_height_mm];
+    let mut marker_mm = card_layout.marker_size_mm;
+    let mut sheet = String::from("none");
+    let mut specimen_ids: Vec<String> = vec![];
+    let mut id_field = false;
+    let mut copies: usize = 0;
+    let mut quiet = false;
+    let description = settings_help(
+        "Generates a printable card with the ArUco markers #0 to #3 (4x4 dictionary) in the \
+         corners of the reference rectangle. A PNG (or other image) only has the markers, print it \
+         at the given resolution without any scaling. An SVG or PDF has the physical size of the \
+         card and adds the marker labels, a 1 mm checker strip and optionally a specimen ID field, \
+         print it at 100 %.",
+        None,
+    );
+    {
+        let mut parser = ArgumentParser::new();
+        parser.set_description(&description);
+
+        parser.refer(&mut output_path).add_option(
+            &["-o", "--out"],
+            Store,
+            "Output path, its extension gives the format ('.svg', '.pdf' or an image format). \
+             Default is 'idmybee_card.png'.",
+        );
+
+        parser.refer(&mut sheet).add_option(
+            &["--sheet"],
+            Store,
+            "'a4' tiles the cards on A4 sheets with crop marks (one page per sheet in a PDF), \
+             'none' writes a single card. SVG and PDF only. Default is 'none'.",
+        );
+
+        parser.refer(&mut specimen_ids).add_option(
+            &["--ids"],
+            List,
+            "Specimen IDs, one card each with the ID printed as text and QR code under the \
+             markers. SVG and PDF only.",
+        );
+
+        parser.refer(&mut id_field).add_option(
+            &["--id_field"],
+            StoreTrue,
+            "Add an empty ID field under the markers, to write the specimen ID by hand. SVG and \
+             PDF only.",
+        );
+
+        parser.refer(&mut copies).add_option(
+            &["--copies"],
+            Store,
+            "Number of cards without ID on the sheets. Default is as many as fit on one sheet.",
+        );
+
+        parser.refer(&mut dpi).add_option(
+            &["--dpi"],
+            Store,
+            "Print resolution in dots per inch. Default is 600.",
+        );
+
+        parser.refer(&mut card_mm).add_option(
+            &["--card_mm"],
+            List,
+            "Width and height in millimeters of the rectangle formed by the outer corners of \
+             markers #0 to #3 (default is '--card_mm 21.7 20.8').",
+        );
+
+        parser.refer(&mut marker_mm).add_option(
+            &["--marker_mm"],
+            Store,
+            "Side of the markers in millimeters, black border included. Default is 3.2.",
+        );
+
+        parser.refer(&mut margin_mm).add_option(
+            &["--margin_mm"],
+            Store,
+            "White margin around the markers in millimeters. Default is 2.",
+        );
+
+        parser.refer(&mut quiet).add_option(
+            &["-q", "--quiet"],
+            StoreTrue,
+            "Only log warnings and errors.",
+        );
+
+        parse_or_exit(&parser, args);
+    }
+    init_logger(quiet);
+    settings.log_issues();
+
+    if card_mm.len() != 2 {
+        return Err(kind_error(
+            ErrorKind::InvalidInput,
+            format!(
+                "--card_mm expects 2 values (width height), got {:?}",
+                card_mm
+            ),
+        ));
+    }
+    card_layout.ref_width_mm = card_mm[0];
+    card_layout.ref_height_mm = card_mm[1];
+    card_layout.marker_size_mm = marker_mm;
+    if dpi <= 0.
+        || margin_mm < 0.
+        || marker_mm <= 0.
+        || 2. * marker_mm >= card_mm[0].min(card_mm[1])
+    {
+        return Err(kind_error(
+            ErrorKind::InvalidInput,
+            "--dpi and --marker_mm must be positive, --margin_mm not negative, and two markers \
+             must fit in the card width and height",
+        ));
+    }
+
+    let output_path = arg_path(&output_path);
+    let extension = path_extension(&output_path);
+    let tiled = match sheet.trim().to_lowercase().as_str() {
+        "a4" => true,
+        "none" => false,
+        _ => {
+            return Err(kind_error(
+                ErrorKind::InvalidInput,
+                format!("Unknown sheet {sheet:?}, expected 'a4' or 'none'"),
+            ))
+        }
+    };
+    if extension != "svg" && extension != "pdf" {
+        if tiled || !specimen_ids.is_empty() || id_field {
+            return Err(kind_error(
+                ErrorKind::InvalidInput,
+                "--sheet, --ids and --id_field need an SVG or PDF output",
+            ));
+        }
+        let card = render_card(&card_layout, dpi, margin_mm)?;
+        write_image(&output_path, &card, &Vector::new()).with_kind(ErrorKind::Write)?;
+        log::info!(
+            "Card written to {:?}, print it at {} DPI without scaling",
+            output_path,
+            dpi
+        );
+        return Ok(());
+    }
+
+    let cards = match specimen_ids.is_empty() {
+        true => {
+            let card = card_drawing(&card_layout, margin_mm, id_field.then_some(""))?;
+            // A single card, or enough to fill a sheet
+            let count = match (copies, tiled) {
+                (0, false) => 1,
+                (0, true) => {
+                    let (columns, rows) = sheet_grid((card.width_mm, card.height_mm), A4_MM);
+                    (columns * rows).max(1)
+                }
+                (copies, _) => copies,
+            };
+            vec![card; count]
+        }
+        false => specimen_ids
+            .iter()
+            .map(|specimen_id| card_drawing(&card_layout, margin_mm, Some(specimen_id.as_str())))
+            .collect::<Result<Vec<_>, _>>()?,
+    };
+    let pages = match tiled {
+        true => tile_cards(&cards, A4_MM).with_kind(ErrorKind::InvalidInput)?,
+        false => cards,
+    };
+    let bytes = match extension.as_str() {
+        "svg" if pages.len() > 1 => {
+            return Err(kind_error(
+                ErrorKind::InvalidInput,
+                format!(
+                "An SVG holds a single page, these cards need {} (write a PDF or use --sheet a4)",
+                pages.len()
+            ),
+            ))
+        }
+        "svg" => pages[0].to_svg().into_bytes(),
+        _ => drawings_to_pdf(&pages),
+    };
+    write_output(&output_path, &bytes).with_kind(ErrorKind::Write)?;
+    log::info!(
+        "{} page(s) written to {:?}, print at 100 % without scaling",
+        pages.len(),
+        output_path
+    );
+    Ok(())
+}
@@ -0,0 +1,812 @@
+//! 'crop', 'rerender' and 'watch' commands
+use anyhow::Result;
+use argparse::{ArgumentParser, List, Store, StoreFalse, StoreTrue};
+use opencv::core::{Mat, Rect, Size, Vector};
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use idmybee::card_layout::CardLayout;
+use idmybee::command_line::init_logger;
+use idmybee::crop::{get_reference_points, warp_and_crop, CropParameters, WarpBackend};
+use idmybee::encoding::EncodingOptions;
+use idmybee::image_io::{
+    decode_image, encode_image, guess_extension, path_extension, read_image, SourceExif,
+    SourceMetadataPolicy, IMAGE_EXTENSIONS,
+};
+use idmybee::marker_utils::marker_processing::*;
+use idmybee::metadata::OutputMetadata;
+use idmybee::output_template::{input_roots, OutputNaming, TemplateValues, PLACEHOLDERS_HELP};
+use idmybee::pipeline_error::{kind_error, ErrorKind, WithErrorKind};
+use idmybee::sidecar::CropSidecar;
+
+use crate::batch_state::{fingerprint, BatchState, InputKey, DEFAULT_STATE_FILENAME};
+use crate::report::{ImageRecord, RecordStatus};
+use crate::watch::watch_dir;
+use crate::{
+    add_input_options, arg_path, collect_image_inputs, emit_record, load_settings, parse_or_exit,
+    preview_available, resolve_out_size, run_records, settings_help, timed_record, write_output,
+    Command, InputOptions, RecordFormat, STDIO_PATH,
+};
+
+/// Parameters shared by every image of a run
+#[derive(Debug)]
+struct CropOptions {
+    out_size: Size,
+    zoom_vec: Vec<f32>,
+    naming: OutputNaming,
+    show: bool,
+    write_sidecar: bool,
+    rerender: bool,
+    embed_metadata: bool,
+    card_layout: CardLayout,
+    source_metadata: SourceMetadataPolicy,
+    encoding: EncodingOptions,
+    /// Whole `out_size` from the origin when not set
+    crop_window: Option<Rect>,
+    interpolation: Interpolation,
+    backend: WarpBackend,
+    /// Outer corners of markers #0 to #3 in pixels of the input, replace the detection
+    manual_points: Option<[[f32; 2]; 4]>,
+}
+
+pub fn run_crop(command: Command, mut args: Vec<String>) -> Result<()> {
+    let rerender = command == Command::Rerender;
+    let watch_mode = command == Command::Watch;
+    let settings = load_settings(&mut args, true)?;
+
+    // let mut verbose = false;
+    let mut input_options = InputOptions::default();
+    let mut out_dir = String::new();
+    let mut state_path = String::new();
+    let mut use_state = true;
+    let mut force = false;
+    let mut hash_inputs = false;
+    let mut settle_secs: f32 = 2.;
+    let mut filename_template = settings.cli_filename_template.clone();
+    let mut mirror_dirs = false;
+    // let mut opt_output_path: Option<String> = None;
+    let mut output_paths: Vec<String> = vec![];
+    let mut out_dim = vec![settings.out_size.width, settings.out_size.height];
+    let mut show = false;
+    let mut write_sidecar = settings.write_sidecar;
+    let mut embed_metadata = settings.embed_metadata;
+    let mut card_layout = settings.card_layout.clone();
+    let mut source_metadata = settings.source_metadata;
+    let mut card_mm = vec![card_layout.ref_width_mm, card_layout.ref_height_mm];
+    let mut zoom_vec: Vec<f32> = vec![settings.zoom];
+    let mut crop_window: Vec<i32> = settings
+        .crop_window
+        .map(|window| vec![window.x, window.y, window.width, window.height])
+        .unwrap_or_default();
+    let mut interpolation = settings.interpolation;
+    let mut backend = WarpBackend::default();
+    let mut manual_points: Vec<f32> = vec![];
+    let mut encoding = settings.encoding.clone();
+    let mut out_format = String::new();
+    let template_help = format!(
+        "Filename of the outputs written without an explicit path, relative to the output \
+         directory (may contain '/' to create subdirectories). Placeholders: {PLACEHOLDERS_HELP}. \
+         Default is '{filename_template}'."
+    );
+
+    let (description, inputs_help) = match command {
+        Command::Rerender => (
+            "Renders the source images of sidecar JSON files written by 'crop --sidecar' again \
+             with the given dimensions and zoom, reusing the stored marker positions instead of \
+             detecting them.",
+            "Sidecar JSON files, directories or glob patterns (e.g. 'crops/*.json') to render \
+             again.",
+        ),
+        Command::Watch => (
+            "Crops the images arriving in a directory until stopped. Outputs go to \
+             '<directory>/cropped' unless --out_dir is given, images that cannot be processed are \
+             moved to '<directory>/failed'.",
+            "Directory to watch.",
+        ),
+        _ => (
+            "This tools is used to preprcocess photos taken with the ID My Bee protocol. It \
+             automatically crop and correct the photo angle.",
+            "Input image paths, directories or glob patterns (e.g. 'photos/*.jpg') to preprocess. \
+             '-' reads a single image from stdin (needs --img_out).",
+        ),
+    };
+    let description = settings_help(description, Some(&settings));
+
+    {
+        let mut parser = ArgumentParser::new();
+        parser.set_description(&description);
+        add_input_options(&mut parser, &mut input_options, inputs_help);
+
+        parser.refer(&mut state_path).add_option(
+            &["--state"],
+            Store,
+            "State file listing the images already processed, used to skip them in the next runs \
+             and to resume an interrupted run. Default is '[output \
+             directory]/.idmybee_state.ndjson', or '.idmybee_state.ndjson' in the current \
+             directory for batches without output directory (none for a single image).",
+        );
+
+        parser.refer(&mut use_state).add_option(
+            &["--no_state"],
+            StoreFalse,
+            "Do not read nor write the state file.",
+        );
+
+        parser.refer(&mut force).add_option(
+            &["--force"],
+            StoreTrue,
+            "Process every input again, even those already processed with the same parameters.",
+        );
+
+        parser.refer(&mut hash_inputs).add_option(
+            &["--hash_inputs"],
+            StoreTrue,
+            "Recognise unchanged inputs by their content hash instead of their size and \
+             modification time (slower, but survives copies).",
+        );
+
+        if watch_mode {
+            parser.refer(&mut settle_secs).add_option(
+                &["--settle_secs"],
+                Store,
+                "Seconds a new file size must stay unchanged before it is considered fully \
+                 written. Default is 2.",
+            );
+        }
+
+        parser.refer(&mut out_dir).add_option(
+            &["--out_dir"],
+            Store,
+            "Directory where the outputs are written when no output path is given. Default is the \
+             folder of each input image.",
+        );
+
+        parser.refer(&mut filename_template).add_option(
+            &["-t", "--template"],
+            Store,
+            &template_help,
+        );
+
+        parser.refer(&mut mirror_dirs).add_option(
+            &["--mirror_dirs"],
+            StoreTrue,
+            "Recreate the subdirectories of the input directories in --out_dir (e.g. \
+             'photos/hive1/a.jpg' with input 'photos' is written to '[out_dir]/hive1/').",
+        );
+
+        // Also parsed in watch mode, to reject it with a clear message
+        parser.refer(&mut output_paths).add_option(
+            &["-o", "--img_out"],
+            List,
+            "Output preprocessed image path (single input only, not in watch mode).  /!\\ The \
+             number of output files given must be 0 or the same as the number of zoom levels. If \
+             not given, the outputs are named with --template. '-' writes the image to stdout, in \
+             the --out_format or the format of the input.",
+        );
+
+        parser.refer(&mut out_dim).add_option(
+            &["-d", "--out_dim"],
+            List,
+            "Output image dimensions width height (e.g. default is '-d 600 400').",
+        );
+
+        parser.refer(&mut zoom_vec).add_option(
+            &["-z", "--zoom"],
+            List,
+            "The zoom to apply (can be float numbers). Multiple values can be used. Default is \
+             1.2.",
+        );
+
+        parser.refer(&mut crop_window).add_option(
+            &["--crop_window"],
+            List,
+            "Region of the corrected image kept in the output: x y width height in pixels (e.g. \
+             '--crop_window 100 0 400 400'). Default is the whole output dimensions from the top \
+             left corner.",
+        );
+
+        parser.refer(&mut interpolation).add_option(
+            &["--interpolation"],
+            Store,
+            "Interpolation of the perspective correction: 'nearest', 'linear', 'cubic', 'area' or \
+             'lanczos4'. Default is 'lanczos4'.",
+        );
+
+        parser.refer(&mut backend).add_option(
+            &["--backend"],
+            Store,
+            "Implementation of the perspective correction: 'opencv' (default) or 'rust' (pure \
+             Rust, builds with the 'rust-warp' feature).",
+        );
+
+        if command == Command::Crop {
+            parser.refer(&mut manual_points).add_option(
+                &["--points"],
+                List,
+                "Outer corners of markers #0 to #3 in pixels of the input (x0 y0 x1 y1 x2 y2 x3 \
+                 y3), used instead of detecting the markers (e.g. when they are damaged or \
+                 hidden).",
+            );
+        }
+
+        if !watch_mode {
+            parser.refer(&mut show).add_option(
+                &["-s", "--show"],
+                StoreTrue,
+                "Show the image in a window instead of saving it. Once the windows is open, press \
+                 any key to exit, Ctrl-C to copy the image and Ctrl-S to save it manually.",
+            );
+        }
+
+        parser.refer(&mut write_sidecar).add_option(
+            &["--sidecar"],
+            StoreTrue,
+            "Write a '[output path].json' file next to each output image with the input hash, the \
+             detected markers, the homography and the crop parameters.",
+        );
+
+        parser.refer(&mut out_format).add_option(
+            &["--out_format"],
+            Store,
+            "Output image format (e.g. 'png', 'jpg', 'tif') used for the default output paths. \
+             Default is the format of the input image.",
+        );
+
+        parser.refer(&mut encoding.jpeg_quality).add_option(
+            &["--jpeg_quality"],
+            Store,
+            "JPEG (and WebP) quality from 0 to 100. Default is 95.",
+        );
+
+        parser.refer(&mut encoding.jpeg_subsampling).add_option(
+            &["--jpeg_subsampling"],
+            Store,
+            "JPEG chroma subsampling: '444', '422' or '420'. Default is '420'.",
+        );
+
+        parser.refer(&mut encoding.png_compression).add_option(
+            &["--png_compression"],
+            Store,
+            "PNG compression level from 0 (fastest) to 9 (smallest). Default is 3.",
+        );
+
+        parser.refer(&mut encoding.tiff_compression).add_option(
+            &["--tiff_compression"],
+            Store,
+            "TIFF compression: 'none', 'lzw', 'deflate' or 'packbits'. Default is 'lzw'.",
+        );
+
+        parser.refer(&mut embed_metadata).add_option(
+            &["--no_metadata"],
+            StoreFalse,
+            "Do not embed the source filename, card layout, px/mm scale, homography and tool \
+             version in the output images (PNG text chunks, JPEG XMP, TIFF resolution).",
+        );
+
+        parser.refer(&mut source_metadata).add_option(
+            &["--source_metadata"],
+            Store,
+            "Fields of the input EXIF copied to the outputs: 'strip' (none), 'capture' (capture \
+             date and camera, default) or 'all' (also copies the GPS position).",
+        );
+
+        parser.refer(&mut card_mm).add_option(
+            &["--card_mm"],
+            List,
+            "Width and height in millimeters of the rectangle formed by the outer corners of \
+             markers #0 to #3, used to compute the output scale (default is '--card_mm 21.7 \
+             20.8').",
+        );
+
+        parse_or_exit(&parser, args);
+    }
+    init_logger(input_options.quiet);
+    log::info!("Settings loaded from {:?}", settings.sources);
+    settings.log_issues();
+    let show = preview_available(show);
+
+    if card_mm.len() != 2 {
+        return Err(kind_error(
+            ErrorKind::InvalidInput,
+            format!(
+                "--card_mm expects 2 values (width height), got {:?}",
+                card_mm
+            ),
+        ));
+    }
+    card_layout.ref_width_mm = card_mm[0];
+    card_layout.ref_height_mm = card_mm[1];
+
+    if !out_format.is_empty() {
+        encoding.format = Some(out_format.trim_start_matches('.').to_lowercase());
+    }
+    if !(0..=100).contains(&encoding.jpeg_quality) || !(0..=9).contains(&encoding.png_compression) {
+        return Err(kind_error(
+            ErrorKind::InvalidInput,
+            "--jpeg_quality must be between 0 and 100 and --png_compression between 0 and 9",
+        ));
+    }
+
+    if !output_paths.is_empty() && output_paths.len() != zoom_vec.len() {
+        return Err(kind_error(
+            ErrorKind::InvalidInput,
+            format!(
+                "Mismatch between the number of output paths (={:?}) and the number of zoom values \
+                 (={:?})",
+                output_paths.len(),
+                zoom_vec.len()
+            ),
+        ));
+    }
+
+    let crop_window = match crop_window[..] {
+        [] => None,
+        [x, y, width, height] if x >= 0 && y >= 0 && width > 0 && height > 0 => {
+            Some(Rect::new(x, y, width, height))
+        }
+        _ => {
+            return Err(kind_error(
+                ErrorKind::InvalidInput,
+                format!(
+                    "--crop_window expects 4 values (x y width height) with a positive origin and \
+                     size, got {:?}",
+                    crop_window
+                ),
+            ))
+        }
+    };
+
+    let manual_points = match manual_points[..] {
+        [] => None,
+        [x0, y0, x1, y1, x2, y2, x3, y3] => Some([[x0, y0], [x1, y1], [x2, y2], [x3, y3]]),
+        _ => {
+            return Err(kind_error(
+                ErrorKind::InvalidInput,
+                format!(
+                    "--points expects 8 values (x0 y0 x1 y1 x2 y2 x3 y3), got {:?}",
+                    manual_points
+                ),
+            ))
+        }
+    };
+
+    let output_paths: Vec<PathBuf> = output_paths
+        .iter()
+        .map(|output_path| arg_path(output_path))
+        .collect();
+    let stdin_input = input_options
+        .input_args
+        .iter()
+        .any(|input_arg| input_arg == STDIO_PATH);
+    let stdout_outputs = output_paths
+        .iter()
+        .filter(|output_path| *output_path == Path::new(STDIO_PATH))
+        .count();
+    if stdin_input {
+        if rerender || watch_mode || input_options.input_args.len() > 1 {
+            return Err(kind_error(
+                ErrorKind::InvalidInput,
+                "Reading from stdin ('-i -') only works with 'crop' and a single input",
+            ));
+        }
+        if output_paths.is_empty() {
+            return Err(kind_error(
+                ErrorKind::InvalidInput,
+                "An image read from stdin needs an output path (--img_out), '-' for stdout",
+            ));
+        }
+    }
+    if stdout_outputs > 1 {
+        return Err(kind_error(
+            ErrorKind::InvalidInput,
+            "Only one output can be written to stdout, give a single zoom level",
+        ));
+    }
+    if stdout_outputs > 0 && input_options.record_format != RecordFormat::Text {
+        return Err(kind_error(
+            ErrorKind::InvalidInput,
+            "--format json and ndjson write on stdout, they cannot be used with '-o -'",
+        ));
+    }
+    if (stdin_input || stdout_outputs > 0) && write_sidecar {
+        return Err(kind_error(
+            ErrorKind::InvalidInput,
+            "Sidecars need an input and an output file, they cannot be written with stdin or \
+             stdout",
+        ));
+    }
+
+    if mirror_dirs && out_dir.is_empty() && !watch_mode {
+        return Err(kind_error(
+            ErrorKind::InvalidInput,
+            "--mirror_dirs needs an output directory (--out_dir)",
+        ));
+    }
+
+    let mut out_dir = match out_dir.is_empty() {
+        true => None,
+        false => Some(arg_path(&out_dir)),
+    };
+    if watch_mode {
+        if !output_paths.is_empty() {
+            return Err(kind_error(
+                ErrorKind::InvalidInput,
+                "Output paths (-o) cannot be used in watch mode, the outputs are named with \
+                 --template in --out_dir",
+            ));
+        }
+        let input_args = &input_options.input_args;
+        if input_args.len() != 1 || !arg_path(&input_args[0]).is_dir() {
+            return Err(kind_error(
+                ErrorKind::InvalidInput,
+                format!("watch expects a single directory, got {:?}", input_args),
+            ));
+        }
+        // Outputs must not land in the watched directory, they would be processed again
+        out_dir.get_or_insert_with(|| arg_path(&input_args[0]).join("cropped"));
+    }
+
+    let options = CropOptions {
+        out_size: resolve_out_size(&settings, &out_dim, &zoom_vec)?,
+        zoom_vec,
+        naming: OutputNaming {
+            template: filename_template,
+            out_dir,
+            mirror_dirs,
+            input_roots: input_roots(&input_options.input_paths()),
+        },
+        show,
+        write_sidecar,
+        rerender,
+        embed_metadata,
+        card_layout,
+        source_metadata,
+        encoding,
+        crop_window,
+        interpolation,
+        backend,
+        manual_points,
+    };
+    if let Some(out_dir) = options.naming.out_dir.as_ref() {
+        std::fs::create_dir_all(out_dir).with_kind(ErrorKind::Write)?;
+    }
+
+    if show {
+        // highgui windows must be opened from a single thread
+        input_options.jobs = 1;
+    }
+    if input_options.jobs > 1 && !watch_mode {
+        // Parallelism comes from the workers, OpenCV's own threads would oversubscribe the CPU
+        opencv::core::set_num_threads(1)?;
+    }
+
+    // Outputs of the same inputs change with any of these parameters
+    let params_fingerprint = fingerprint(&format!(
+        "{:?} {:?} {}",
+        options,
+        output_paths,
+        env!("CARGO_PKG_VERSION")
+    ));
+    // A single image file is a one-off crop, the working directory only gets a state file for
+    // batches (several inputs, directories, patterns or watch)
+    let batch_mode = watch_mode
+        || input_options.input_args.len() > 1
+        || input_options
+            .input_args
+            .first()
+            .map_or(true, |input_arg| !arg_path(input_arg).is_file());
+    let state_path = match (state_path.is_empty(), options.naming.out_dir.as_ref()) {
+        (false, _) => Some(arg_path(&state_path)),
+        (true, Some(out_dir)) => Some(out_dir.join(DEFAULT_STATE_FILENAME)),
+        (true, None) if batch_mode => Some(PathBuf::from(DEFAULT_STATE_FILENAME)),
+        (true, None) => None,
+    };
+    // Nothing identifies an image read from stdin between runs
+    let state = match state_path.filter(|_| use_state && !show && !stdin_input) {
+        Some(state_path) => {
+            let input_key = match hash_inputs {
+                true => InputKey::ContentHash,
+                false => InputKey::SizeAndMtime,
+            };
+            let state = BatchState::open(&state_path, input_key).with_kind(ErrorKind::Write)?;
+            log::info!("State file: {:?}", state.path());
+            Some(state)
+        }
+        None => None,
+    };
+
+    let process_and_record = |index: usize, input_path: &Path| -> Result<ImageRecord> {
+        if let Some(state) = state.as_ref() {
+            if !force && state.is_done(input_path, &params_fingerprint) {
+                log::info!(
+                    "Skipping {:?}, already processed with the same parameters",
+                    input_path
+                );
+                return Ok(ImageRecord::skipped(input_path));
+            }
+        }
+        let record = process_input(input_path, index, &output_paths, &options)?;
+        if let Some(state) = state.as_ref() {
+            if let Err(err) = state.record(input_path, &params_fingerprint, &record.outputs) {
+                log::warn!("Could not update the state file: {err}");
+            }
+        }
+        Ok(record)
+    };
+
+    if watch_mode {
+        let ignored_dirs: Vec<PathBuf> = options.naming.out_dir.iter().cloned().collect();
+        let mut watch_index = 0;
+        return watch_dir(
+            &arg_path(&input_options.input_args[0]),
+            input_options.recursive,
+            &IMAGE_EXTENSIONS,
+            &ignored_dirs,
+            Duration::from_secs_f32(settle_secs),
+            |input_path| {
+                watch_index += 1;
+                let record = timed_record(input_path, |input_path| {
+                    process_and_record(watch_index, input_path)
+                });
+                emit_record(&record, input_options.record_format, true);
+                match record.status {
+                    RecordStatus::Failed => {
+                        Err(anyhow::anyhow!(record.error_message.unwrap_or_default()))
+                    }
+                    _ => Ok(()),
+                }
+            },
+        );
+    }
+
+    let extensions: Vec<&str> = match rerender {
+        true => vec!["json"],
+        false => IMAGE_EXTENSIONS.to_vec(),
+    };
+    let inputs = match stdin_input {
+        true => vec![PathBuf::from(STDIO_PATH)],
+        false => collect_image_inputs(&input_options, &extensions)?,
+    };
+    if inputs.len() > 1 && !output_paths.is_empty() {
+        return Err(kind_error(
+            ErrorKind::InvalidInput,
+            format!(
+                "Output paths can only be given for a single input ({} inputs found), use \
+                 --out_dir instead",
+                inputs.len()
+            ),
+        ));
+    }
+    run_records(&inputs, &input_options, process_and_record)
+}
+
+/// Reads an input image and the extension of its format, '-' reads the encoded image from stdin
+fn read_input_image(img_path: &Path) -> Result<(Mat, SourceExif, String)> {
+    if img_path != Path::new(STDIO_PATH) {
+        let (img, source_exif) = read_image(img_path)?;
+        return Ok((img, source_exif, path_extension(img_path)));
+    }
+    let mut bytes = Vec::new();
+    std::io::stdin().lock().read_to_end(&mut bytes)?;
+    let (img, source_exif) = decode_image(&bytes)
+        .map_err(|err| anyhow::anyhow!("The image read from stdin could not be decoded: {err}"))?;
+    Ok((
+        img,
+        source_exif,
+        guess_extension(&bytes).unwrap_or(String::from("png")),
+    ))
+}
+
+/// Crops one input and returns its record with the written outputs
+fn process_input(
+    input_path: &Path,
+    index: usize,
+    output_paths: &[PathBuf],
+    options: &CropOptions,
+) -> Result<ImageRecord> {
+    let CropOptions {
+        out_size,
+        zoom_vec,
+        show,
+        write_sidecar,
+        embed_metadata,
+        card_layout,
+        source_metadata,
+        encoding,
+        interpolation,
+        ..
+    } = options;
+    let mut output_paths = output_paths.to_vec();
+    let mut record = ImageRecord::new(input_path);
+
+    let stored_sidecar = match options.rerender {
+        true => Some(CropSidecar::read(input_path).with_kind(ErrorKind::InvalidSidecar)?),
+        false => None,
+    };
+    let img_path = match stored_sidecar.as_ref() {
+        Some(stored) => {
+            let img_path = stored.resolve_input_path(input_path);
+            if CropSidecar::hash_file(&img_path).with_kind(ErrorKind::Read)? != stored.input_sha256
+            {
+                return Err(anyhow::anyhow!(
+                    "Image {:?} does not match the hash stored in {:?}, it was modified since the \
+                     sidecar was written.",
+                    img_path,
+                    input_path
+                ))
+                .with_kind(ErrorKind::InvalidSidecar);
+            }
+            img_path
+        }
+        None => input_path.to_path_buf(),
+    };
+    log::info!("Image path: {img_path:?}");
+
+    // let img = get_image(&input_path).to_rgba8();
+    let (mut img, source_exif, input_extension) =
+        read_input_image(&img_path).with_kind(ErrorKind::Read)?;
+
+    let working_scale = get_resize_ratios(&img.size()?, out_size);
+    img = resize_if_larger_dims(img, out_size)?;
+    // show_image(&img);
+
+    let (markers_coor, markers_id, ordered_points) =
+        match (stored_sidecar.as_ref(), options.manual_points) {
+            (Some(stored), _) => {
+                // Rerendering: the stored positions replace the detection
+                record.set_markers(stored.marker_ids.clone(), stored.marker_corners.clone());
+                record.card_id = stored.card_id.clone();
+                (
+                    Vector::new(),
+                    Vector::new(),
+                    stored.scaled_source_points(working_scale),
+                )
+            }
+            (None, Some(points)) => {
+                log::info!("Markers not detected, using the corners given with --points");
+                record.card_id = read_card_id(&img);
+                (
+                    Vector::new(),
+                    Vector::new(),
+                    points_to_working(&points, working_scale),
+                )
+            }
+            (None, None) => {
+                let (markers_coor, markers_id, rejected_markers) = get_image_markers(&img)?;
+                let ordered_points =
+                    get_reference_points(&markers_coor, &markers_id, &rejected_markers)?;
+                record.set_markers(
+                    markers_id.to_vec(),
+                    markers_to_arrays(&markers_coor, working_scale),
+                );
+                record.card_id = read_card_id(&img);
+                (markers_coor, markers_id, ordered_points)
+            }
+        };
+
+    // Outputs are named once the card ID is read. Mirroring follows the tree of the inputs
+    // (sidecars when rerendering), otherwise the outputs go next to the image
+    let location_path = match options.naming.mirror_dirs {
+        true => input_path,
+        false => img_path.as_path(),
+    };
+    for (i, &zoom) in zoom_vec.iter().enumerate() {
+        match output_paths.get(i) {
+            Some(_) => (),
+            None => {
+                let values = TemplateValues {
+                    input_path: img_path.clone(),
+                    extension: encoding.output_extension(&img_path),
+                    zoom,
+                    out_size: *out_size,
+                    date: TemplateValues::input_date(
+                        &img_path,
+                        source_exif.capture_date.as_deref(),
+                    ),
+                    card_id: record.card_id.clone(),
+                    index,
+                };
+                let out_path = options.naming.output_path(location_path, &values);
+                log::info!(
+                    "Output path was not specified so image will be written to {out_path:?}"
+                );
+                output_paths.push(out_path);
+            }
+        }
+    }
+    log::info!("Output path: {output_paths:?}");
+
+    log::info!("Points used from marker #0 to #3: {:?}", ordered_points);
+    for (zoom, out_path) in zoom_vec.iter().zip(output_paths.iter()) {
+        let perspective_transform = get_correction_matrix(&ordered_points, out_size, zoom)?;
+        let parameters = CropParameters {
+            out_size: *out_size,
+            zoom: *zoom,
+            crop_window: options.crop_window,
+            interpolation: *interpolation,
+        };
+        let crop_window = parameters.crop_window();
+        let final_image = warp_and_crop(
+            &img,
+            &ordered_points,
+            &perspective_transform,
+            &parameters,
+            options.backend,
+        )?;
+        if record.sharpness.is_none() {
+            record.sharpness = Some(sharpness(&final_image)?);
+        }
+
+        if *show {
+            show_image(&final_image)?;
+        } else {
+            log::info!("Saving image to {:?}", out_path);
+            let metadata = OutputMetadata::new(
+                &img_path,
+                card_layout,
+                out_size,
+                *zoom,
+                &perspective_transform,
+            )?
+            .with_source_exif(&source_exif, *source_metadata)
+            .with_card_id(record.card_id.clone());
+            // Stdout has no extension, it gets the requested format or the one of the input
+            let extension = match out_path == Path::new(STDIO_PATH) {
+                true => encoding.format.clone().unwrap_or(input_extension.clone()),
+                false => path_extension(out_path),
+            };
+            let params: Vector<i32> = match *embed_metadata {
+                true => encoding
+                    .imwrite_params(&extension)
+                    .iter()
+                    .chain(metadata.imwrite_params(&extension).iter())
+                    .collect(),
+                false => encoding.imwrite_params(&extension),
+            };
+            let mut bytes =
+                encode_image(&final_image, &extension, &params).with_kind(ErrorKind::Write)?;
+            if *embed_metadata {
+                bytes = metadata
+                    .embed(bytes, &extension)
+                    .with_kind(ErrorKind::Write)?;
+            }
+            write_output(out_path, &bytes).with_kind(ErrorKind::Write)?;
+            let (px_per_mm_x, px_per_mm_y) = card_layout.px_per_mm(out_size, *zoom);
+            record.outputs.push(out_path.display().to_string());
+            record.px_per_mm.push([px_per_mm_x, px_per_mm_y]);
+
+            if *write_sidecar {
+                let sidecar = match stored_sidecar.as_ref() {
+                    Some(stored) => stored.rerendered(
+                        working_scale,
+                        &perspective_transform,
+                        out_size,
+                        *zoom,
+                        &crop_window,
+                        *interpolation,
+                    )?,
+                    None => CropSidecar::new(
+                        &img_path,
+                        &markers_coor,
+                        &markers_id,
+                        &ordered_points,
+                        working_scale,
+                        &perspective_transform,
+                        out_size,
+                        *zoom,
+                        &crop_window,
+                        *interpolation,
+                    )?
+                    .with_card_id(record.card_id.clone()),
+                };
+                let sidecar_path = sidecar.write(out_path).with_kind(ErrorKind::Write)?;
+                log::info!("Sidecar written to {:?}", sidecar_path);
+            }
+        }
+    }
+
+    Ok(record)
+}
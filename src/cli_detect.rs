@@ -0,0 +1,54 @@
+//! 'detect' command
+use anyhow::Result;
+use argparse::ArgumentParser;
+use std::path::Path;
+
+use idmybee::command_line::init_logger;
+use idmybee::crop::get_reference_points;
+use idmybee::image_io::{read_image, IMAGE_EXTENSIONS};
+use idmybee::marker_utils::marker_processing::*;
+use idmybee::pipeline_error::{ErrorKind, WithErrorKind};
+
+use crate::report::ImageRecord;
+use crate::{add_input_options, collect_image_inputs, parse_or_exit, run_records, InputOptions};
+
+pub fn run_detect(args: Vec<String>) -> Result<()> {
+    let mut input_options = InputOptions::default();
+    {
+        let mut parser = ArgumentParser::new();
+        parser.set_description(
+            "Detects the markers of the input images and reports their ids and corners (in input \
+             image coordinates, see --format json), without writing any image. Images where \
+             markers #0 to #3 are not all found fail with exit code 4.",
+        );
+        add_input_options(
+            &mut parser,
+            &mut input_options,
+            "Input image paths, directories or glob patterns (e.g. 'photos/*.jpg') to inspect.",
+        );
+        parse_or_exit(&parser, args);
+    }
+    init_logger(input_options.quiet);
+
+    if input_options.jobs > 1 {
+        opencv::core::set_num_threads(1)?;
+    }
+    let inputs = collect_image_inputs(&input_options, &IMAGE_EXTENSIONS)?;
+    run_records(&inputs, &input_options, |_, input_path| {
+        detect_input(input_path)
+    })
+}
+
+fn detect_input(input_path: &Path) -> Result<ImageRecord> {
+    let (img, _) = read_image(input_path).with_kind(ErrorKind::Read)?;
+    let (markers_coor, markers_id, rejected_markers) = get_image_markers(&img)?;
+    get_reference_points(&markers_coor, &markers_id, &rejected_markers)?;
+
+    let mut record = ImageRecord::new(input_path);
+    record.set_markers(
+        markers_id.to_vec(),
+        markers_to_arrays(&markers_coor, (1., 1.)),
+    );
+    record.card_id = read_card_id(&img);
+    Ok(record)
+}
@@ -0,0 +1,239 @@
+//! 'overlay' command
+use anyhow::Result;
+use argparse::{ArgumentParser, List, Store, StoreTrue};
+use opencv::core::{Rect, Size, Vector};
+use std::path::{Path, PathBuf};
+
+use idmybee::command_line::init_logger;
+use idmybee::crop::get_reference_points;
+use idmybee::image_io::{read_image, write_image, IMAGE_EXTENSIONS};
+use idmybee::marker_utils::marker_processing::*;
+use idmybee::output_template::{
+    input_roots, OutputNaming, OutputTemplate, TemplateValues, DEFAULT_OVERLAY_TEMPLATE,
+    PLACEHOLDERS_HELP,
+};
+use idmybee::pipeline_error::{kind_error, ErrorKind, WithErrorKind};
+
+use crate::overlay::{crop_outline, render_overlay};
+use crate::report::ImageRecord;
+use crate::{
+    add_input_options, arg_path, collect_image_inputs, load_settings, parse_or_exit,
+    preview_available, resolve_out_size, run_records, settings_help, InputOptions,
+};
+
+pub fn run_overlay(mut args: Vec<String>) -> Result<()> {
+    let settings = load_settings(&mut args, true)?;
+    let mut input_options = InputOptions::default();
+    let mut output_path = String::new();
+    let mut out_dir = String::new();
+    let mut out_dim = vec![settings.out_size.width, settings.out_size.height];
+    let mut zoom_vec: Vec<f32> = vec![settings.zoom];
+    let mut show = false;
+    let mut filename_template: OutputTemplate = DEFAULT_OVERLAY_TEMPLATE.parse()?;
+    let mut mirror_dirs = false;
+    let template_help = format!(
+        "Filename of the outputs written without an explicit path, relative to the output \
+         directory (may contain '/' to create subdirectories). Placeholders: {PLACEHOLDERS_HELP}. \
+         Default is '{filename_template}'."
+    );
+    let description = settings_help(
+        "Draws the detection on the input images to debug it: accepted markers in green with their \
+         ids, rejected candidates in red, the reference rectangle (outer corners of markers #0 to \
+         #3) in blue and the area cropped for each zoom in yellow.",
+        Some(&settings),
+    );
+    {
+        let mut parser = ArgumentParser::new();
+        parser.set_description(&description);
+        add_input_options(
+            &mut parser,
+            &mut input_options,
+            "Input image paths, directories or glob patterns (e.g. 'photos/*.jpg') to draw the \
+             detection on.",
+        );
+
+        parser.refer(&mut output_path).add_option(
+            &["-o", "--img_out"],
+            Store,
+            "Output image path (single input only).",
+        );
+
+        parser.refer(&mut out_dir).add_option(
+            &["--out_dir"],
+            Store,
+            "Directory where the outputs are written when no output path is given. Default is the \
+             folder of each input image.",
+        );
+
+        parser.refer(&mut filename_template).add_option(
+            &["-t", "--template"],
+            Store,
+            &template_help,
+        );
+
+        parser.refer(&mut mirror_dirs).add_option(
+            &["--mirror_dirs"],
+            StoreTrue,
+            "Recreate the subdirectories of the input directories in --out_dir.",
+        );
+
+        parser.refer(&mut out_dim).add_option(
+            &["-d", "--out_dim"],
+            List,
+            "Crop dimensions width height used to draw the crop outlines (default is '-d 600 \
+             400').",
+        );
+
+        parser.refer(&mut zoom_vec).add_option(
+            &["-z", "--zoom"],
+            List,
+            "Zoom values whose crop outlines are drawn. Default is 1.2.",
+        );
+
+        parser.refer(&mut show).add_option(
+            &["-s", "--show"],
+            StoreTrue,
+            "Show the overlay in a window instead of saving it. Press any key to go to the next \
+             image.",
+        );
+
+        parse_or_exit(&parser, args);
+    }
+    init_logger(input_options.quiet);
+    settings.log_issues();
+    let show = preview_available(show);
+
+    if show {
+        // highgui windows must be opened from a single thread
+        input_options.jobs = 1;
+    }
+    if input_options.jobs > 1 {
+        opencv::core::set_num_threads(1)?;
+    }
+    if mirror_dirs && out_dir.is_empty() {
+        return Err(kind_error(
+            ErrorKind::InvalidInput,
+            "--mirror_dirs needs an output directory (--out_dir)",
+        ));
+    }
+    let naming = OutputNaming {
+        template: filename_template,
+        out_dir: match out_dir.is_empty() {
+            true => None,
+            false => Some(arg_path(&out_dir)),
+        },
+        mirror_dirs,
+        input_roots: input_roots(&input_options.input_paths()),
+    };
+    let inputs = collect_image_inputs(&input_options, &IMAGE_EXTENSIONS)?;
+    if inputs.len() > 1 && !output_path.is_empty() {
+        return Err(kind_error(
+            ErrorKind::InvalidInput,
+            format!(
+                "An output path can only be given for a single input ({} inputs found), use \
+                 --out_dir instead",
+                inputs.len()
+            ),
+        ));
+    }
+
+    let out_size = resolve_out_size(&settings, &out_dim, &zoom_vec)?;
+    let crop_window = settings.crop_window.unwrap_or(get_crop_window(&out_size));
+    let output_path = match output_path.is_empty() {
+        true => None,
+        false => Some(arg_path(&output_path)),
+    };
+    run_records(&inputs, &input_options, |index, input_path| {
+        let output_path = output_path.clone();
+        overlay_input(
+            input_path,
+            index,
+            output_path,
+            &naming,
+            &out_size,
+            &crop_window,
+            &zoom_vec,
+            show,
+        )
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+fn overlay_input(
+    input_path: &Path,
+    index: usize,
+    output_path: Option<PathBuf>,
+    naming: &OutputNaming,
+    out_size: &Size,
+    crop_window: &Rect,
+    zoom_vec: &[f32],
+    show: bool,
+) -> Result<ImageRecord> {
+    let (img, source_exif) = read_image(input_path).with_kind(ErrorKind::Read)?;
+    let working_scale = get_resize_ratios(&img.size()?, out_size);
+    // Same working image as 'crop', so that the crop outlines match
+    let img = resize_if_larger_dims(img, out_size)?;
+    let (markers_coor, markers_id, rejected_markers) = get_image_markers(&img)?;
+
+    let mut record = ImageRecord::new(input_path);
+    record.set_markers(
+        markers_id.to_vec(),
+        markers_to_arrays(&markers_coor, working_scale),
+    );
+    record.card_id = read_card_id(&img);
+
+    // The overlay is still drawn when the detection fails, it is what it is for
+    let reference_points = match get_reference_points(&markers_coor, &markers_id, &rejected_markers)
+    {
+        Ok(points) => Some(points),
+        Err(err) => {
+            log::warn!("{err}");
+            None
+        }
+    };
+    let mut crop_outlines = Vec::new();
+    if let Some(points) = reference_points.as_ref() {
+        for zoom in zoom_vec {
+            crop_outlines.push(crop_outline(
+                &get_correction_matrix(points, out_size, zoom)?,
+                crop_window,
+            )?);
+        }
+    }
+    let overlay = render_overlay(
+        &img,
+        &markers_coor,
+        &markers_id,
+        &rejected_markers,
+        reference_points.as_ref(),
+        &crop_outlines,
+    )?;
+
+    if show {
+        show_image(&overlay)?;
+    } else {
+        let output_path = output_path.unwrap_or_else(|| {
+            let values = TemplateValues {
+                input_path: input_path.to_path_buf(),
+                extension: input_path
+                    .extension()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_lowercase(),
+                zoom: zoom_vec.first().copied().unwrap_or(1.),
+                out_size: *out_size,
+                date: TemplateValues::input_date(input_path, source_exif.capture_date.as_deref()),
+                card_id: record.card_id.clone(),
+                index,
+            };
+            naming.output_path(input_path, &values)
+        });
+        log::info!("Saving overlay to {:?}", output_path);
+        if let Some(parent) = output_path.parent() {
+            std::fs::create_dir_all(parent).with_kind(ErrorKind::Write)?;
+        }
+        write_image(&output_path, &overlay, &Vector::new()).with_kind(ErrorKind::Write)?;
+        record.outputs.push(output_path.display().to_string());
+    }
+    Ok(record)
+}
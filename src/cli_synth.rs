@@ -0,0 +1,255 @@
+//! 'synth' command
+use anyhow::Result;
+use argparse::{ArgumentParser, List, Store, StoreTrue};
+use opencv::core::{Size, Vector};
+
+use idmybee::command_line::init_logger;
+use idmybee::image_io::write_image;
+use idmybee::marker_utils::marker_processing::new_marker_detector;
+use idmybee::pipeline_error::{kind_error, ErrorKind, WithErrorKind};
+
+use crate::synthetic_card::{check_synthetic, render_synthetic, SyntheticOptions};
+use crate::{arg_path, load_settings, parse_or_exit, settings_help};
+
+pub fn run_synth(mut args: Vec<String>) -> Result<()> {
+    let settings = load_settings(&mut args, false)?;
+    let defaults = SyntheticOptions::default();
+    let mut out_dir = String::from("synthetic");
+    let mut count: usize = 20;
+    let mut seed: u64 = 0;
+    let mut image_dim = vec![defaults.image_size.width, defaults.image_size.height];
+    let mut card_dpi = defaults.card_dpi;
+    let mut max_rotation_deg = defaults.max_rotation_deg;
+    let mut scale_range = vec![defaults.scale_range.0, defaults.scale_range.1];
+    let mut max_perspective = defaults.max_perspective;
+    let mut max_blur = defaults.max_blur;
+    let mut max_noise = defaults.max_noise;
+    let mut max_glare_spots = defaults.max_glare_spots;
+    let mut max_gradient = defaults.max_gradient;
+    let mut occlusion_probability = defaults.occlusion_probability;
+    let mut check = false;
+    let mut tolerance_px: f64 = 1.5;
+    let mut quiet = false;
+    let description = settings_help(
+        "Generates photos of the card (layout of the config files) under random perspective, \
+         rotation, scale, blur, noise, glare, lighting gradient and occluded markers. Each \
+         '<name>.png' gets a '<name>.json' with the expected marker corners, usable as known \
+         answers for regression tests. The same seed always gives the same photos.",
+        None,
+    );
+    {
+        let mut parser = ArgumentParser::new();
+        parser.set_description(&description);
+
+        parser.refer(&mut out_dir).add_option(
+            &["-o", "--out_dir"],
+            Store,
+            "Directory of the generated photos. Default is 'synthetic'.",
+        );
+
+        parser.refer(&mut count).add_option(
+            &["-n", "--count"],
+            Store,
+            "Number of photos. Default is 20.",
+        );
+
+        parser.refer(&mut seed).add_option(
+            &["--seed"],
+            Store,
+            "Seed of the first photo, the next ones use the following seeds. Default is 0.",
+        );
+
+        parser.refer(&mut image_dim).add_option(
+            &["--image_dim"],
+            List,
+            "Photo dimensions width height. Default is '--image_dim 1600 1200'.",
+        );
+
+        parser.refer(&mut card_dpi).add_option(
+            &["--card_dpi"],
+            Store,
+            "Resolution of the card before it is warped into the photo. Default is 600.",
+        );
+
+        parser.refer(&mut max_rotation_deg).add_option(
+            &["--max_rotation"],
+            Store,
+            "Largest rotation of the card in degrees, either way. Default is 180.",
+        );
+
+        parser.refer(&mut scale_range).add_option(
+            &["--scale"],
+            List,
+            "Smallest and largest card width over photo width. Default is '--scale 0.3 0.7'.",
+        );
+
+        parser.refer(&mut max_perspective).add_option(
+            &["--perspective"],
+            Store,
+            "Largest shift of each card corner as a fraction of the card width. Default is 0.15.",
+        );
+
+        parser.refer(&mut max_blur).add_option(
+            &["--blur"],
+            Store,
+            "Largest Gaussian blur sigma in pixels. Default is 2.",
+        );
+
+        parser.refer(&mut max_noise).add_option(
+            &["--noise"],
+            Store,
+            "Largest Gaussian noise sigma in levels (0 to 255). Default is 8.",
+        );
+
+        parser.refer(&mut max_glare_spots).add_option(
+            &["--glare"],
+            Store,
+            "Largest number of glare spots. Default is 2.",
+        );
+
+        parser.refer(&mut max_gradient).add_option(
+            &["--gradient"],
+            Store,
+            "Largest brightness change across the photo (0.4 is 40 %). Default is 0.4.",
+        );
+
+        parser.refer(&mut occlusion_probability).add_option(
+            &["--occlusion"],
+            Store,
+            "Probability that each marker is partly hidden. Default is 0.1.",
+        );
+
+        parser.refer(&mut check).add_option(
+            &["--check"],
+            StoreTrue,
+            "Detect the markers on each photo and compare them to the expected ones. Fails when a \
+             photo without hidden marker is not detected or when a detection is off by more than \
+             --tolerance_px.",
+        );
+
+        parser.refer(&mut tolerance_px).add_option(
+            &["--tolerance_px"],
+            Store,
+            "Largest accepted error of --check, in pixels of the photo for the corners and of the \
+             output (--out_dim and --zoom of the config files) for the crop. Default is 1.5.",
+        );
+
+        parser.refer(&mut quiet).add_option(
+            &["-q", "--quiet"],
+            StoreTrue,
+            "Only log warnings and errors.",
+        );
+
+        parse_or_exit(&parser, args);
+    }
+    init_logger(quiet);
+    settings.log_issues();
+
+    let options = match (&image_dim[..], &scale_range[..]) {
+        ([width, height], [min_scale, max_scale])
+            if *width > 0 && *height > 0 && 0. < *min_scale && min_scale <= max_scale =>
+        {
+            SyntheticOptions {
+                image_size: Size::new(*width, *height),
+                card_dpi,
+                margin_mm: defaults.margin_mm,
+                max_rotation_deg,
+                scale_range: (*min_scale, *max_scale),
+                max_perspective,
+                max_blur,
+                max_noise,
+                max_glare_spots,
+                max_gradient,
+                occlusion_probability,
+            }
+        }
+        _ => {
+            return Err(kind_error(
+                ErrorKind::InvalidInput,
+                format!(
+                    "--image_dim expects 2 positive values and --scale a positive minimum and \
+                     maximum, got {:?} and {:?}",
+                    image_dim, scale_range
+                ),
+            ))
+        }
+    };
+    if card_dpi <= 0.
+        || max_blur < 0.
+        || max_noise < 0.
+        || max_perspective < 0.
+        || !(0. ..=1.).contains(&occlusion_probability)
+    {
+        return Err(kind_error(
+            ErrorKind::InvalidInput,
+            "--card_dpi must be positive, --blur, --noise and --perspective not negative and \
+             --occlusion between 0 and 1",
+        ));
+    }
+
+    let out_dir = arg_path(&out_dir);
+    std::fs::create_dir_all(&out_dir).with_kind(ErrorKind::Write)?;
+    let detector = new_marker_detector()?;
+    let mut failed = 0;
+    for index in 0..count {
+        let sample_seed = seed.wrapping_add(index as u64);
+        let mut sample = render_synthetic(&settings.card_layout, &options, sample_seed)?;
+        let image_path = out_dir.join(format!("synthetic_{index:04}.png"));
+        sample.truth.image = image_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        write_image(&image_path, &sample.image, &Vector::new()).with_kind(ErrorKind::Write)?;
+        let truth = serde_json::to_string_pretty(&sample.truth)?;
+        std::fs::write(image_path.with_extension("json"), truth).with_kind(ErrorKind::Write)?;
+        log::info!(
+            "{:?} written (seed {}, hidden markers {:?})",
+            image_path,
+            sample_seed,
+            sample.truth.occluded_ids
+        );
+
+        if check {
+            let result = check_synthetic(
+                &detector,
+                &sample,
+                &settings.out_size,
+                settings.zoom,
+                tolerance_px,
+            )?;
+            match (result.passed, result.detected) {
+                (true, true) => log::info!(
+                    "{}: corners off by {:.3} px, crop off by {:.3} px",
+                    result.image,
+                    result.max_corner_error.unwrap_or_default(),
+                    result.max_output_error.unwrap_or_default()
+                ),
+                (true, false) => log::info!(
+                    "{}: not detected, markers {:?} are hidden",
+                    result.image,
+                    sample.truth.occluded_ids
+                ),
+                (false, _) => {
+                    failed += 1;
+                    log::error!(
+                        "{}: check failed {}",
+                        result.image,
+                        serde_json::to_string(&result)?
+                    );
+                }
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow::anyhow!(
+            "{} of {} synthetic photos failed the check",
+            failed,
+            count
+        ));
+    }
+    if check {
+        log::info!("{} synthetic photos passed the check", count);
+    }
+    Ok(())
+}
@@ -0,0 +1,70 @@
+//! Helpers shared by the command line tools, idmybee_cli and idmybee_server
+use std::io::Write;
+
+/// Value of an option that must be known before parsing the other flags, e.g. '--config <path>'
+/// or '--config=<path>'
+pub fn prescan_option<'a>(args: &'a [String], option: &str) -> Option<&'a str> {
+    let prefix = format!("{option}=");
+    args.iter()
+        .position(|arg| arg == option)
+        .and_then(|i| args.get(i + 1))
+        .or_else(|| args.iter().find(|arg| arg.starts_with(&prefix)))
+        .map(|arg| arg.strip_prefix(prefix.as_str()).unwrap_or(arg))
+}
+
+/// Removes every '<option> <value>' and '<option>=<value>' from the arguments and returns the
+/// last value, for the options read before parsing the others
+pub fn take_option(args: &mut Vec<String>, option: &str) -> anyhow::Result<Option<String>> {
+    let prefix = format!("{option}=");
+    let mut value = None;
+    let mut i = 0;
+    while i < args.len() {
+        if args[i] == option {
+            if i + 1 == args.len() {
+                anyhow::bail!("{option} expects a value");
+            }
+            value = Some(args.remove(i + 1));
+            args.remove(i);
+        } else if let Some(arg_value) = args[i].strip_prefix(prefix.as_str()) {
+            value = Some(arg_value.to_string());
+            args.remove(i);
+        } else {
+            i += 1;
+        }
+    }
+    Ok(value)
+}
+
+/// Logs to stderr without decoration, at the info level or only warnings and errors when
+/// `quiet`. RUST_LOG still overrides the level.
+pub fn init_logger(quiet: bool) {
+    env_logger::Builder::new()
+        .filter_level(match quiet {
+            true => log::LevelFilter::Warn,
+            false => log::LevelFilter::Info,
+        })
+        .parse_default_env()
+        .format(|buf, record| writeln!(buf, "{}", record.args()))
+        .init();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn take_option_removes_every_form() {
+        let mut args = strings(&["crop", "--config", "a.ini", "-i", "x.jpg", "--config=b.ini"]);
+        assert_eq!(
+            take_option(&mut args, "--config").unwrap().as_deref(),
+            Some("b.ini")
+        );
+        assert_eq!(args, strings(&["crop", "-i", "x.jpg"]));
+        assert_eq!(take_option(&mut args, "--preset").unwrap(), None);
+        assert!(take_option(&mut strings(&["crop", "--preset"]), "--preset").is_err());
+    }
+}
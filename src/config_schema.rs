@@ -0,0 +1,471 @@
+use anyhow::Result;
+use configparser::ini::Ini;
+#[cfg(feature = "gui")]
+use egui::Key;
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use crate::encoding::{ChromaSubsampling, TiffCompression};
+use crate::image_io::{SourceMetadataPolicy, IMAGE_EXTENSIONS};
+use crate::marker_utils::marker_processing::Interpolation;
+use crate::output_template::OutputTemplate;
+use crate::presets::{parse_crop_window, PRESET_SECTION_PREFIX};
+
+/// Value expected for a config key
+#[derive(Debug, Clone, Copy)]
+enum ValueKind {
+    Int(i64, i64),
+    /// Exclusive minimum, inclusive maximum
+    Float(f64, f64),
+    Bool,
+    Shortcut,
+    Text,
+    Parsed(fn(&str) -> Result<()>),
+}
+
+fn parse_source_metadata(value: &str) -> Result<()> {
+    value.parse::<SourceMetadataPolicy>().map(|_| ())
+}
+
+fn parse_template(value: &str) -> Result<()> {
+    value.parse::<OutputTemplate>().map(|_| ())
+}
+
+fn parse_interpolation(value: &str) -> Result<()> {
+    value.parse::<Interpolation>().map(|_| ())
+}
+
+fn parse_window(value: &str) -> Result<()> {
+    parse_crop_window(value).map(|_| ())
+}
+
+fn parse_format(value: &str) -> Result<()> {
+    let format = value.trim_start_matches('.').to_lowercase();
+    match format == "input" || IMAGE_EXTENSIONS.contains(&format.as_str()) {
+        true => Ok(()),
+        false => Err(anyhow::anyhow!(
+            "Unknown format {value:?}, expected 'input' or an image extension (png, jpg, tif...)"
+        )),
+    }
+}
+
+fn parse_subsampling(value: &str) -> Result<()> {
+    value.parse::<ChromaSubsampling>().map(|_| ())
+}
+
+fn parse_tiff_compression(value: &str) -> Result<()> {
+    value.parse::<TiffCompression>().map(|_| ())
+}
+
+/// Every known `section.key` of the config files
+const SCHEMA: [(&str, &str, ValueKind); 27] = [
+    ("crop_parameters", "out_x", ValueKind::Int(1, 20000)),
+    ("crop_parameters", "out_y", ValueKind::Int(1, 20000)),
+    ("crop_parameters", "zoom", ValueKind::Float(0., 10.)),
+    (
+        "crop_parameters",
+        "crop_window",
+        ValueKind::Parsed(parse_window),
+    ),
+    (
+        "crop_parameters",
+        "interpolation",
+        ValueKind::Parsed(parse_interpolation),
+    ),
+    ("shortcuts", "next_file", ValueKind::Shortcut),
+    ("shortcuts", "previous_file", ValueKind::Shortcut),
+    ("shortcuts", "increase_zoom", ValueKind::Shortcut),
+    ("shortcuts", "decrease_zoom", ValueKind::Shortcut),
+    ("shortcuts", "crop_image", ValueKind::Shortcut),
+    ("shortcuts", "select_input_dir", ValueKind::Shortcut),
+    ("shortcuts", "select_output_dir", ValueKind::Shortcut),
+    ("shortcuts", "save_crop_image", ValueKind::Shortcut),
+    ("output", "write_sidecar", ValueKind::Bool),
+    ("output", "embed_metadata", ValueKind::Bool),
+    (
+        "output",
+        "source_metadata",
+        ValueKind::Parsed(parse_source_metadata),
+    ),
+    (
+        "output",
+        "gui_filename_template",
+        ValueKind::Parsed(parse_template),
+    ),
+    (
+        "output",
+        "cli_filename_template",
+        ValueKind::Parsed(parse_template),
+    ),
+    ("encoding", "format", ValueKind::Parsed(parse_format)),
+    ("encoding", "jpeg_quality", ValueKind::Int(0, 100)),
+    (
+        "encoding",
+        "jpeg_subsampling",
+        ValueKind::Parsed(parse_subsampling),
+    ),
+    ("encoding", "png_compression", ValueKind::Int(0, 9)),
+    (
+        "encoding",
+        "tiff_compression",
+        ValueKind::Parsed(parse_tiff_compression),
+    ),
+    ("card", "name", ValueKind::Text),
+    ("card", "ref_width_mm", ValueKind::Float(0., 1000.)),
+    ("card", "ref_height_mm", ValueKind::Float(0., 1000.)),
+    ("card", "marker_size_mm", ValueKind::Float(0., 1000.)),
+];
+
+/// Keys of the `[preset.<name>]` sections
+const PRESET_SCHEMA: [(&str, ValueKind); 9] = [
+    ("description", ValueKind::Text),
+    ("out_x", ValueKind::Int(1, 20000)),
+    ("out_y", ValueKind::Int(1, 20000)),
+    ("px_per_mm", ValueKind::Float(0., 1000.)),
+    ("zoom", ValueKind::Float(0., 10.)),
+    ("crop_window", ValueKind::Parsed(parse_window)),
+    ("interpolation", ValueKind::Parsed(parse_interpolation)),
+    ("format", ValueKind::Parsed(parse_format)),
+    ("filename_template", ValueKind::Parsed(parse_template)),
+];
+
+/// Keys of the GUI shortcuts, e.g. 'S', 'Space' or 'ArrowDown'. Returns the key and its name.
+#[cfg(feature = "gui")]
+pub fn parse_shortcut(value: &str) -> Option<(Key, String)> {
+    let mut chars = value.trim().chars();
+    let name: String = match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => return None,
+    };
+    serde_json::from_str::<Key>(&format!("\"{}\"", name))
+        .ok()
+        .map(|key| (key, name))
+}
+
+fn check_value(kind: ValueKind, value: &str) -> Result<(), String> {
+    match kind {
+        ValueKind::Int(min, max) => match value.parse::<i64>() {
+            Ok(number) if (min..=max).contains(&number) => Ok(()),
+            Ok(_) => Err(format!("{value} is out of range, expected {min} to {max}")),
+            Err(_) => Err(format!("{value:?} is not an integer")),
+        },
+        ValueKind::Float(min, max) => match value.parse::<f64>() {
+            Ok(number) if number > min && number <= max => Ok(()),
+            Ok(_) => Err(format!("{value} is out of range, expected more than {min} and at most {max}")),
+            Err(_) => Err(format!("{value:?} is not a number")),
+        },
+        ValueKind::Bool => match value.to_lowercase().as_str() {
+            "true" | "false" => Ok(()),
+            _ => Err(format!("{value:?} is not 'true' or 'false'")),
+        },
+        #[cfg(feature = "gui")]
+        ValueKind::Shortcut => match parse_shortcut(value) {
+            Some(_) => Ok(()),
+            None => Err(format!(
+                "{value:?} is not a key, expected a single key such as 'S', 'Space', 'Enter' or 'ArrowDown' (modifiers such as Ctrl are not supported)"
+            )),
+        },
+        // Only the GUI knows the key names, the other builds never read the shortcuts
+        #[cfg(not(feature = "gui"))]
+        ValueKind::Shortcut => check_value(ValueKind::Text, value),
+        ValueKind::Text => match value.is_empty() {
+            true => Err(String::from("the value is empty")),
+            false => Ok(()),
+        },
+        ValueKind::Parsed(parse) => parse(value).map_err(|err| err.to_string()),
+    }
+}
+
+/// Checks a value against the schema, e.g. for settings given outside of a config file
+pub fn check_entry(section: &str, key: &str, value: &str) -> Result<(), String> {
+    match SCHEMA
+        .iter()
+        .find(|(schema_section, schema_key, _)| *schema_section == section && *schema_key == key)
+    {
+        Some((_, _, kind)) => check_value(*kind, value),
+        None => Err(format!("unknown setting {section}.{key}")),
+    }
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, char_a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, char_b) in b.iter().enumerate() {
+            let substitution = previous[j] + (char_a != *char_b) as usize;
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The entry is ignored and its default is used
+    Error,
+    /// The entry has no effect
+    Warning,
+}
+
+/// Problem found in a config file, reported as 'path:line: message'
+#[derive(Debug, Clone)]
+pub struct ConfigIssue {
+    pub path: PathBuf,
+    pub line: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.path.display(), self.line, self.message)
+    }
+}
+
+/// Checks every line of a config file against the schema. Returns the issues and the
+/// `(section, key)` of the invalid entries, which must be ignored.
+pub fn validate_file(path: &Path, content: &str) -> (Vec<ConfigIssue>, Vec<(String, String)>) {
+    let mut issues = Vec::new();
+    let mut invalid_entries = Vec::new();
+    let mut issue = |line: usize, severity: Severity, message: String| {
+        issues.push(ConfigIssue {
+            path: path.to_path_buf(),
+            line,
+            severity,
+            message,
+        })
+    };
+    let suggestion = |name: &str, candidates: &[&str]| {
+        candidates
+            .iter()
+            .filter(|candidate| edit_distance(name, candidate) <= 2)
+            .min_by_key(|candidate| edit_distance(name, candidate))
+            .map(|candidate| format!(", did you mean '{candidate}'?"))
+            .unwrap_or_default()
+    };
+    let mut sections: Vec<&str> = SCHEMA.iter().map(|(section, _, _)| *section).collect();
+    sections.dedup();
+    // Keys and values expected in a section, `None` for unknown sections
+    let section_keys = |section: &str| -> Option<Vec<(&str, ValueKind)>> {
+        match section.strip_prefix(PRESET_SECTION_PREFIX) {
+            Some(name) if !name.is_empty() => Some(PRESET_SCHEMA.to_vec()),
+            _ if sections.contains(&section) => Some(
+                SCHEMA
+                    .iter()
+                    .filter(|(schema_section, _, _)| *schema_section == section)
+                    .map(|(_, schema_key, kind)| (*schema_key, *kind))
+                    .collect(),
+            ),
+            _ => None,
+        }
+    };
+
+    let mut section = String::from("default");
+    for (i, line) in content.lines().enumerate() {
+        let line_number = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') {
+            match line
+                .strip_prefix('[')
+                .and_then(|line| line.strip_suffix(']'))
+            {
+                Some(name) => {
+                    section = name.trim().to_lowercase();
+                    if section_keys(&section).is_none() {
+                        let hint = suggestion(&section, &sections);
+                        issue(
+                            line_number,
+                            Severity::Warning,
+                            format!("Unknown section [{section}]{hint}"),
+                        );
+                    }
+                }
+                None => issue(
+                    line_number,
+                    Severity::Error,
+                    format!("Invalid section header {line:?}"),
+                ),
+            }
+            continue;
+        }
+        let Some((key, value)) = line.split_once(['=', ':']) else {
+            issue(
+                line_number,
+                Severity::Error,
+                format!("Expected 'key = value', got {line:?}"),
+            );
+            continue;
+        };
+        let (key, value) = (key.trim().to_lowercase(), value.trim());
+        let Some(section_keys) = section_keys(&section) else {
+            if section == "default" {
+                issue(
+                    line_number,
+                    Severity::Warning,
+                    format!("Key '{key}' is outside of any section"),
+                );
+            }
+            // Otherwise already reported with its section
+            continue;
+        };
+
+        let keys: Vec<&str> = section_keys
+            .iter()
+            .map(|(schema_key, _)| *schema_key)
+            .collect();
+        let Some((_, kind)) = section_keys
+            .iter()
+            .find(|(schema_key, _)| *schema_key == key)
+        else {
+            let hint = suggestion(&key, &keys);
+            issue(
+                line_number,
+                Severity::Warning,
+                format!("Unknown key '{key}' in [{section}]{hint}"),
+            );
+            continue;
+        };
+        if let Err(message) = check_value(*kind, value) {
+            issue(
+                line_number,
+                Severity::Error,
+                format!("Invalid value for {section}.{key}: {message}, the default is used"),
+            );
+            invalid_entries.push((section.clone(), key));
+        }
+    }
+    (issues, invalid_entries)
+}
+
+/// Reads a config file, validates it and drops its invalid entries
+pub fn load_validated(path: &Path) -> Result<(Ini, Vec<ConfigIssue>)> {
+    let content = std::fs::read_to_string(path)?;
+    let mut config = Ini::new();
+    config
+        .read(content.clone())
+        .map_err(|err| anyhow::anyhow!(err))?;
+    let (issues, invalid_entries) = validate_file(path, &content);
+    for (section, key) in invalid_entries {
+        config.remove_key(&section, &key);
+    }
+    Ok((config, issues))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_are_checked_against_the_schema() {
+        for (section, key, value) in [
+            ("crop_parameters", "zoom", "1.5"),
+            ("crop_parameters", "out_x", "800"),
+            ("crop_parameters", "crop_window", "0, 0, 600, 400"),
+            ("crop_parameters", "interpolation", "lanczos4"),
+            ("output", "write_sidecar", "TRUE"),
+            ("output", "cli_filename_template", "{date}/{stem}.{ext}"),
+            ("encoding", "jpeg_quality", "0"),
+            ("card", "name", "test_card_v4"),
+        ] {
+            assert_eq!(
+                check_entry(section, key, value),
+                Ok(()),
+                "{section}.{key} = {value}"
+            );
+        }
+        for (section, key, value) in [
+            ("crop_parameters", "zoom", "0"),
+            ("crop_parameters", "zoom", "abc"),
+            ("crop_parameters", "out_x", "20001"),
+            ("crop_parameters", "crop_window", "0 0 600"),
+            ("crop_parameters", "interpolation", "bogus"),
+            ("output", "write_sidecar", "yes"),
+            ("output", "cli_filename_template", "../{stem}.{ext}"),
+            ("encoding", "jpeg_quality", "101"),
+            ("card", "name", ""),
+        ] {
+            assert!(
+                check_entry(section, key, value).is_err(),
+                "{section}.{key} = {value} was accepted"
+            );
+        }
+        assert_eq!(
+            check_entry("crop", "zoom", "1"),
+            Err(String::from("unknown setting crop.zoom"))
+        );
+    }
+
+    #[test]
+    fn file_issues_have_their_line_and_severity() {
+        let content = "\
+; comment
+stray = 1
+[crop_parameters]
+zoom = 0
+out_x = 800
+zom = 2
+[crop_paramters]
+out_y = 300
+[encoding]
+jpeg_quality = abc
+[preset.forewing]
+px_per_mm = 40
+zoom = abc
+[broken
+orphan line
+";
+        let (issues, invalid_entries) = validate_file(Path::new("config.ini"), content);
+        let found: Vec<(usize, Severity)> = issues
+            .iter()
+            .map(|issue| (issue.line, issue.severity))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (2, Severity::Warning),
+                (4, Severity::Error),
+                (6, Severity::Warning),
+                (7, Severity::Warning),
+                (10, Severity::Error),
+                (13, Severity::Error),
+                (14, Severity::Error),
+                (15, Severity::Error),
+            ]
+        );
+        assert!(
+            issues[2].message.ends_with("did you mean 'zoom'?"),
+            "{}",
+            issues[2]
+        );
+        assert!(
+            issues[3]
+                .message
+                .ends_with("did you mean 'crop_parameters'?"),
+            "{}",
+            issues[3]
+        );
+        assert!(issues[1].to_string().starts_with("config.ini:4: "));
+        assert_eq!(
+            invalid_entries,
+            [
+                (String::from("crop_parameters"), String::from("zoom")),
+                (String::from("encoding"), String::from("jpeg_quality")),
+                (String::from("preset.forewing"), String::from("zoom")),
+            ]
+        );
+    }
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("zoom", "zoom"), 0);
+        assert_eq!(edit_distance("", "out_x"), 5);
+    }
+}
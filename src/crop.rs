@@ -0,0 +1,257 @@
+use anyhow::Result;
+use opencv::{
+    core::{Mat, Point2f, Rect, Size, Vector},
+    objdetect::ArucoDetector,
+    prelude::*,
+    types::VectorOfPoint2f,
+};
+
+use crate::marker_utils::marker_processing::*;
+use crate::pipeline_error::{kind_error, ErrorKind, WithErrorKind};
+use std::str::FromStr;
+
+/// Geometry and resampling of a crop
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CropParameters {
+    pub out_size: Size,
+    pub zoom: f32,
+    /// Whole `out_size` from the origin when not set
+    pub crop_window: Option<Rect>,
+    pub interpolation: Interpolation,
+}
+
+impl CropParameters {
+    pub fn crop_window(&self) -> Rect {
+        self.crop_window.unwrap_or(get_crop_window(&self.out_size))
+    }
+}
+
+/// Implementation of the perspective warp
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WarpBackend {
+    #[default]
+    OpenCv,
+    /// `rust_warp`, only in the builds with the `rust-warp` feature
+    Rust,
+}
+
+impl FromStr for WarpBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "opencv" => Ok(WarpBackend::OpenCv),
+            "rust" if cfg!(feature = "rust-warp") => Ok(WarpBackend::Rust),
+            "rust" => Err(anyhow::anyhow!(
+                "This build has no pure Rust backend (feature 'rust-warp')"
+            )),
+            _ => Err(anyhow::anyhow!(
+                "Unknown backend {s:?}, expected 'opencv' or 'rust'"
+            )),
+        }
+    }
+}
+
+/// Crop of an image in memory and how it was made. Marker corners and source points are in
+/// the coordinates of the input image, before the upscaling done by `resize_if_larger_dims`.
+pub struct CropResult {
+    pub image: Mat,
+    pub marker_ids: Vec<i32>,
+    pub marker_corners: Vec<[[f32; 2]; 4]>,
+    pub rejected_count: usize,
+    /// Outer corners of markers #0 to #3
+    pub source_points: [[f32; 2]; 4],
+    pub working_scale: (f64, f64),
+    /// Perspective transform from the working image to the corrected image
+    pub homography: Mat,
+    pub crop_window: Rect,
+    /// Specimen ID of the QR code on the card
+    pub card_id: Option<String>,
+}
+
+/// Outer corners of markers #0 to #3, fails when the 4 markers were not all found
+pub fn get_reference_points(
+    markers_coor: &MarkersVec,
+    markers_id: &Vector<i32>,
+    rejected_markers: &MarkersVec,
+) -> Result<VectorOfPoint2f> {
+    if markers_coor.len() != 4 {
+        let rejected_marker_positions: VectorOfPoint2f = rejected_markers
+            .iter()
+            .map(|p_vec| p_vec.iter().fold(Point2f::default(), |sum_p, p| sum_p + p) / 4.)
+            .collect();
+        return Err(anyhow::anyhow!("Error: {:?} markers were found instead of 4.\nFollowing markers were rejected: {:?}\nThe image may be too blurred (i.e. not enough contrast at markers positions) or there may be stray reflections on the markers (makers not black and white). Also check that markers 0 to 4 are present on the picture.",
+            markers_coor.len(), rejected_marker_positions
+        ))
+        .with_kind(ErrorKind::MarkersNotFound);
+    }
+    parse_markers(markers_coor, markers_id).with_kind(ErrorKind::MarkersNotFound)
+}
+
+/// Detects the markers with `detector`, corrects the perspective and crops
+pub fn crop_image(
+    detector: &ArucoDetector,
+    img: Mat,
+    parameters: &CropParameters,
+) -> Result<CropResult> {
+    let working_scale = get_resize_ratios(&img.size()?, &parameters.out_size);
+    let img = resize_if_larger_dims(img, &parameters.out_size)?;
+    let (markers_coor, markers_id, rejected_markers) = detect_markers(detector, &img)?;
+    let ordered_points = get_reference_points(&markers_coor, &markers_id, &rejected_markers)?;
+    let card_id = read_card_id(&img);
+
+    let homography =
+        get_correction_matrix(&ordered_points, &parameters.out_size, &parameters.zoom)?;
+    let image = warp_and_crop(
+        &img,
+        &ordered_points,
+        &homography,
+        parameters,
+        WarpBackend::OpenCv,
+    )?;
+    let crop_window = parameters.crop_window();
+
+    let (width_ratio, height_ratio) = working_scale;
+    let mut source_points = [[0f32; 2]; 4];
+    for (source, point) in source_points.iter_mut().zip(ordered_points.iter()) {
+        *source = [
+            (point.x as f64 / width_ratio) as f32,
+            (point.y as f64 / height_ratio) as f32,
+        ];
+    }
+    Ok(CropResult {
+        image,
+        marker_ids: markers_id.to_vec(),
+        marker_corners: markers_to_arrays(&markers_coor, working_scale),
+        rejected_count: rejected_markers.len(),
+        source_points,
+        working_scale,
+        homography,
+        crop_window,
+        card_id,
+    })
+}
+
+/// Corrects the perspective of the working image with `homography` (from `ordered_points`) and
+/// keeps the crop window
+pub fn warp_and_crop(
+    img: &Mat,
+    ordered_points: &VectorOfPoint2f,
+    homography: &Mat,
+    parameters: &CropParameters,
+    backend: WarpBackend,
+) -> Result<Mat> {
+    if backend == WarpBackend::Rust {
+        return rust_warp_and_crop(img, ordered_points, parameters);
+    }
+    let warped_image = warp_image(img, homography, parameters.interpolation)?;
+    let crop_window = parameters.crop_window();
+    Ok(Mat::roi(&warped_image, crop_window)
+        .map_err(|_| {
+            kind_error(
+                ErrorKind::InvalidInput,
+                format!(
+                    "The crop window {:?} does not fit in the corrected image {:?}",
+                    crop_window,
+                    warped_image.size().unwrap_or_default()
+                ),
+            )
+        })?
+        .try_clone()?)
+}
+
+#[cfg(feature = "rust-warp")]
+impl From<Interpolation> for crate::rust_warp::Sampling {
+    fn from(interpolation: Interpolation) -> Self {
+        use crate::rust_warp::Sampling;
+        match interpolation {
+            Interpolation::Nearest => Sampling::Nearest,
+            // OpenCV also warps with a bilinear kernel for INTER_AREA
+            Interpolation::Linear | Interpolation::Area => Sampling::Bilinear,
+            Interpolation::Cubic => Sampling::Bicubic,
+            Interpolation::Lanczos4 => Sampling::Lanczos,
+        }
+    }
+}
+
+#[cfg(feature = "rust-warp")]
+fn rust_warp_and_crop(
+    img: &Mat,
+    ordered_points: &VectorOfPoint2f,
+    parameters: &CropParameters,
+) -> Result<Mat> {
+    use image::{Luma, Rgb, Rgba};
+    use opencv::core::{CV_16U, CV_8U};
+    let mut source_points = [[0f32; 2]; 4];
+    for (source, point) in source_points.iter_mut().zip(ordered_points.iter()) {
+        *source = [point.x, point.y];
+    }
+    // The channels are warped separately, their order (BGR) does not matter
+    match (img.depth(), img.channels()) {
+        (CV_8U, 1) => rust_warp_typed::<Luma<u8>>(img, &source_points, parameters),
+        (CV_8U, 3) => rust_warp_typed::<Rgb<u8>>(img, &source_points, parameters),
+        (CV_8U, 4) => rust_warp_typed::<Rgba<u8>>(img, &source_points, parameters),
+        (CV_16U, 1) => rust_warp_typed::<Luma<u16>>(img, &source_points, parameters),
+        (CV_16U, 3) => rust_warp_typed::<Rgb<u16>>(img, &source_points, parameters),
+        (CV_16U, 4) => rust_warp_typed::<Rgba<u16>>(img, &source_points, parameters),
+        (depth, channels) => Err(kind_error(
+            ErrorKind::InvalidInput,
+            format!("The Rust backend does not support images of OpenCV depth {depth} with {channels} channels"),
+        )),
+    }
+}
+
+#[cfg(feature = "rust-warp")]
+fn rust_warp_typed<P>(
+    img: &Mat,
+    source_points: &[[f32; 2]; 4],
+    parameters: &CropParameters,
+) -> Result<Mat>
+where
+    P: image::Pixel,
+    P::Subpixel: opencv::core::DataType,
+{
+    let single_channel = match img.is_continuous() {
+        true => img.reshape(1, 0)?,
+        false => img.try_clone()?.reshape(1, 0)?,
+    };
+    let data = single_channel.data_typed::<P::Subpixel>()?.to_vec();
+    let buffer = image::ImageBuffer::<P, _>::from_raw(img.cols() as u32, img.rows() as u32, data)
+        .ok_or_else(|| anyhow::anyhow!("The image data does not match its size"))?;
+    let out_size = (
+        parameters.out_size.width as u32,
+        parameters.out_size.height as u32,
+    );
+    let crop_window = parameters.crop_window.map(|window| {
+        (
+            window.x,
+            window.y,
+            window.width as u32,
+            window.height as u32,
+        )
+    });
+    let (image, _) = crate::rust_warp::rectify(
+        &buffer,
+        source_points,
+        out_size,
+        parameters.zoom,
+        crop_window,
+        parameters.interpolation.into(),
+    )?;
+    let rows = image.height() as i32;
+    Ok(Mat::from_slice(image.as_raw())?
+        .reshape(P::CHANNEL_COUNT as i32, rows)?
+        .try_clone()?)
+}
+
+#[cfg(not(feature = "rust-warp"))]
+fn rust_warp_and_crop(
+    _img: &Mat,
+    _ordered_points: &VectorOfPoint2f,
+    _parameters: &CropParameters,
+) -> Result<Mat> {
+    Err(kind_error(
+        ErrorKind::InvalidInput,
+        "This build has no pure Rust backend (feature 'rust-warp')",
+    ))
+}
@@ -0,0 +1,174 @@
+use anyhow::Result;
+use configparser::ini::Ini;
+use opencv::{core::Vector, imgcodecs};
+use std::{path::Path, str::FromStr};
+use strum_macros::{Display, EnumIter};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Display, EnumIter)]
+pub enum ChromaSubsampling {
+    #[strum(serialize = "444")]
+    S444,
+    #[strum(serialize = "422")]
+    S422,
+    #[default]
+    #[strum(serialize = "420")]
+    S420,
+}
+
+impl FromStr for ChromaSubsampling {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().replace(':', "").as_str() {
+            "444" => Ok(ChromaSubsampling::S444),
+            "422" => Ok(ChromaSubsampling::S422),
+            "420" => Ok(ChromaSubsampling::S420),
+            _ => Err(anyhow::anyhow!(
+                "Unknown chroma subsampling {s:?}, expected '444', '422' or '420'"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Display, EnumIter)]
+pub enum TiffCompression {
+    #[strum(serialize = "none")]
+    None,
+    #[default]
+    #[strum(serialize = "lzw")]
+    Lzw,
+    #[strum(serialize = "deflate")]
+    Deflate,
+    #[strum(serialize = "packbits")]
+    PackBits,
+}
+
+impl TiffCompression {
+    /// libtiff compression codes
+    fn code(&self) -> i32 {
+        match self {
+            TiffCompression::None => 1,
+            TiffCompression::Lzw => 5,
+            TiffCompression::Deflate => 8,
+            TiffCompression::PackBits => 32773,
+        }
+    }
+}
+
+impl FromStr for TiffCompression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "none" => Ok(TiffCompression::None),
+            "lzw" => Ok(TiffCompression::Lzw),
+            "deflate" | "zip" => Ok(TiffCompression::Deflate),
+            "packbits" => Ok(TiffCompression::PackBits),
+            _ => Err(anyhow::anyhow!(
+                "Unknown TIFF compression {s:?}, expected 'none', 'lzw', 'deflate' or 'packbits'"
+            )),
+        }
+    }
+}
+
+/// Output formats offered in the GUI, `None` keeps the extension of the input
+pub const OUTPUT_FORMATS: [Option<&str>; 6] = [
+    None,
+    Some("png"),
+    Some("jpg"),
+    Some("tif"),
+    Some("webp"),
+    Some("bmp"),
+];
+
+/// How output images are encoded by `imgcodecs::imencode`
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncodingOptions {
+    /// Output extension, `None` keeps the extension of the input
+    pub format: Option<String>,
+    /// 0 to 100
+    pub jpeg_quality: i32,
+    pub jpeg_subsampling: ChromaSubsampling,
+    /// 0 (fastest, largest) to 9 (slowest, smallest)
+    pub png_compression: i32,
+    pub tiff_compression: TiffCompression,
+}
+
+impl Default for EncodingOptions {
+    fn default() -> Self {
+        EncodingOptions {
+            format: None,
+            jpeg_quality: 95,
+            jpeg_subsampling: ChromaSubsampling::default(),
+            png_compression: 3,
+            tiff_compression: TiffCompression::default(),
+        }
+    }
+}
+
+impl EncodingOptions {
+    pub fn from_config(config: &Ini) -> Self {
+        let default = EncodingOptions::default();
+        EncodingOptions {
+            format: config
+                .get("encoding", "format")
+                .map(|format| format.trim().trim_start_matches('.').to_lowercase())
+                .filter(|format| !format.is_empty() && format != "input"),
+            jpeg_quality: config
+                .getint("encoding", "jpeg_quality")
+                .unwrap_or(None)
+                .map(|quality| quality.clamp(0, 100) as i32)
+                .unwrap_or(default.jpeg_quality),
+            jpeg_subsampling: config
+                .get("encoding", "jpeg_subsampling")
+                .and_then(|subsampling| subsampling.parse().ok())
+                .unwrap_or(default.jpeg_subsampling),
+            png_compression: config
+                .getint("encoding", "png_compression")
+                .unwrap_or(None)
+                .map(|compression| compression.clamp(0, 9) as i32)
+                .unwrap_or(default.png_compression),
+            tiff_compression: config
+                .get("encoding", "tiff_compression")
+                .and_then(|compression| compression.parse().ok())
+                .unwrap_or(default.tiff_compression),
+        }
+    }
+
+    /// Extension of the output for a given input, without the dot
+    pub fn output_extension(&self, input_path: &Path) -> String {
+        match self.format.as_ref() {
+            Some(format) => format.clone(),
+            None => input_path
+                .extension()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string(),
+        }
+    }
+
+    /// Parameters for `imgcodecs::imencode`, picked from the output `extension` (without the dot)
+    pub fn imwrite_params(&self, extension: &str) -> Vector<i32> {
+        match extension.to_lowercase().as_str() {
+            "jpg" | "jpeg" | "jpe" => Vector::from_slice(&[
+                imgcodecs::IMWRITE_JPEG_QUALITY,
+                self.jpeg_quality,
+                imgcodecs::IMWRITE_JPEG_SAMPLING_FACTOR,
+                match self.jpeg_subsampling {
+                    ChromaSubsampling::S444 => imgcodecs::IMWRITE_JPEG_SAMPLING_FACTOR_444,
+                    ChromaSubsampling::S422 => imgcodecs::IMWRITE_JPEG_SAMPLING_FACTOR_422,
+                    ChromaSubsampling::S420 => imgcodecs::IMWRITE_JPEG_SAMPLING_FACTOR_420,
+                },
+            ]),
+            "png" => {
+                Vector::from_slice(&[imgcodecs::IMWRITE_PNG_COMPRESSION, self.png_compression])
+            }
+            "tif" | "tiff" => Vector::from_slice(&[
+                imgcodecs::IMWRITE_TIFF_COMPRESSION,
+                self.tiff_compression.code(),
+            ]),
+            "webp" => Vector::from_slice(&[imgcodecs::IMWRITE_WEBP_QUALITY, self.jpeg_quality]),
+            _ => Vector::new(),
+        }
+    }
+}
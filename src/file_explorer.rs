@@ -1,11 +1,17 @@
 use anyhow::Result;
 
-use egui::{Color32, Label, RichText, ScrollArea, SelectableLabel, TextEdit, Ui, Vec2};
+use egui::{
+    Color32, ComboBox, Label, RichText, ScrollArea, SelectableLabel, Slider, TextEdit, Ui, Vec2,
+};
 use rfd::FileDialog;
 use same_file::is_same_file;
 use std::cmp::min;
 use std::fs::DirEntry;
 use std::path::{Path, PathBuf};
+use strum::IntoEnumIterator;
+
+use idmybee::encoding::{ChromaSubsampling, EncodingOptions, TiffCompression, OUTPUT_FORMATS};
+use idmybee::image_io::IMAGE_EXTENSIONS;
 
 pub struct FileExplorer<'a> {
     pub current_dir: PathBuf,
@@ -14,6 +20,7 @@ pub struct FileExplorer<'a> {
     pub selected_file_index: Option<usize>,
     pub output_img_name: String,
     pub output_img_dir: PathBuf,
+    pub encoding: EncodingOptions,
     dir_vec: Vec<PathBuf>,
     file_vec: Vec<PathBuf>,
     dirnames: Vec<String>,
@@ -31,14 +38,12 @@ impl FileExplorer<'_> {
             selected_file_index: None,
             output_img_name: String::new(),
             output_img_dir: PathBuf::new(),
+            encoding: EncodingOptions::default(),
             dir_vec: Vec::new(),
             file_vec: Vec::new(),
             dirnames: Vec::new(),
             filenames: Vec::new(),
-            allowed_extensions: vec![
-                "bmp", "dib", "jpeg", "jpg", "jpe", "jp2", "png", "webp", "avif", "pbm", "pgm",
-                "ppm", "pxm", "pnm", "pfm", "sr", "ras", "tiff", "tif", "exr", "hdr", "pic",
-            ],
+            allowed_extensions: IMAGE_EXTENSIONS.to_vec(),
             err: Ok(()),
         };
         fe.change_dir(&fe.current_dir.clone());
@@ -108,11 +113,8 @@ impl FileExplorer<'_> {
         )
     }
 
-    pub fn get_filepath(&self) -> Option<String> {
-        // Si Some alors on transforme en Some(str) sinon on renvoie None
-        self.selected_file
-            .as_ref()
-            .map(|path| path.display().to_string())
+    pub fn get_filepath(&self) -> Option<PathBuf> {
+        self.selected_file.clone()
     }
 
     pub fn previous_file(&mut self) {
@@ -273,28 +275,12 @@ impl FileExplorer<'_> {
                     self.selected_file = Some(file_path.clone());
                     self.selected_file_index = Some(i);
                     is_file_clicked = true;
-                    self.output_img_name = self.get_default_output_filename();
                 };
             }
         });
         is_file_clicked
     }
 
-    pub fn get_default_output_filename(&self) -> String {
-        let mut out_filename = String::new();
-        if let Some(filename) = self.selected_file.as_ref() {
-            let ext = filename
-                .extension()
-                .unwrap_or_default()
-                .to_string_lossy()
-                .to_string();
-            out_filename.push_str(&filename.file_stem().unwrap_or_default().to_string_lossy());
-            out_filename.push_str("_crop.");
-            out_filename.push_str(&ext);
-        }
-        out_filename
-    }
-
     pub fn img_saving_ui(&mut self, ui: &mut egui::Ui, is_visible: bool) -> bool {
         let mut must_save = false;
         ui.add_visible_ui(is_visible, |ui| {
@@ -327,8 +313,62 @@ impl FileExplorer<'_> {
                         TextEdit::singleline(&mut self.output_img_name),
                     );
                 });
+                ui.separator();
+                self.save_options_ui(ui);
             });
         });
         must_save
     }
+
+    fn save_options_ui(&mut self, ui: &mut egui::Ui) {
+        ui.collapsing("Save options", |ui| {
+            let previous_format = self.encoding.format.clone();
+            ComboBox::from_label("Format")
+                .selected_text(self.encoding.format.as_deref().unwrap_or("Same as input"))
+                .show_ui(ui, |ui| {
+                    for format in OUTPUT_FORMATS {
+                        ui.selectable_value(
+                            &mut self.encoding.format,
+                            format.map(String::from),
+                            format.unwrap_or("Same as input"),
+                        );
+                    }
+                });
+            if self.encoding.format != previous_format && !self.output_img_name.is_empty() {
+                let ext = match self.selected_file.as_ref() {
+                    Some(filename) => self.encoding.output_extension(filename),
+                    None => self.encoding.format.clone().unwrap_or_default(),
+                };
+                self.output_img_name = Path::new(&self.output_img_name)
+                    .with_extension(ext)
+                    .to_string_lossy()
+                    .to_string();
+            }
+
+            ui.add(Slider::new(&mut self.encoding.jpeg_quality, 0..=100).text("JPEG/WebP quality"));
+            ComboBox::from_label("JPEG chroma subsampling")
+                .selected_text(self.encoding.jpeg_subsampling.to_string())
+                .show_ui(ui, |ui| {
+                    for subsampling in ChromaSubsampling::iter() {
+                        ui.selectable_value(
+                            &mut self.encoding.jpeg_subsampling,
+                            subsampling,
+                            subsampling.to_string(),
+                        );
+                    }
+                });
+            ui.add(Slider::new(&mut self.encoding.png_compression, 0..=9).text("PNG compression"));
+            ComboBox::from_label("TIFF compression")
+                .selected_text(self.encoding.tiff_compression.to_string())
+                .show_ui(ui, |ui| {
+                    for compression in TiffCompression::iter() {
+                        ui.selectable_value(
+                            &mut self.encoding.tiff_compression,
+                            compression,
+                            compression.to_string(),
+                        );
+                    }
+                });
+        });
+    }
 }
@@ -1,123 +1,549 @@
-use argparse::{ArgumentParser, Store, StoreTrue, List };
-use anyhow::{Error, Result};
-use opencv::{
-    core::{Mat, Point2f, Rect, Vector, Size},
-    imgcodecs,
-    types::VectorOfPoint2f,
+use anyhow::Result;
+use argparse::{ArgumentParser, List, Store, StoreTrue};
+use opencv::core::Size;
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+    time::Instant,
 };
 
-mod marker_utils;
-use marker_utils::marker_processing::*;
+use idmybee::command_line::take_option;
+use idmybee::pipeline_error::{error_kind, kind_error, ErrorKind, WithErrorKind};
+use idmybee::settings::{Settings, SETTINGS_HELP};
 
-fn main() -> Result<()>{
-    // let mut verbose = false;
-    let mut input_path = String::new();
-    // let mut opt_output_path: Option<String> = None;
-    let mut output_paths: Vec<String> = vec![];
-    let mut out_dim = vec![600, 400];
-    let mut show = false;
-    let mut zoom_vec : Vec<f32> = vec![1.];
+mod batch;
+use batch::{collect_inputs, run_batch, BatchSummary, MemoryBudget};
 
-    {
-        let mut parser = ArgumentParser::new();
-        parser.set_description("This tools is used to preprcocess photos taken with the ID My Bee protocol. It automatically crop and correct the photo angle.");
-
-        parser.refer(&mut input_path)
-            .add_option(&["-i", "--img"], Store,
-            "Input image path to preprocess.")
-            .required();
-        
-        parser.refer(&mut output_paths)
-            .add_option(&["-o", "--img_out"], List,
-            "Output preprocessed image path.  /!\\ The number of output files given must be 0 or the same as the number of zoom levels. If not given, the default output file(s) will follow the pattern: '[input_folder]/[base input filename]_preproc_z[zoom level].[input file extension]'");
-
-        parser.refer(&mut out_dim)
-            .add_option(&["-d", "--out_dim"], List,
-            "Output image dimensions width height (e.g. default is '-d 600 400').");
-
-        parser.refer(&mut zoom_vec)
-            .add_option(&["-z", "--zoom"], List,
-            "The zoom to apply (can be float numbers). Multiple values can be used. Default is 1.");
-        
-        parser.refer(&mut show)
-            .add_option(&["-s", "--show"], StoreTrue,
-            "Show the image in a window instead of saving it. Once the windows is open, press any key to exit, Ctrl-C to copy the image and Ctrl-S to save it manually.");
-            
-        parser.parse_args_or_exit();
-    }
-
-    println!("Input path: {input_path:?}");
-    
-    if !output_paths.is_empty() && output_paths.len() != zoom_vec.len() {
-        return Err(
-            anyhow::anyhow!(
-                "Mismatch between the number of output paths (={:?}) and the number of zoom values (={:?})", 
-                output_paths.len(), 
-                zoom_vec.len()
-            )
-        )
-    }
-    
-    for (i, &zoom) in zoom_vec.iter().enumerate() {
-        match output_paths.get(i) {
-            Some(_) => (),
-            None => {
-                let (base_path, extenstion) = input_path.rsplit_once('.').ok_or(format!("Input file {input_path:?}")).map_err(Error::msg)?;
-
-                let out_path = format!("{base_path}_preproc_z{:.2}.{extenstion}", zoom).replacen('.', "-", 1);
-                println!("{base_path}_preproc_z{:.3}.{extenstion}", zoom);
-                println!("Output path was not specified so image will be written to {out_path}");
-                output_paths.push(out_path);
-            }
+mod batch_state;
+mod watch;
+
+mod report;
+use report::{duplicate_card_ids, ImageRecord, RecordStatus};
+
+mod batch_report;
+use batch_report::{write_csv, write_html};
+
+mod card_generator;
+mod overlay;
+mod synthetic_card;
+
+mod cli_card;
+mod cli_crop;
+mod cli_detect;
+mod cli_overlay;
+mod cli_synth;
+
+const USAGE: &str = "Usage: idmybee_cli <command> [options]
+
+This tools is used to preprcocess photos taken with the ID My Bee protocol.
+
+Commands:
+  crop      Detect the markers, correct the photo angle and crop it (default when no command is given)
+  detect    Only detect the markers and report their ids and positions
+  overlay   Draw the detected markers and the crop outlines on the photos, to debug a detection
+  rerender  Crop the photos again from the sidecar JSON files written by 'crop --sidecar'
+  watch     Crop the photos arriving in a directory
+  card      Generate a printable card with markers #0 to #3 (PNG, or SVG/PDF at physical scale)
+  synth     Generate synthetic photos of the card with their expected marker positions, and check the detection on them
+
+Run 'idmybee_cli <command> --help' for the options of a command. The defaults shown there are the built-in ones, config files may change them.
+
+Exit codes: 0 success, 1 unexpected error, 2 invalid arguments, 3 unreadable input, 4 markers not found, 5 invalid sidecar, 6 output not written. A batch where images failed for different reasons exits with 1.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    Crop,
+    Detect,
+    Overlay,
+    Rerender,
+    Watch,
+    Card,
+    Synth,
+}
+
+impl FromStr for Command {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "crop" => Ok(Command::Crop),
+            "detect" => Ok(Command::Detect),
+            "overlay" | "render-overlay" => Ok(Command::Overlay),
+            "rerender" => Ok(Command::Rerender),
+            "watch" => Ok(Command::Watch),
+            "card" => Ok(Command::Card),
+            "synth" | "synthetic" => Ok(Command::Synth),
+            _ => Err(kind_error(
+                ErrorKind::InvalidInput,
+                format!("Unknown command {s:?}, run 'idmybee_cli --help' for the list of commands"),
+            )),
+        }
+    }
+}
+
+/// Input or output path standing for stdin or stdout, e.g. 'crop -i - -o -' in a pipe
+const STDIO_PATH: &str = "-";
+
+/// What the CLI writes on stdout, human logs always go to stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RecordFormat {
+    Text,
+    /// A JSON array of the image records, once the batch is over
+    Json,
+    /// One JSON image record per line, as soon as each image is processed
+    Ndjson,
+}
+
+impl FromStr for RecordFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "text" => Ok(RecordFormat::Text),
+            "json" => Ok(RecordFormat::Json),
+            "ndjson" | "jsonl" => Ok(RecordFormat::Ndjson),
+            _ => Err(anyhow::anyhow!(
+                "Unknown format {s:?}, expected 'text', 'json' or 'ndjson'"
+            )),
+        }
+    }
+}
+
+/// Options of every command working on a list of images
+struct InputOptions {
+    input_args: Vec<String>,
+    recursive: bool,
+    jobs: usize,
+    max_memory_mb: u64,
+    record_format: RecordFormat,
+    /// Path of the batch reports without extension
+    report: String,
+    quiet: bool,
+}
+
+impl InputOptions {
+    /// Input arguments as paths, see `arg_path`
+    fn input_paths(&self) -> Vec<PathBuf> {
+        self.input_args
+            .iter()
+            .map(|input_arg| arg_path(input_arg))
+            .collect()
+    }
+}
+
+impl Default for InputOptions {
+    fn default() -> Self {
+        InputOptions {
+            input_args: vec![],
+            recursive: false,
+            jobs: std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1),
+            max_memory_mb: 2048,
+            record_format: RecordFormat::Text,
+            report: String::new(),
+            quiet: false,
+        }
+    }
+}
+
+fn add_input_options<'p>(
+    parser: &mut ArgumentParser<'p>,
+    options: &'p mut InputOptions,
+    inputs_help: &'p str,
+) {
+    let InputOptions {
+        input_args,
+        recursive,
+        jobs,
+        max_memory_mb,
+        record_format,
+        report,
+        quiet,
+    } = options;
+
+    parser
+        .refer(input_args)
+        .add_option(&["-i", "--img"], List, inputs_help)
+        .add_argument("inputs", List, "Same as -i/--img.")
+        .required();
+
+    parser.refer(recursive).add_option(
+        &["-R", "--recursive"],
+        StoreTrue,
+        "Also look for images in the subdirectories of the input directories.",
+    );
+
+    parser.refer(jobs).add_option(
+        &["-j", "--jobs"],
+        Store,
+        "Number of images processed in parallel. Default is the number of CPU cores.",
+    );
+
+    parser.refer(max_memory_mb).add_option(
+        &["--max_memory_mb"],
+        Store,
+        "Approximate memory (MB) that the images processed at the same time may use, workers wait \
+         when it is reached. Default is 2048.",
+    );
+
+    parser.refer(record_format).add_option(
+        &["--format"],
+        Store,
+        "Output written on stdout: 'text' (nothing, logs only), 'json' (array of one record per \
+         image, written at the end) or 'ndjson' (one record per line, written as soon as each \
+         image is done). Records contain the input, the outputs, the marker ids and corners, the \
+         scale, the sharpness and detection confidence, the timing and the error kind and message. \
+         Human readable logs always go to stderr.",
+    );
+
+    parser.refer(report).add_option(
+        &["--report"],
+        Store,
+        "Write a summary of the batch to '<report>.csv' (one row per image: status, error kind, \
+         marker count, scale, sharpness, confidence and outputs) and '<report>.html' \
+         (self-contained page with before/after thumbnails, failures grouped by reason and the \
+         success rate per folder).",
+    );
+
+    parser.refer(quiet).add_option(
+        &["-q", "--quiet"],
+        StoreTrue,
+        "Only log warnings and errors.",
+    );
+}
+
+fn parse_or_exit(parser: &ArgumentParser, args: Vec<String>) {
+    if let Err(code) = parser.parse(args, &mut std::io::stdout(), &mut std::io::stderr()) {
+        std::process::exit(code);
+    }
+}
+
+/// Settings from the config layers. '--config <path>' and, for the commands with presets,
+/// '--preset <name>' are taken out of the arguments before parsing the other flags, since their
+/// defaults come from the settings.
+fn load_settings(args: &mut Vec<String>, with_presets: bool) -> Result<Settings> {
+    let extra_config = take_option(args, "--config").with_kind(ErrorKind::InvalidInput)?;
+    let settings = Settings::load(extra_config.map(|path| arg_path(&path)).as_deref())
+        .with_kind(ErrorKind::InvalidInput)?;
+    match with_presets {
+        true => match take_option(args, "--preset").with_kind(ErrorKind::InvalidInput)? {
+            Some(preset) => settings
+                .with_preset(&preset)
+                .with_kind(ErrorKind::InvalidInput),
+            None => Ok(settings),
+        },
+        false => Ok(settings),
+    }
+}
+
+/// Help of '--config' and '--preset', which are not parser options, appended to the description
+/// of the commands reading the settings
+fn settings_help(description: &str, settings: Option<&Settings>) -> String {
+    let mut help = format!("{description} '--config <path>' adds a config file. {SETTINGS_HELP}");
+    if let Some(settings) = settings {
+        help.push_str(&format!(" '--preset <name>': {}", preset_help(settings)));
+    }
+    help
+}
+
+/// Output dimensions once '--out_dim' and '--zoom' are parsed. A preset with `px_per_mm` keeps
+/// its scale for the zoom given on the command line, unless '--out_dim' is given explicitly.
+fn resolve_out_size(settings: &Settings, out_dim: &[i32], zoom_vec: &[f32]) -> Result<Size> {
+    let out_size = match out_dim[..] {
+        [width, height] => Size::new(width, height),
+        _ => {
+            return Err(kind_error(
+                ErrorKind::InvalidInput,
+                format!(
+                    "--out_dim expects 2 values (width height), got {:?}",
+                    out_dim
+                ),
+            ))
         }
     };
-    println!("Output path: {output_paths:?}");
-
-    // let img = get_image(&input_path).to_rgba8();    
-    let mut img = imgcodecs::imread(&input_path, imgcodecs::IMREAD_UNCHANGED)?;
-    let out_size = Size::new(out_dim[0], out_dim[1]);
-    img = resize_if_larger_dims(img, &out_size)?;
-    // show_image(&img);
-
-    let (markers_coor, markers_id, rejected_markers) = get_image_markers(&img)?;
-    let ordered_points = parse_markers(&markers_coor, &markers_id)?;
-    
-    if markers_coor.len() == 4 {
-        println!("Points used from marker #0 to #3: {:?}", ordered_points);
-        for (zoom, out_path) in zoom_vec.iter().zip(output_paths.iter()) {
-        
-            // println!("{:?}", markers_coor);
-    
-            // let ordered_points = parse(&marker_points, &markers_id);
-    
-            
-            let warped_image = correct_image(&img, &ordered_points, &out_size, zoom)?;
-
-            let final_image = Mat::roi(&warped_image, Rect {
-                x: 0,
-                y: 0,
-                width: out_size.width,
-                height: out_size.height,
-            }).unwrap();
-    
-            if show {
-                show_image(&final_image)?;
-            } else {
-                println!("Saving image to {:?}", out_path);
-                imgcodecs::imwrite(out_path, &final_image, &Vector::new())?;
-    
+    if settings.px_per_mm.is_none() || out_size != settings.out_size {
+        return Ok(out_size);
+    }
+    match zoom_vec {
+        [zoom, others @ ..] if others.iter().all(|other| other == zoom) => {
+            Ok(settings.out_size_for_zoom(*zoom))
+        }
+        [] => Ok(out_size),
+        _ => Err(kind_error(
+            ErrorKind::InvalidInput,
+            "The preset sets px_per_mm, the output dimensions depend on the zoom: give a single \
+             zoom level or --out_dim",
+        )),
+    }
+}
+
+/// Help of '--preset', listing the presets of the config files
+fn preset_help(settings: &Settings) -> String {
+    let presets = match settings.presets.is_empty() {
+        true => String::from("none defined"),
+        false => settings
+            .presets
+            .iter()
+            .map(|preset| preset.label())
+            .collect::<Vec<_>>()
+            .join(", "),
+    };
+    format!(
+        "Named preset of the config files ('[preset.<name>]' sections) giving the defaults of the \
+         crop parameters, output format and filename template. Flags given explicitly still \
+         override it. Available: {presets}."
+    )
+}
+
+/// `--show` needs the OpenCV windows, builds without them write the outputs instead
+fn preview_available(show: bool) -> bool {
+    if show && !cfg!(feature = "highgui-preview") {
+        log::warn!(
+            "This build has no preview window (feature 'highgui-preview'), --show is ignored and \
+             the outputs are written"
+        );
+        return false;
+    }
+    show
+}
+
+/// Arguments that are not valid UTF-8, by the lossy string given to the parser
+static RAW_ARGS: OnceLock<HashMap<String, OsString>> = OnceLock::new();
+
+/// Path of a parsed argument, with its original bytes when it was not valid UTF-8
+fn arg_path(arg: &str) -> PathBuf {
+    match RAW_ARGS.get().and_then(|raw_args| raw_args.get(arg)) {
+        Some(raw_arg) => PathBuf::from(raw_arg),
+        None => PathBuf::from(arg),
+    }
+}
+
+fn main() {
+    // The parser only takes UTF-8, other arguments are given to it lossily and their original
+    // bytes are restored by `arg_path`
+    let mut raw_args = HashMap::new();
+    let mut args: Vec<String> = std::env::args_os()
+        .map(|arg| match arg.into_string() {
+            Ok(arg) => arg,
+            Err(arg) => {
+                let lossy = arg.to_string_lossy().into_owned();
+                raw_args.insert(lossy.clone(), arg);
+                lossy
             }
+        })
+        .collect();
+    RAW_ARGS.get_or_init(|| raw_args);
+    let program = match args.is_empty() {
+        true => String::from("idmybee_cli"),
+        false => args.remove(0),
+    };
+
+    if let Err(err) = run(&program, args) {
+        eprintln!("Error: {err:?}");
+        std::process::exit(error_kind(&err).exit_code());
+    }
+}
+
+fn run(program: &str, mut args: Vec<String>) -> Result<()> {
+    let command = match args.first().map(String::as_str) {
+        None => {
+            eprintln!("{USAGE}");
+            return Err(kind_error(ErrorKind::InvalidInput, "No command given"));
         }
-    }  else {
-        let rejected_marker_positions : VectorOfPoint2f = rejected_markers.iter().map(
-            |p_vec| p_vec.iter().fold(
-                Point2f::default(), |sum_p, p| sum_p + p
-            ) / 4.
-        ).collect();
-        return Err(anyhow::anyhow!("Error: {:?} markers were found instead of 4.\nFollowing markers were rejected: {:?}\nThe image may be too blurred (i.e. not enough contrast at markers positions) or there may be stray reflections on the markers (makers not black and white). Also check that markers 0 to 4 are present on the picture.", 
-            markers_coor.len(), rejected_marker_positions
-        ))
+        Some("-h") | Some("--help") => {
+            println!("{USAGE}");
+            return Ok(());
+        }
+        // Command names come first, a file named like a command does not change the command.
+        // 'idmybee_cli [options] <inputs>' still crops, as before the commands existed.
+        Some(arg) => match Command::from_str(arg) {
+            Ok(command) => {
+                args.remove(0);
+                command
+            }
+            Err(_) if arg.starts_with('-') || looks_like_path(arg) => Command::Crop,
+            Err(err) => return Err(err),
+        },
+    };
+
+    // argparse takes the program name first, it is shown in the usage of the command
+    let name = match command {
+        Command::Crop => "crop",
+        Command::Detect => "detect",
+        Command::Overlay => "overlay",
+        Command::Rerender => "rerender",
+        Command::Watch => "watch",
+        Command::Card => "card",
+        Command::Synth => "synth",
     };
+    args.insert(0, format!("{program} {name}"));
+
+    match command {
+        Command::Crop | Command::Rerender | Command::Watch => cli_crop::run_crop(command, args),
+        Command::Detect => cli_detect::run_detect(args),
+        Command::Overlay => cli_overlay::run_overlay(args),
+        Command::Card => cli_card::run_card(args),
+        Command::Synth => cli_synth::run_synth(args),
+    }
+}
 
+/// Argument that can only be an input of the implicit crop: it has a path separator, an
+/// extension or a glob pattern
+fn looks_like_path(arg: &str) -> bool {
+    arg.contains(['/', std::path::MAIN_SEPARATOR, '*', '?']) || Path::new(arg).extension().is_some()
+}
+
+/// Writes a record on stdout when the format streams them
+fn emit_record(record: &ImageRecord, record_format: RecordFormat, streaming: bool) {
+    if record_format == RecordFormat::Ndjson || (streaming && record_format == RecordFormat::Json) {
+        let mut stdout = std::io::stdout().lock();
+        let _ = writeln!(
+            stdout,
+            "{}",
+            serde_json::to_string(record).unwrap_or_default()
+        );
+        let _ = stdout.flush();
+    }
+}
+
+/// Runs `process` on one input, a failure becomes a failed record
+fn timed_record<F>(input_path: &Path, process: F) -> ImageRecord
+where
+    F: Fn(&Path) -> Result<ImageRecord>,
+{
+    let start = Instant::now();
+    let mut record = match process(input_path) {
+        Ok(record) => record,
+        Err(err) => {
+            log::error!("Failed to process {:?}: {}", input_path, err);
+            ImageRecord::failed(input_path, &err)
+        }
+    };
+    record.duration_ms = start.elapsed().as_millis() as u64;
+    record
+}
+
+/// Processes every input with the worker pool (`process` also gets the position of the input),
+/// writes the records and returns an error of the kind shared by the failures (`Other` when they
+/// failed for different reasons)
+fn run_records<F>(inputs: &[PathBuf], input_options: &InputOptions, process: F) -> Result<()>
+where
+    F: Fn(usize, &Path) -> Result<ImageRecord> + Sync,
+{
+    let record_format = input_options.record_format;
+    log::info!("{} input(s) to process", inputs.len());
+
+    let memory_budget = MemoryBudget::new(input_options.max_memory_mb * 1024 * 1024);
+    let records = run_batch(
+        inputs,
+        input_options.jobs,
+        &memory_budget,
+        // Indexes of the output templates start at 1
+        |index, input_path| timed_record(input_path, |input_path| process(index + 1, input_path)),
+        |record| emit_record(record, record_format, false),
+    );
+
+    if record_format == RecordFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&records)?);
+    }
+
+    let mut summary = BatchSummary::default();
+    for record in records.iter() {
+        summary.add(record);
+    }
+    if summary.total() > 1 {
+        summary.print();
+    }
+    for (card_id, inputs) in duplicate_card_ids(&records) {
+        log::warn!(
+            "Card ID {:?} was read on {} images: {}",
+            card_id,
+            inputs.len(),
+            inputs.join(", ")
+        );
+    }
+    if !input_options.report.is_empty() {
+        write_reports(&records, &input_options.report).with_kind(ErrorKind::Write)?;
+    }
+
+    let failed: Vec<&ImageRecord> = records
+        .iter()
+        .filter(|record| record.status == RecordStatus::Failed)
+        .collect();
+    let Some(first_failed) = failed.first() else {
+        return Ok(());
+    };
+    let kind = match failed
+        .iter()
+        .all(|record| record.error_kind == first_failed.error_kind)
+    {
+        true => first_failed.error_kind.unwrap_or(ErrorKind::Other),
+        false => ErrorKind::Other,
+    };
+    match records.len() {
+        // A single input keeps its detailed error
+        1 => Err(kind_error(
+            kind,
+            first_failed.error_message.clone().unwrap_or_default(),
+        )),
+        _ => Err(kind_error(
+            kind,
+            format!("{} image(s) could not be processed", failed.len()),
+        )),
+    }
+}
+
+/// Writes '<report>.csv' and '<report>.html'
+fn write_reports(records: &[ImageRecord], report: &str) -> Result<()> {
+    let report_path = |extension: &str| {
+        let mut path = arg_path(report).into_os_string();
+        path.push(extension);
+        PathBuf::from(path)
+    };
+    let csv_path = report_path(".csv");
+    let html_path = report_path(".html");
+    if let Some(parent) = csv_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    write_csv(records, &csv_path)?;
+    log::info!("CSV report written to {:?}", csv_path);
+    write_html(records, &html_path)?;
+    log::info!("HTML report written to {:?}", html_path);
     Ok(())
-}
\ No newline at end of file
+}
+
+fn collect_image_inputs(input_options: &InputOptions, extensions: &[&str]) -> Result<Vec<PathBuf>> {
+    let inputs = collect_inputs(
+        &input_options.input_paths(),
+        input_options.recursive,
+        extensions,
+    )
+    .with_kind(ErrorKind::InvalidInput)?;
+    if inputs.is_empty() {
+        return Err(kind_error(
+            ErrorKind::InvalidInput,
+            format!("No input image was found in {:?}", input_options.input_args),
+        ));
+    }
+    Ok(inputs)
+}
+
+/// Writes an encoded output, '-' writes it to stdout
+fn write_output(out_path: &Path, bytes: &[u8]) -> Result<()> {
+    if out_path == Path::new(STDIO_PATH) {
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(bytes)?;
+        stdout.flush()?;
+        return Ok(());
+    }
+    if let Some(parent) = out_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(out_path, bytes)
+        .map_err(|err| anyhow::anyhow!("Image {:?} could not be written: {}", out_path, err))
+}
@@ -2,26 +2,33 @@
 
 use std::{
     cmp::max,
-    env::current_exe,
     path::{Path, PathBuf},
 };
 
 use anyhow::{Error, Result};
-use configparser::ini::Ini;
 use cv_convert::TryIntoCv;
 use eframe::{egui, run_native, App, NativeOptions};
-use egui::{Color32, ColorImage, Key, Label, RichText, ScrollArea, TextEdit, Vec2};
+use egui::{Color32, ColorImage, ComboBox, Key, Label, RichText, ScrollArea, TextEdit, Vec2};
 use egui_extras::RetainedImage;
 use image::DynamicImage;
 use opencv::{
     core::{Mat, Rect, Size, Vector},
-    imgcodecs,
     imgproc::{cvt_color, COLOR_BGR2RGB, COLOR_RGB2BGR},
+    prelude::*,
 };
 use rfd::FileDialog;
 
-mod marker_utils;
-use marker_utils::marker_processing::*;
+use idmybee::card_layout::CardLayout;
+use idmybee::config_schema::Severity;
+use idmybee::crop::{crop_image, CropParameters};
+use idmybee::image_io::{
+    encode_image, path_extension, read_image, SourceExif, SourceMetadataPolicy,
+};
+use idmybee::marker_utils::marker_processing::*;
+use idmybee::metadata::OutputMetadata;
+use idmybee::output_template::{OutputTemplate, TemplateValues};
+use idmybee::settings::Settings;
+use idmybee::sidecar::CropSidecar;
 
 mod file_explorer;
 use file_explorer::FileExplorer;
@@ -30,6 +37,7 @@ mod app_shortcuts;
 use app_shortcuts::AppShortcuts;
 
 fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
     let window_options = NativeOptions {
         initial_window_size: Option::from(Vec2::new(1200., 800.)),
         ..Default::default()
@@ -44,13 +52,30 @@ fn main() {
 
 struct IdMyBeeApp<'a> {
     explorer: FileExplorer<'a>,
+    orig_image_path: Option<PathBuf>,
+    orig_image_exif: SourceExif,
+    /// QR code of the card of the loaded image, read by its crops
+    orig_card_id: Option<Option<String>>,
     cv_orig_image: Option<Mat>,
     cv_cropped_image: Option<Mat>,
+    /// Geometry of the current crop, written next to the output when `write_sidecar` is set
+    crop_sidecar: Option<CropSidecar>,
+    crop_metadata: Option<OutputMetadata>,
     egui_orig_image: Option<RetainedImage>,
     egui_cropped_image: Option<RetainedImage>,
     out_x: u32,
     out_y: u32,
     zoom: f32,
+    write_sidecar: bool,
+    embed_metadata: bool,
+    source_metadata: SourceMetadataPolicy,
+    card_layout: CardLayout,
+    filename_template: OutputTemplate,
+    crop_window: Option<Rect>,
+    interpolation: Interpolation,
+    /// Settings without any preset, restored when the preset is unselected
+    settings: Settings,
+    preset: Option<String>,
     try_load: bool,
     load_img_res: Result<()>,
     crop_img_res: Result<()>,
@@ -60,48 +85,66 @@ struct IdMyBeeApp<'a> {
 
 impl IdMyBeeApp<'_> {
     fn new(_cc: &eframe::CreationContext<'_>) -> Self {
-        let mut config = Ini::new();
-        let mut config_path: PathBuf = current_exe().unwrap_or_default();
-        config_path.pop();
-        config_path.push("config.ini");
-
-        println!("Config file path: {:?}", config_path);
-        println!("Does the file exists {:?}", config_path.is_file());
-        let load_conf_result = match config.load(config_path) {
-            Ok(_) => Ok(()),
-            Err(err) => Err(anyhow::anyhow!(err)),
+        let load_conf_result = Settings::load(None);
+        let settings = match load_conf_result.as_ref() {
+            Ok(settings) => settings.clone(),
+            Err(_) => Settings::default(),
         };
+        log::info!("Settings loaded from {:?}", settings.sources);
+        settings.log_issues();
+        // Invalid entries fall back to their default, they are shown until the first image is loaded
+        let load_conf_result = load_conf_result.and_then(|settings| {
+            let errors: Vec<String> = settings
+                .issues
+                .iter()
+                .filter(|issue| issue.severity == Severity::Error)
+                .map(|issue| issue.to_string())
+                .collect();
+            match errors.is_empty() {
+                true => Ok(()),
+                false => Err(anyhow::anyhow!("Invalid settings:\n{}", errors.join("\n"))),
+            }
+        });
 
-        println!("{:?}", config);
+        let mut explorer = FileExplorer::new();
+        explorer.encoding = settings.encoding.clone();
 
         IdMyBeeApp {
-            explorer: FileExplorer::new(),
+            explorer,
             // img_path: "C:/Users/20100/Documents/Rust/idmybee/ressources/test_cards/Photos-001/IMG_20230805_231619.jpg",
+            orig_image_path: None,
+            orig_image_exif: SourceExif::default(),
+            orig_card_id: None,
             cv_orig_image: None,
             cv_cropped_image: None,
+            crop_sidecar: None,
+            crop_metadata: None,
             egui_orig_image: None,
             egui_cropped_image: None,
-            out_x: config
-                .getint("crop_parameters", "out_x")
-                .unwrap_or(None)
-                .unwrap_or(600) as u32,
-            out_y: config
-                .getint("crop_parameters", "out_y")
-                .unwrap_or(None)
-                .unwrap_or(600) as u32,
-            zoom: config
-                .getfloat("crop_parameters", "zoom")
-                .unwrap_or(None)
-                .unwrap_or(1.2) as f32,
+            out_x: settings.out_size.width as u32,
+            out_y: settings.out_size.height as u32,
+            zoom: settings.zoom,
+            write_sidecar: settings.write_sidecar,
+            embed_metadata: settings.embed_metadata,
+            source_metadata: settings.source_metadata,
+            card_layout: settings.card_layout.clone(),
+            filename_template: settings.gui_filename_template.clone(),
+            crop_window: settings.crop_window,
+            interpolation: settings.interpolation,
+            preset: None,
             try_load: load_conf_result.is_err(),
             load_img_res: load_conf_result,
             crop_img_res: Ok(()),
             save_img_res: Ok(()),
-            app_shortcuts: AppShortcuts::new(&config),
+            app_shortcuts: AppShortcuts::new(&settings.config),
+            settings,
         }
     }
 
     fn clear_orig_images(&mut self) {
+        self.orig_image_path = None;
+        self.orig_image_exif = SourceExif::default();
+        self.orig_card_id = None;
         self.cv_orig_image = None;
         self.egui_orig_image = None;
         self.crop_img_res = Ok(());
@@ -110,6 +153,8 @@ impl IdMyBeeApp<'_> {
 
     fn clear_cropped_images(&mut self) {
         self.cv_cropped_image = None;
+        self.crop_sidecar = None;
+        self.crop_metadata = None;
         self.egui_cropped_image = None;
         self.crop_img_res = Ok(());
         self.save_img_res = Ok(());
@@ -120,17 +165,19 @@ impl IdMyBeeApp<'_> {
         self.clear_cropped_images();
     }
 
-    fn load_image_from_path(&mut self, img_path: &str) {
+    fn load_image_from_path(&mut self, img_path: &Path) {
         self.try_load = true;
-        let load_img_res = imgcodecs::imread(img_path, imgcodecs::IMREAD_UNCHANGED);
+        let load_img_res = read_image(img_path);
         let brg_cv_img: Mat;
+        let source_exif: SourceExif;
         match load_img_res {
-            Ok(img) => {
+            Ok((img, exif)) => {
                 brg_cv_img = img;
+                source_exif = exif;
                 self.load_img_res = Ok(());
             }
             Err(err) => {
-                self.load_img_res = Err(err.into());
+                self.load_img_res = Err(err);
                 self.clear_all_images();
                 return;
             }
@@ -171,12 +218,38 @@ impl IdMyBeeApp<'_> {
         );
 
         self.clear_cropped_images();
+        self.orig_image_path = Some(img_path.to_path_buf());
+        self.orig_image_exif = source_exif;
+        self.orig_card_id = None;
     }
 
     fn load_image_from_explorer(&mut self) {
         if let Some(img_path) = self.explorer.get_filepath() {
             self.load_image_from_path(&img_path);
+            self.explorer.output_img_name = self.default_output_filename();
+        };
+    }
+
+    fn default_output_filename(&self) -> String {
+        let Some(img_path) = self.orig_image_path.as_ref() else {
+            return String::new();
+        };
+        let values = TemplateValues {
+            input_path: img_path.clone(),
+            extension: self.explorer.encoding.output_extension(img_path),
+            zoom: self.zoom,
+            out_size: Size::new(self.out_x as i32, self.out_y as i32),
+            date: TemplateValues::input_date(
+                img_path,
+                self.orig_image_exif.capture_date.as_deref(),
+            ),
+            card_id: self.orig_card_id.clone().flatten(),
+            index: self
+                .explorer
+                .selected_file_index
+                .map_or(1, |index| index + 1),
         };
+        self.filename_template.render(&values).display().to_string()
     }
 
     fn cv_img_to_egui_img(
@@ -196,28 +269,37 @@ impl IdMyBeeApp<'_> {
         Err(anyhow::anyhow!("No opened image was found"))
     }
 
-    fn process_image(&mut self) -> Result<Mat> {
+    fn process_image(&mut self) -> Result<(Mat, Option<CropSidecar>, Option<OutputMetadata>)> {
         if let Some(img) = self.cv_orig_image.as_ref() {
-            let out_size = Size::new(self.out_x as i32, self.out_y as i32);
-            let img = resize_if_larger_dims(img.to_owned(), &out_size)?;
-            let (markers_coor, markers_id, _) = get_image_markers(&img)?;
-            if markers_coor.len() != 4 {
-                return Err(anyhow::anyhow!("Error: {:?} markers were found instead of 4.\nThe image may be too blurred (i.e. not enough contrast at markers positions) or there may be stray reflections on the markers (makers not black and white). Also check that markers 0 to 4 are present on the picture.", 
-                    markers_coor.len()
-                ));
-            }
-            let ordered_points = parse_markers(&markers_coor, &markers_id)?;
-            let warped_image = correct_image(&img, &ordered_points, &out_size, &self.zoom)?;
-            let final_image = Mat::roi(
-                &warped_image,
-                Rect {
-                    x: 0,
-                    y: 0,
-                    width: out_size.width,
-                    height: out_size.height,
-                },
-            )?;
-            return Ok(final_image);
+            let parameters = CropParameters {
+                out_size: Size::new(self.out_x as i32, self.out_y as i32),
+                zoom: self.zoom,
+                crop_window: self.crop_window,
+                interpolation: self.interpolation,
+            };
+            let crop = crop_image(&new_marker_detector()?, img.try_clone()?, &parameters)?;
+            self.orig_card_id = Some(crop.card_id.clone());
+
+            // Built for every crop so that toggling the sidecar does not detect the markers again
+            let sidecar = match self.orig_image_path.as_ref() {
+                Some(img_path) => Some(CropSidecar::from_crop(img_path, &crop, &parameters)?),
+                None => None,
+            };
+            let metadata = match (self.embed_metadata, self.orig_image_path.as_ref()) {
+                (true, Some(img_path)) => Some(
+                    OutputMetadata::new(
+                        img_path,
+                        &self.card_layout,
+                        &parameters.out_size,
+                        self.zoom,
+                        &crop.homography,
+                    )?
+                    .with_source_exif(&self.orig_image_exif, self.source_metadata)
+                    .with_card_id(crop.card_id.clone()),
+                ),
+                _ => None,
+            };
+            return Ok((crop.image, sidecar, metadata));
         }
         let err_str = "No image was previously loaded. Select an image with the explorer in the left panel and then crop it.";
         Err(anyhow::anyhow!(err_str))
@@ -225,14 +307,16 @@ impl IdMyBeeApp<'_> {
 
     fn process_image_wrapper(&mut self) {
         match self.process_image() {
-            Ok(img) => {
+            Ok((img, sidecar, metadata)) => {
                 self.cv_cropped_image = Some(img);
+                self.crop_sidecar = sidecar;
+                self.crop_metadata = metadata;
                 self.crop_img_res = IdMyBeeApp::cv_img_to_egui_img(
                     &self.cv_cropped_image,
                     "Cropped Image",
                     &mut self.egui_cropped_image,
                 );
-                self.explorer.output_img_name = self.explorer.get_default_output_filename();
+                self.explorer.output_img_name = self.default_output_filename();
             }
             Err(err) => {
                 self.crop_img_res = Err(err);
@@ -249,6 +333,11 @@ impl IdMyBeeApp<'_> {
         if self.cv_cropped_image.is_some() {
             let mut out_full_path = self.explorer.output_img_dir.clone();
             out_full_path.push(&self.explorer.output_img_name);
+            // The filename template may write into subdirectories
+            if let Some(Err(err)) = out_full_path.parent().map(std::fs::create_dir_all) {
+                self.save_img_res = Err(err.into());
+                return;
+            }
 
             let mut rgb_img = Mat::default();
             match cvt_color(
@@ -261,15 +350,39 @@ impl IdMyBeeApp<'_> {
                 Err(err) => self.save_img_res = Err(err.into()),
             }
 
-            match imgcodecs::imwrite(&out_full_path.to_string_lossy(), &rgb_img, &Vector::new()) {
+            let extension = path_extension(&out_full_path);
+            let encoding_params = self.explorer.encoding.imwrite_params(&extension);
+            let params: Vector<i32> = match self.crop_metadata.as_ref() {
+                Some(metadata) => encoding_params
+                    .iter()
+                    .chain(metadata.imwrite_params(&extension).iter())
+                    .collect(),
+                None => encoding_params,
+            };
+            let encoded = encode_image(&rgb_img, &extension, &params).and_then(|bytes| match self
+                .crop_metadata
+                .as_ref()
+            {
+                Some(metadata) => metadata.embed(bytes, &extension),
+                None => Ok(bytes),
+            });
+            match encoded
+                .and_then(|bytes| std::fs::write(&out_full_path, bytes).map_err(Into::into))
+            {
                 Ok(_) => {
                     self.crop_img_res = Ok(());
-                    self.explorer.update_paths();
                     self.save_img_res = Ok(());
+                    if let Some(sidecar) = self.crop_sidecar.as_ref().filter(|_| self.write_sidecar)
+                    {
+                        if let Err(err) = sidecar.write(&out_full_path) {
+                            self.save_img_res = Err(err);
+                        }
+                    }
+                    self.explorer.update_paths();
                 }
                 Err(err) => {
-                    println!("Error: {}", err);
-                    self.save_img_res = Err(err.into())
+                    log::error!("Could not save the crop to {:?}: {}", out_full_path, err);
+                    self.save_img_res = Err(err)
                 }
             }
         }
@@ -435,13 +548,64 @@ impl IdMyBeeApp<'_> {
         }
     }
 
+    /// Crop parameters of the selected preset, or of the settings when none is selected
+    fn apply_preset(&mut self) {
+        let settings = match self
+            .preset
+            .as_ref()
+            .map(|name| self.settings.with_preset(name))
+        {
+            Some(Ok(settings)) => settings,
+            Some(Err(err)) => {
+                self.crop_img_res = Err(err);
+                return;
+            }
+            None => self.settings.clone(),
+        };
+        self.out_x = settings.out_size.width as u32;
+        self.out_y = settings.out_size.height as u32;
+        self.zoom = settings.zoom;
+        self.crop_window = settings.crop_window;
+        self.interpolation = settings.interpolation;
+        self.explorer.encoding.format = settings.encoding.format;
+        self.filename_template = settings.gui_filename_template;
+        self.process_image_wrapper();
+    }
+
     fn crop_param_ui(&mut self, ui: &mut egui::Ui) {
         ui.horizontal_wrapped(|ui| {
+            if !self.settings.presets.is_empty() {
+                let previous_preset = self.preset.clone();
+                ComboBox::from_label("Preset")
+                    .selected_text(self.preset.as_deref().unwrap_or("None"))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut self.preset, None, "None")
+                            .on_hover_text("Crop parameters of the settings");
+                        for preset in self.settings.presets.iter() {
+                            let option = ui.selectable_value(
+                                &mut self.preset,
+                                Some(preset.name.clone()),
+                                &preset.name,
+                            );
+                            if let Some(description) = preset.description.as_ref() {
+                                option.on_hover_text(description);
+                            }
+                        }
+                    });
+                if self.preset != previous_preset {
+                    self.apply_preset();
+                }
+                ui.separator();
+            }
             let slider = ui.add(egui::Slider::new(&mut self.zoom, 1.0..=2.5).text("Zoom"));
             if slider.drag_released() || slider.lost_focus() && slider.changed() {
                 self.process_image_wrapper();
             };
             ui.separator();
+            ui.checkbox(&mut self.write_sidecar, "JSON sidecar")
+                .on_hover_text(
+                    "Write a '[output image].json' file with the crop geometry when saving",
+                );
             ui.separator();
             if IdMyBeeApp::<'_>::integer_edit_field(ui, &mut self.out_x, Vec2::new(40., 15.))
                 .lost_focus()
@@ -0,0 +1,576 @@
+use anyhow::Result;
+use argparse::{ArgumentParser, Store, StoreTrue};
+use base64::Engine;
+use opencv::{objdetect::ArucoDetector, prelude::*};
+use serde::Serialize;
+use std::{
+    io::{Cursor, Read},
+    path::PathBuf,
+    time::Instant,
+};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use idmybee::command_line::{init_logger, prescan_option};
+use idmybee::crop::{crop_image, CropParameters};
+use idmybee::image_io::{decode_image, encode_image, guess_extension};
+use idmybee::marker_utils::marker_processing::*;
+use idmybee::pipeline_error::{error_kind, kind_error, ErrorKind, WithErrorKind};
+use idmybee::settings::{Settings, SETTINGS_HELP};
+
+const DESCRIPTION: &str = "Local HTTP server cropping the photos taken with the ID My Bee protocol, for applications that would otherwise call idmybee_cli once per image.
+
+Endpoints:
+  GET  /health  Status, version and limits of the server, as JSON
+  POST /crop    Crops the uploaded image. The body is either the raw image bytes or a
+                multipart/form-data form with the image in a file field named 'image'.
+                Parameters are given in the query string (e.g. '/crop?zoom=1.6&response=json')
+                or as text fields of the form:
+                  preset, out_x, out_y, zoom, crop_window ('x,y,width,height'), interpolation,
+                  format, jpeg_quality, jpeg_subsampling, png_compression, tiff_compression
+                  response  'image' (default, the encoded crop) or 'json' (markers, corners,
+                            homography, scale and diagnostics)
+                  include_image  'true' to add the crop in base64 to the JSON response
+                Errors are returned as JSON with the error kind and message.";
+
+type HttpResponse = Response<Cursor<Vec<u8>>>;
+
+#[derive(Serialize)]
+struct Health {
+    status: &'static str,
+    version: &'static str,
+    workers: usize,
+    max_body_bytes: usize,
+    presets: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error_kind: ErrorKind,
+    error: String,
+}
+
+#[derive(Serialize)]
+struct Diagnostics {
+    input_size: [i32; 2],
+    /// Width and height ratios applied to the input before detection and warping
+    working_scale: [f64; 2],
+    markers_found: usize,
+    rejected_candidates: usize,
+    confidence: Option<f64>,
+    sharpness: f64,
+    duration_ms: u64,
+}
+
+#[derive(Serialize)]
+struct CropBody {
+    marker_ids: Vec<i32>,
+    /// Corners of each detected marker in input image coordinates
+    marker_corners: Vec<[[f32; 2]; 4]>,
+    /// Specimen ID of the QR code on the card
+    card_id: Option<String>,
+    /// Outer corners of markers #0 to #3 used for the correction
+    source_points: [[f32; 2]; 4],
+    /// Perspective transform from the working image to the corrected image
+    homography: [[f64; 3]; 3],
+    out_size: [i32; 2],
+    zoom: f32,
+    crop_window: [i32; 4],
+    interpolation: String,
+    px_per_mm: [f64; 2],
+    diagnostics: Diagnostics,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image_format: Option<String>,
+    /// Encoded crop in base64, with `include_image=true`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image: Option<String>,
+}
+
+/// Part of a multipart/form-data body
+struct FormPart {
+    name: String,
+    filename: Option<String>,
+    data: Vec<u8>,
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+}
+
+fn json_response<T: Serialize>(status: u16, body: &T) -> HttpResponse {
+    Response::from_data(serde_json::to_vec_pretty(body).unwrap_or_default())
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json"))
+}
+
+fn error_response(status: u16, kind: ErrorKind, message: impl ToString) -> HttpResponse {
+    json_response(
+        status,
+        &ErrorBody {
+            error_kind: kind,
+            error: message.to_string(),
+        },
+    )
+}
+
+/// HTTP status of a failed crop
+fn status_code(kind: ErrorKind) -> u16 {
+    match kind {
+        ErrorKind::InvalidInput | ErrorKind::InvalidSidecar => 400,
+        // The upload is not a readable image, or not a photo of the card
+        ErrorKind::Read | ErrorKind::MarkersNotFound => 422,
+        ErrorKind::Write | ErrorKind::Other => 500,
+    }
+}
+
+fn mime_type(extension: &str) -> &'static str {
+    match extension {
+        "png" => "image/png",
+        "jpg" | "jpeg" | "jpe" => "image/jpeg",
+        "tif" | "tiff" => "image/tiff",
+        "webp" => "image/webp",
+        "bmp" | "dib" => "image/bmp",
+        _ => "application/octet-stream",
+    }
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes[i] {
+            b'%' if i + 2 < bytes.len() => std::str::from_utf8(&bytes[i + 1..i + 3])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match (escaped, bytes[i]) {
+            (Some(byte), _) => {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+            (None, b'+') => decoded.push(b' '),
+            (None, byte) => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+fn query_parameters(url: &str) -> Vec<(String, String)> {
+    let Some((_, query)) = url.split_once('?') else {
+        return Vec::new();
+    };
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect()
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|position| position + from)
+}
+
+/// Parameter of a header value, e.g. `boundary` in 'multipart/form-data; boundary=xyz'
+fn header_parameter(value: &str, name: &str) -> Option<String> {
+    value
+        .split(';')
+        .map(str::trim)
+        .find_map(|parameter| parameter.strip_prefix(name)?.strip_prefix('='))
+        .map(|parameter| parameter.trim_matches('"').to_string())
+}
+
+fn parse_multipart(content_type: &str, body: &[u8]) -> Result<Vec<FormPart>> {
+    let invalid = |message: &str| {
+        kind_error(
+            ErrorKind::InvalidInput,
+            format!("Invalid multipart body: {message}"),
+        )
+    };
+    let boundary =
+        header_parameter(content_type, "boundary").ok_or_else(|| invalid("no boundary"))?;
+    let delimiter = format!("--{boundary}").into_bytes();
+    let part_end = [b"\r\n".as_slice(), &delimiter].concat();
+
+    let mut parts = Vec::new();
+    let mut position = find_bytes(body, &delimiter, 0)
+        .ok_or_else(|| invalid("boundary not found"))?
+        + delimiter.len();
+    // The last delimiter is followed by '--'
+    while !body.get(position..).unwrap_or_default().starts_with(b"--") {
+        let headers_start = position + 2;
+        let headers_end = find_bytes(body, b"\r\n\r\n", headers_start)
+            .ok_or_else(|| invalid("truncated part headers"))?;
+        let data_start = headers_end + 4;
+        let data_end =
+            find_bytes(body, &part_end, data_start).ok_or_else(|| invalid("truncated part"))?;
+
+        let headers = String::from_utf8_lossy(&body[headers_start..headers_end]);
+        let disposition = headers
+            .lines()
+            .find(|line| line.to_lowercase().starts_with("content-disposition"))
+            .unwrap_or_default();
+        parts.push(FormPart {
+            name: header_parameter(disposition, "name").unwrap_or_default(),
+            filename: header_parameter(disposition, "filename"),
+            data: body[data_start..data_end].to_vec(),
+        });
+        position = data_end + part_end.len();
+    }
+    Ok(parts)
+}
+
+/// Reads the body, up to `max_body_bytes`. Fails with the HTTP status and message to return.
+fn read_body(request: &mut Request, max_body_bytes: usize) -> Result<Vec<u8>, (u16, String)> {
+    let too_large = || {
+        (
+            413,
+            format!("The request body is larger than the limit of {max_body_bytes} bytes (--max_body_mb)"),
+        )
+    };
+    if request.body_length().unwrap_or(0) > max_body_bytes {
+        return Err(too_large());
+    }
+    let mut body = Vec::new();
+    request
+        .as_reader()
+        .take(max_body_bytes as u64 + 1)
+        .read_to_end(&mut body)
+        .map_err(|err| (400, format!("Could not read the request body: {err}")))?;
+    match body.len() > max_body_bytes {
+        true => Err(too_large()),
+        false => Ok(body),
+    }
+}
+
+/// Crops an uploaded image and returns either the encoded crop or the JSON result
+fn crop_response(
+    detector: &ArucoDetector,
+    settings: &Settings,
+    content_type: &str,
+    url: &str,
+    body: Vec<u8>,
+) -> Result<HttpResponse> {
+    let start = Instant::now();
+    let mut parameters = query_parameters(url);
+    let image_data = match content_type
+        .to_lowercase()
+        .starts_with("multipart/form-data")
+    {
+        true => {
+            let mut image_data = None;
+            for part in parse_multipart(content_type, &body)? {
+                match image_data.is_none() && (part.name == "image" || part.filename.is_some()) {
+                    true => image_data = Some(part.data),
+                    false => parameters.push((
+                        part.name,
+                        String::from_utf8_lossy(&part.data).trim().to_string(),
+                    )),
+                }
+            }
+            image_data.ok_or_else(|| {
+                kind_error(
+                    ErrorKind::InvalidInput,
+                    "No image in the form, expected a file field named 'image'",
+                )
+            })?
+        }
+        false => body,
+    };
+
+    let mut settings = match parameters.iter().find(|(key, _)| key == "preset") {
+        Some((_, preset)) => settings
+            .with_preset(preset)
+            .with_kind(ErrorKind::InvalidInput)?,
+        None => settings.clone(),
+    };
+    let mut json = false;
+    let mut include_image = false;
+    for (key, value) in parameters.iter() {
+        match key.as_str() {
+            "preset" => (),
+            "response" => {
+                json = match value.as_str() {
+                    "image" => false,
+                    "json" => true,
+                    _ => {
+                        return Err(kind_error(
+                            ErrorKind::InvalidInput,
+                            format!(
+                                "Invalid parameter response: {value:?}, expected 'image' or 'json'"
+                            ),
+                        ))
+                    }
+                }
+            }
+            "include_image" => include_image = value == "true" || value == "1",
+            _ => settings
+                .set_parameter(key, value)
+                .with_kind(ErrorKind::InvalidInput)?,
+        }
+    }
+
+    let (img, _) = decode_image(&image_data).with_kind(ErrorKind::Read)?;
+    let input_size = img.size()?;
+    let crop_parameters = CropParameters {
+        out_size: settings.out_size,
+        zoom: settings.zoom,
+        crop_window: settings.crop_window,
+        interpolation: settings.interpolation,
+    };
+    let result = crop_image(detector, img, &crop_parameters)?;
+
+    // Same format as the upload unless one is given
+    let extension = settings
+        .encoding
+        .format
+        .clone()
+        .unwrap_or_else(|| guess_extension(&image_data).unwrap_or(String::from("png")));
+    let params = settings.encoding.imwrite_params(&extension);
+    let encoded = encode_image(&result.image, &extension, &params).with_kind(ErrorKind::Write)?;
+
+    let (px_per_mm_x, px_per_mm_y) = settings
+        .card_layout
+        .px_per_mm(&settings.out_size, settings.zoom);
+    if !json {
+        let mut response = Response::from_data(encoded)
+            .with_header(header("Content-Type", mime_type(&extension)))
+            .with_header(header(
+                "X-IdMyBee-Px-Per-Mm",
+                &format!("{px_per_mm_x},{px_per_mm_y}"),
+            ));
+        // Header values are limited to visible ASCII, other IDs are only in the JSON response
+        if let Some(card_id) = result
+            .card_id
+            .as_ref()
+            .filter(|card_id| card_id.chars().all(|c| c.is_ascii_graphic() || c == ' '))
+        {
+            response.add_header(header("X-IdMyBee-Card-Id", card_id));
+        }
+        return Ok(response);
+    }
+
+    let crop_window = result.crop_window;
+    let body = CropBody {
+        diagnostics: Diagnostics {
+            input_size: [input_size.width, input_size.height],
+            working_scale: [result.working_scale.0, result.working_scale.1],
+            markers_found: result.marker_ids.len(),
+            rejected_candidates: result.rejected_count,
+            confidence: markers_confidence(&result.marker_corners),
+            sharpness: sharpness(&result.image)?,
+            duration_ms: start.elapsed().as_millis() as u64,
+        },
+        marker_ids: result.marker_ids,
+        marker_corners: result.marker_corners,
+        card_id: result.card_id,
+        source_points: result.source_points,
+        homography: matrix_to_array(&result.homography)?,
+        out_size: [settings.out_size.width, settings.out_size.height],
+        zoom: settings.zoom,
+        crop_window: [
+            crop_window.x,
+            crop_window.y,
+            crop_window.width,
+            crop_window.height,
+        ],
+        interpolation: settings.interpolation.to_string(),
+        px_per_mm: [px_per_mm_x, px_per_mm_y],
+        image_format: include_image.then(|| extension.clone()),
+        image: include_image.then(|| base64::engine::general_purpose::STANDARD.encode(&encoded)),
+    };
+    Ok(json_response(200, &body))
+}
+
+fn handle_request(
+    request: &mut Request,
+    detector: &ArucoDetector,
+    settings: &Settings,
+    workers: usize,
+    max_body_bytes: usize,
+) -> HttpResponse {
+    let url = request.url().to_string();
+    let path = url.split('?').next().unwrap_or_default();
+    match (request.method(), path) {
+        (Method::Get, "/health") => json_response(
+            200,
+            &Health {
+                status: "ok",
+                version: env!("CARGO_PKG_VERSION"),
+                workers,
+                max_body_bytes,
+                presets: settings
+                    .presets
+                    .iter()
+                    .map(|preset| preset.name.clone())
+                    .collect(),
+            },
+        ),
+        (Method::Post, "/crop") => {
+            let content_type = request
+                .headers()
+                .iter()
+                .find(|header| header.field.equiv("Content-Type"))
+                .map(|header| header.value.to_string())
+                .unwrap_or_default();
+            let body = match read_body(request, max_body_bytes) {
+                Ok(body) => body,
+                Err((status, message)) => {
+                    return error_response(status, ErrorKind::InvalidInput, message)
+                }
+            };
+            match crop_response(detector, settings, &content_type, &url, body) {
+                Ok(response) => response,
+                Err(err) => {
+                    let kind = error_kind(&err);
+                    log::warn!(
+                        "Crop failed ({kind}): {}",
+                        err.to_string().lines().next().unwrap_or_default()
+                    );
+                    error_response(status_code(kind), kind, err)
+                }
+            }
+        }
+        (_, "/health") | (_, "/crop") => error_response(
+            405,
+            ErrorKind::InvalidInput,
+            format!("{} is not allowed on {path}", request.method()),
+        ),
+        _ => error_response(
+            404,
+            ErrorKind::InvalidInput,
+            format!("Unknown endpoint {path}, expected /health or /crop"),
+        ),
+    }
+}
+
+/// Serves requests until the server stops, with its own detector kept across requests
+fn worker(
+    server: &Server,
+    settings: &Settings,
+    workers: usize,
+    max_body_bytes: usize,
+) -> Result<()> {
+    let detector = new_marker_detector()?;
+    loop {
+        let mut request = match server.recv() {
+            Ok(request) => request,
+            Err(err) => {
+                log::error!("Could not receive a request: {err}");
+                return Ok(());
+            }
+        };
+        let start = Instant::now();
+        let (method, url) = (request.method().clone(), request.url().to_string());
+        let response = handle_request(&mut request, &detector, settings, workers, max_body_bytes);
+        let status = response.status_code().0;
+        if let Err(err) = request.respond(response) {
+            log::warn!("Could not send the response to {method} {url}: {err}");
+        }
+        log::info!(
+            "{method} {url} {status} ({} ms)",
+            start.elapsed().as_millis()
+        );
+    }
+}
+
+fn main() {
+    if let Err(err) = run() {
+        eprintln!("Error: {err:?}");
+        std::process::exit(error_kind(&err).exit_code());
+    }
+}
+
+fn run() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    let extra_config = prescan_option(&args, "--config").map(PathBuf::from);
+    let settings = Settings::load(extra_config.as_deref()).with_kind(ErrorKind::InvalidInput)?;
+
+    let mut address = String::from("127.0.0.1:8080");
+    let mut workers = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1);
+    let mut max_body_mb: usize = 50;
+    let mut config_path = String::new();
+    let mut quiet = false;
+    {
+        let mut parser = ArgumentParser::new();
+        parser.set_description(DESCRIPTION);
+
+        parser.refer(&mut address).add_option(
+            &["-a", "--address"],
+            Store,
+            "Address and port to listen on. Default is '127.0.0.1:8080' (this computer only).",
+        );
+
+        parser.refer(&mut workers).add_option(
+            &["-j", "--jobs"],
+            Store,
+            "Number of requests processed in parallel. Default is the number of CPU cores.",
+        );
+
+        parser.refer(&mut max_body_mb)
+            .add_option(&["--max_body_mb"], Store,
+            "Largest request body accepted, in MB. Larger uploads are refused with the status 413. Default is 50.");
+
+        parser
+            .refer(&mut config_path)
+            .add_option(&["--config"], Store, SETTINGS_HELP);
+
+        parser.refer(&mut quiet).add_option(
+            &["-q", "--quiet"],
+            StoreTrue,
+            "Only log warnings and errors.",
+        );
+
+        if let Err(code) = parser.parse(args, &mut std::io::stdout(), &mut std::io::stderr()) {
+            std::process::exit(code);
+        }
+    }
+    init_logger(quiet);
+    log::info!("Settings loaded from {:?}", settings.sources);
+    settings.log_issues();
+
+    if workers == 0 {
+        return Err(kind_error(
+            ErrorKind::InvalidInput,
+            "--jobs must be at least 1",
+        ));
+    }
+    if workers > 1 {
+        // Parallelism comes from the workers, OpenCV's own threads would oversubscribe the CPU
+        opencv::core::set_num_threads(1)?;
+    }
+    let max_body_bytes = max_body_mb * 1024 * 1024;
+    let server = Server::http(&address).map_err(|err| {
+        kind_error(
+            ErrorKind::InvalidInput,
+            format!("Could not listen on {address}: {err}"),
+        )
+    })?;
+    log::info!("Listening on http://{address} with {workers} worker(s)");
+
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers)
+            .map(|_| scope.spawn(|| worker(&server, &settings, workers, max_body_bytes)))
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("A worker panicked")))
+            })
+            .collect::<Result<Vec<()>>>()
+    })?;
+    Ok(())
+}
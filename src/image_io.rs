@@ -0,0 +1,208 @@
+use anyhow::Result;
+use opencv::{
+    core::{self, Mat, Vector},
+    imgcodecs,
+    prelude::*,
+};
+use std::{
+    io::{BufRead, Cursor, Seek},
+    path::Path,
+    str::FromStr,
+};
+
+/// Extensions of the images that can be decoded by `imgcodecs::imdecode`
+pub const IMAGE_EXTENSIONS: [&str; 22] = [
+    "bmp", "dib", "jpeg", "jpg", "jpe", "jp2", "png", "webp", "avif", "pbm", "pgm", "ppm", "pxm",
+    "pnm", "pfm", "sr", "ras", "tiff", "tif", "exr", "hdr", "pic",
+];
+
+/// Which fields of the source EXIF are carried over to the output metadata
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SourceMetadataPolicy {
+    /// Nothing from the source
+    Strip,
+    /// Capture date and camera fields, GPS position excluded (default, apiary locations must
+    /// not leak in shared wing images)
+    #[default]
+    Capture,
+    /// Capture date, camera fields and GPS position
+    All,
+}
+
+impl FromStr for SourceMetadataPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "strip" | "none" => Ok(SourceMetadataPolicy::Strip),
+            "capture" => Ok(SourceMetadataPolicy::Capture),
+            "all" => Ok(SourceMetadataPolicy::All),
+            _ => Err(anyhow::anyhow!(
+                "Unknown source metadata policy {s:?}, expected 'strip', 'capture' or 'all'"
+            )),
+        }
+    }
+}
+
+/// EXIF fields of an input photo that matter for loading and for the output metadata
+#[derive(Debug, Clone, Default)]
+pub struct SourceExif {
+    pub orientation: u32,
+    pub capture_date: Option<String>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub gps_latitude: Option<String>,
+    pub gps_longitude: Option<String>,
+    pub gps_altitude: Option<String>,
+}
+
+impl SourceExif {
+    /// Returns the default (no orientation, no fields) if the image has no readable EXIF
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        SourceExif::from_container(&mut Cursor::new(bytes))
+    }
+
+    fn from_container<R: BufRead + Seek>(container: &mut R) -> Self {
+        let Ok(exif) = exif::Reader::new().read_from_container(container) else {
+            return SourceExif::default();
+        };
+
+        let get_string = |tag: exif::Tag| -> Option<String> {
+            exif.get_field(tag, exif::In::PRIMARY).map(|field| {
+                field
+                    .display_value()
+                    .with_unit(&exif)
+                    .to_string()
+                    .trim_matches('"')
+                    .to_string()
+            })
+        };
+        let get_gps = |value_tag: exif::Tag, ref_tag: exif::Tag| -> Option<String> {
+            let value = get_string(value_tag)?;
+            match get_string(ref_tag) {
+                Some(reference) => Some(format!("{value} {reference}")),
+                None => Some(value),
+            }
+        };
+
+        SourceExif {
+            orientation: exif
+                .get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+                .and_then(|field| field.value.get_uint(0))
+                .unwrap_or(1),
+            capture_date: get_string(exif::Tag::DateTimeOriginal)
+                .or_else(|| get_string(exif::Tag::DateTime)),
+            camera_make: get_string(exif::Tag::Make),
+            camera_model: get_string(exif::Tag::Model),
+            gps_latitude: get_gps(exif::Tag::GPSLatitude, exif::Tag::GPSLatitudeRef),
+            gps_longitude: get_gps(exif::Tag::GPSLongitude, exif::Tag::GPSLongitudeRef),
+            gps_altitude: get_string(exif::Tag::GPSAltitude),
+        }
+    }
+
+    /// Fields allowed by `policy`, as (XMP property, value) pairs
+    pub fn filtered_entries(&self, policy: SourceMetadataPolicy) -> Vec<(&'static str, String)> {
+        if policy == SourceMetadataPolicy::Strip {
+            return Vec::new();
+        }
+        let mut fields = Vec::new();
+        fields.push(("exif:DateTimeOriginal", &self.capture_date));
+        fields.push(("tiff:Make", &self.camera_make));
+        fields.push(("tiff:Model", &self.camera_model));
+        if policy == SourceMetadataPolicy::All {
+            fields.push(("exif:GPSLatitude", &self.gps_latitude));
+            fields.push(("exif:GPSLongitude", &self.gps_longitude));
+            fields.push(("exif:GPSAltitude", &self.gps_altitude));
+        }
+        fields
+            .into_iter()
+            .filter_map(|(key, value)| value.clone().map(|value| (key, value)))
+            .collect()
+    }
+}
+
+/// Rotates and/or flips an image so that it is displayed upright, following the EXIF
+/// orientation values 1 to 8
+pub fn apply_orientation(img: Mat, orientation: u32) -> Result<Mat, opencv::Error> {
+    let mut oriented = Mat::default();
+    match orientation {
+        2 => core::flip(&img, &mut oriented, 1)?,
+        3 => core::rotate(&img, &mut oriented, core::ROTATE_180)?,
+        4 => core::flip(&img, &mut oriented, 0)?,
+        5 => core::transpose(&img, &mut oriented)?,
+        6 => core::rotate(&img, &mut oriented, core::ROTATE_90_CLOCKWISE)?,
+        7 => {
+            let mut rotated = Mat::default();
+            core::rotate(&img, &mut rotated, core::ROTATE_90_CLOCKWISE)?;
+            core::flip(&rotated, &mut oriented, 0)?;
+        }
+        8 => core::rotate(&img, &mut oriented, core::ROTATE_90_COUNTERCLOCKWISE)?,
+        _ => return Ok(img),
+    };
+    Ok(oriented)
+}
+
+/// Same as `decode_image` for a file, the path does not need to be valid UTF-8
+pub fn read_image(path: &Path) -> Result<(Mat, SourceExif)> {
+    let bytes = std::fs::read(path)
+        .map_err(|err| anyhow::anyhow!("Image {:?} could not be read: {}", path, err))?;
+    decode_image(&bytes)
+        .map_err(|err| anyhow::anyhow!("Image {:?} could not be read: {}", path, err))
+}
+
+/// Decodes an image as stored (no implicit conversion by OpenCV) and applies its EXIF orientation
+pub fn decode_image(bytes: &[u8]) -> Result<(Mat, SourceExif)> {
+    let img = imgcodecs::imdecode(
+        &Vector::<u8>::from_slice(bytes),
+        imgcodecs::IMREAD_UNCHANGED,
+    )?;
+    if img.empty() {
+        return Err(anyhow::anyhow!(
+            "The data ({} bytes) is not an image in a supported format",
+            bytes.len()
+        ));
+    }
+    let source_exif = SourceExif::from_bytes(bytes);
+    if source_exif.orientation > 1 {
+        log::info!("Applying EXIF orientation {}", source_exif.orientation);
+    }
+    let img = apply_orientation(img, source_exif.orientation)?;
+    Ok((img, source_exif))
+}
+
+/// Encodes an image in the format of `extension` (without the dot, e.g. 'png'), `params` are
+/// the same as for `imgcodecs::imwrite`
+pub fn encode_image(img: &Mat, extension: &str, params: &Vector<i32>) -> Result<Vec<u8>> {
+    let mut buffer = Vector::<u8>::new();
+    if !imgcodecs::imencode(&format!(".{extension}"), img, &mut buffer, params)? {
+        return Err(anyhow::anyhow!(
+            "The image could not be encoded as {extension:?}"
+        ));
+    }
+    Ok(buffer.to_vec())
+}
+
+/// Encodes an image in the format given by the extension of `path` and writes it
+pub fn write_image(path: &Path, img: &Mat, params: &Vector<i32>) -> Result<()> {
+    let bytes = encode_image(img, &path_extension(path), params)?;
+    std::fs::write(path, bytes)
+        .map_err(|err| anyhow::anyhow!("Image {:?} could not be written: {}", path, err))
+}
+
+/// Usual extension of the format of an encoded image, e.g. 'jpg' for a JPEG upload
+pub fn guess_extension(bytes: &[u8]) -> Option<String> {
+    image::guess_format(bytes).ok().and_then(|format| {
+        format
+            .extensions_str()
+            .first()
+            .map(|extension| extension.to_string())
+    })
+}
+
+/// Lowercase extension of `path` without the dot, empty if there is none
+pub fn path_extension(path: &Path) -> String {
+    path.extension()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_lowercase()
+}
@@ -0,0 +1,38 @@
+//! Crop engine of ID My Bee as a library, shared by the binaries and the bindings to other
+//! languages: the C ABI (`include/idmybee.h`) and the Python module. The binaries only declare
+//! their own modules (batches, reports, file explorer...). Without the `opencv-backend` feature
+//! only the pure Rust warp (`rust_warp`) is built.
+
+#[cfg(feature = "opencv-backend")]
+pub mod capi;
+#[cfg(feature = "opencv-backend")]
+pub mod card_layout;
+#[cfg(any(feature = "cli", feature = "server"))]
+pub mod command_line;
+#[cfg(feature = "opencv-backend")]
+pub mod config_schema;
+#[cfg(feature = "opencv-backend")]
+pub mod crop;
+#[cfg(feature = "opencv-backend")]
+pub mod encoding;
+#[cfg(feature = "opencv-backend")]
+pub mod image_io;
+#[cfg(feature = "opencv-backend")]
+pub mod marker_utils;
+#[cfg(feature = "opencv-backend")]
+pub mod metadata;
+#[cfg(feature = "opencv-backend")]
+pub mod output_template;
+#[cfg(feature = "opencv-backend")]
+pub mod pipeline_error;
+#[cfg(feature = "opencv-backend")]
+pub mod presets;
+#[cfg(feature = "rust-warp")]
+pub mod rust_warp;
+#[cfg(feature = "opencv-backend")]
+pub mod settings;
+#[cfg(feature = "opencv-backend")]
+pub mod sidecar;
+
+#[cfg(feature = "python")]
+mod python;
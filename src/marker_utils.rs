@@ -1,13 +1,17 @@
 pub mod marker_processing {
     use opencv::{
-        core::{Mat, Point2f, Scalar, Size, Vector, BORDER_CONSTANT, DECOMP_LU},
-        highgui, imgproc,
+        core::{
+            Mat, Point2f, Rect, Scalar, Size, Vector, BORDER_CONSTANT, BORDER_DEFAULT, DECOMP_LU,
+        },
+        imgproc,
         objdetect::*,
         prelude::*,
         types::VectorOfPoint2f,
     };
 
     use num_derive::FromPrimitive;
+    use std::str::FromStr;
+    use strum_macros::{Display, EnumIter};
 
     #[derive(FromPrimitive)]
     enum ZoomMode {
@@ -16,19 +20,78 @@ pub mod marker_processing {
         Zoom = 2,
     }
 
-    type MarkersVec = Vector<VectorOfPoint2f>;
+    pub type MarkersVec = Vector<VectorOfPoint2f>;
+
+    /// Interpolation used to upscale the input before the detection
+    pub const INTERPOLATION: i32 = imgproc::INTER_LANCZOS4;
+
+    /// Interpolation of the perspective correction
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Display, EnumIter)]
+    pub enum Interpolation {
+        #[strum(serialize = "nearest")]
+        Nearest,
+        #[strum(serialize = "linear")]
+        Linear,
+        #[strum(serialize = "cubic")]
+        Cubic,
+        #[strum(serialize = "area")]
+        Area,
+        #[default]
+        #[strum(serialize = "lanczos4")]
+        Lanczos4,
+    }
+
+    impl Interpolation {
+        pub fn flag(&self) -> i32 {
+            match self {
+                Interpolation::Nearest => imgproc::INTER_NEAREST,
+                Interpolation::Linear => imgproc::INTER_LINEAR,
+                Interpolation::Cubic => imgproc::INTER_CUBIC,
+                Interpolation::Area => imgproc::INTER_AREA,
+                Interpolation::Lanczos4 => imgproc::INTER_LANCZOS4,
+            }
+        }
+    }
+
+    impl FromStr for Interpolation {
+        type Err = anyhow::Error;
+
+        fn from_str(s: &str) -> anyhow::Result<Self> {
+            match s.trim().to_lowercase().as_str() {
+                "nearest" => Ok(Interpolation::Nearest),
+                "linear" => Ok(Interpolation::Linear),
+                "cubic" => Ok(Interpolation::Cubic),
+                "area" => Ok(Interpolation::Area),
+                "lanczos4" | "lanczos" => Ok(Interpolation::Lanczos4),
+                _ => Err(anyhow::anyhow!(
+                    "Unknown interpolation {s:?}, expected 'nearest', 'linear', 'cubic', 'area' or 'lanczos4'"
+                )),
+            }
+        }
+    }
+
+    /// Detector of the ID My Bee markers, it can be kept to process several images
+    pub fn new_marker_detector() -> Result<ArucoDetector, opencv::Error> {
+        ArucoDetector::new(
+            &get_predefined_dictionary(PredefinedDictionaryType::DICT_4X4_50)?,
+            &DetectorParameters::default()?,
+            RefineParameters::new(10., 3., true)?,
+        )
+    }
 
     pub fn get_image_markers(
         img: &Mat,
+    ) -> Result<(MarkersVec, Vector<i32>, MarkersVec), opencv::Error> {
+        detect_markers(&new_marker_detector()?, img)
+    }
+
+    pub fn detect_markers(
+        aruco_detector: &ArucoDetector,
+        img: &Mat,
     ) -> Result<(MarkersVec, Vector<i32>, MarkersVec), opencv::Error> {
         let mut gray_image = Mat::default();
         imgproc::cvt_color(&img, &mut gray_image, imgproc::COLOR_BGR2GRAY, 0)?;
         // show_image(&gray_image);
-        let aruco_detector = ArucoDetector::new(
-            &get_predefined_dictionary(PredefinedDictionaryType::DICT_4X4_50)?,
-            &DetectorParameters::default().unwrap(),
-            RefineParameters::new(10., 3., true)?,
-        )?;
         let mut markers_coor: MarkersVec = Vector::new();
         let mut markers_id: Vector<i32> = Vector::new();
         let mut rejected_markers: MarkersVec = Vector::new();
@@ -38,11 +101,36 @@ pub mod marker_processing {
             &mut markers_id,
             &mut rejected_markers,
         )?;
-        println!("Markers found: {:?}", markers_id);
+        log::info!("Markers found: {:?}", markers_id);
 
         Ok((markers_coor, markers_id, rejected_markers))
     }
 
+    /// Specimen ID of the QR code printed on the card (see 'card --ids'), `None` when there is
+    /// none or it cannot be decoded. OpenCV has no DataMatrix decoder, only QR codes are read.
+    pub fn detect_card_id(img: &Mat) -> Result<Option<String>, opencv::Error> {
+        let detector = QRCodeDetector::default()?;
+        let mut points = Mat::default();
+        let mut straight_code = Mat::default();
+        let decoded = detector.detect_and_decode(img, &mut points, &mut straight_code)?;
+        let card_id = String::from_utf8_lossy(&decoded).trim().to_string();
+        match card_id.is_empty() {
+            true => Ok(None),
+            false => {
+                log::info!("Card ID read from the QR code: {card_id:?}");
+                Ok(Some(card_id))
+            }
+        }
+    }
+
+    /// `detect_card_id` where a failure of the QR detector only warns, the crop does not need it
+    pub fn read_card_id(img: &Mat) -> Option<String> {
+        detect_card_id(img).unwrap_or_else(|err| {
+            log::warn!("The QR code of the card could not be read: {err}");
+            None
+        })
+    }
+
     pub fn parse_markers(
         points: &MarkersVec,
         markers_id: &Vector<i32>,
@@ -58,32 +146,37 @@ pub mod marker_processing {
         Ok(reordered_points)
     }
 
+    pub fn get_resize_ratios(img_size: &Size, out_size: &Size) -> (f64, f64) {
+        // Ratios appliqués par resize_if_larger_dims (jamais inférieurs à 1)
+        let width_ratio: f64 = (out_size.width as f64 / img_size.width as f64).max(1.);
+        let height_ratio: f64 = (out_size.height as f64 / img_size.height as f64).max(1.);
+        (width_ratio, height_ratio)
+    }
+
     pub fn resize_if_larger_dims(img: Mat, out_size: &Size) -> Result<Mat, opencv::Error> {
         // Resize l'image si elle est plus petite que les dimensions d'output
         let img_size = img.size()?;
-        let width_ratio: f64 = (out_size.width as f64 / img_size.width as f64).max(1.);
-        let height_ratio: f64 = (out_size.height as f64 / img_size.height as f64).max(1.);
+        let (width_ratio, height_ratio) = get_resize_ratios(&img_size, out_size);
         if width_ratio == 1. && height_ratio == 1. {
             return Ok(img);
         }
         let mut resized_img = img.clone();
-        println!("Original image size {:?}", img.size()?);
-        println!("Image resized by w{:.2} h{:.2}", width_ratio, height_ratio);
+        log::info!("Original image size {:?}", img.size()?);
+        log::info!("Image resized by w{:.2} h{:.2}", width_ratio, height_ratio);
         imgproc::resize(
             &img,
             &mut resized_img,
             Size::default(),
             width_ratio,
             height_ratio,
-            imgproc::INTER_LANCZOS4,
+            INTERPOLATION,
         )?;
 
-        println!("Resized image size {:?}", resized_img.size()?);
+        log::info!("Resized image size {:?}", resized_img.size()?);
         Ok(resized_img)
     }
 
-    pub fn correct_image(
-        img: &Mat,
+    pub fn get_correction_matrix(
         points: &VectorOfPoint2f,
         out_size: &Size,
         zoom: &f32,
@@ -102,9 +195,14 @@ pub mod marker_processing {
         ]);
 
         // Obtenir la matrice de transformation en perspective
-        let perspective_transform =
-            imgproc::get_perspective_transform(&points, &target_points, DECOMP_LU)?;
+        imgproc::get_perspective_transform(&points, &target_points, DECOMP_LU)
+    }
 
+    pub fn warp_image(
+        img: &Mat,
+        perspective_transform: &Mat,
+        interpolation: Interpolation,
+    ) -> Result<Mat, opencv::Error> {
         // Créer une nouvelle matrice pour stocker l'image transformée
         let mut transformed_image = Mat::default();
 
@@ -114,7 +212,7 @@ pub mod marker_processing {
             &mut transformed_image,
             &perspective_transform,
             img.size()?,
-            imgproc::INTER_LANCZOS4,
+            interpolation.flag(),
             BORDER_CONSTANT,
             Scalar::default(),
         )?;
@@ -122,9 +220,135 @@ pub mod marker_processing {
         Ok(transformed_image)
     }
 
+    pub fn correct_image(
+        img: &Mat,
+        points: &VectorOfPoint2f,
+        out_size: &Size,
+        zoom: &f32,
+    ) -> Result<Mat, opencv::Error> {
+        let perspective_transform = get_correction_matrix(points, out_size, zoom)?;
+        warp_image(img, &perspective_transform, Interpolation::default())
+    }
+
+    pub fn matrix_to_array(matrix: &Mat) -> Result<[[f64; 3]; 3], opencv::Error> {
+        let mut array = [[0f64; 3]; 3];
+        for (r, row) in array.iter_mut().enumerate() {
+            for (c, value) in row.iter_mut().enumerate() {
+                *value = *matrix.at_2d::<f64>(r as i32, c as i32)?;
+            }
+        }
+        Ok(array)
+    }
+
+    /// Marker corners as arrays, in the coordinates of the image before `resize_if_larger_dims`
+    pub fn markers_to_arrays(
+        markers: &MarkersVec,
+        working_scale: (f64, f64),
+    ) -> Vec<[[f32; 2]; 4]> {
+        let (width_ratio, height_ratio) = working_scale;
+        markers
+            .iter()
+            .map(|marker| {
+                let mut corners = [[0f32; 2]; 4];
+                for (corner, point) in corners.iter_mut().zip(marker.iter()) {
+                    *corner = [
+                        (point.x as f64 / width_ratio) as f32,
+                        (point.y as f64 / height_ratio) as f32,
+                    ];
+                }
+                corners
+            })
+            .collect()
+    }
+
+    /// Points in the coordinates of the image before `resize_if_larger_dims` moved to the working
+    /// image, e.g. stored or manual corners
+    pub fn points_to_working(points: &[[f32; 2]; 4], working_scale: (f64, f64)) -> VectorOfPoint2f {
+        let (width_ratio, height_ratio) = working_scale;
+        points
+            .iter()
+            .map(|[x, y]| {
+                Point2f::new(
+                    (*x as f64 * width_ratio) as f32,
+                    (*y as f64 * height_ratio) as f32,
+                )
+            })
+            .collect()
+    }
+
+    /// Detection confidence from 0 to 1: ratio of the shortest to the longest side of the least
+    /// regular marker. Markers seen as squares give 1, strong perspective, blur or a partial
+    /// detection give less. `None` when no marker was found.
+    pub fn markers_confidence(marker_corners: &[[[f32; 2]; 4]]) -> Option<f64> {
+        marker_corners
+            .iter()
+            .map(|corners| {
+                let sides: Vec<f64> = (0..4)
+                    .map(|i| {
+                        let ([x0, y0], [x1, y1]) = (corners[i], corners[(i + 1) % 4]);
+                        ((x1 - x0) as f64).hypot((y1 - y0) as f64)
+                    })
+                    .collect();
+                let longest = sides.iter().cloned().fold(0., f64::max);
+                match longest > 0. {
+                    true => sides.iter().cloned().fold(f64::INFINITY, f64::min) / longest,
+                    false => 0.,
+                }
+            })
+            .reduce(f64::min)
+    }
+
+    /// Variance of the Laplacian of the image, higher is sharper. Only comparable between
+    /// images of the same dimensions and zoom.
+    pub fn sharpness(img: &Mat) -> Result<f64, opencv::Error> {
+        let mut gray_image = Mat::default();
+        match img.channels() {
+            3 => imgproc::cvt_color(&img, &mut gray_image, imgproc::COLOR_BGR2GRAY, 0)?,
+            4 => imgproc::cvt_color(&img, &mut gray_image, imgproc::COLOR_BGRA2GRAY, 0)?,
+            _ => gray_image = img.clone(),
+        }
+        let mut laplacian = Mat::default();
+        imgproc::laplacian(
+            &gray_image,
+            &mut laplacian,
+            opencv::core::CV_64F,
+            1,
+            1.,
+            0.,
+            BORDER_DEFAULT,
+        )?;
+        let mut mean = Mat::default();
+        let mut std_dev = Mat::default();
+        opencv::core::mean_std_dev(
+            &laplacian,
+            &mut mean,
+            &mut std_dev,
+            &opencv::core::no_array(),
+        )?;
+        let std_dev = *std_dev.at::<f64>(0)?;
+        Ok(std_dev * std_dev)
+    }
+
+    pub fn get_crop_window(out_size: &Size) -> Rect {
+        Rect {
+            x: 0,
+            y: 0,
+            width: out_size.width,
+            height: out_size.height,
+        }
+    }
+
+    #[cfg(feature = "highgui-preview")]
     pub fn show_image(image: &Mat) -> Result<(), opencv::Error> {
-        highgui::imshow("Preprocess image", image)?;
-        highgui::wait_key(0)?;
+        opencv::highgui::imshow("Preprocess image", image)?;
+        opencv::highgui::wait_key(0)?;
+        Ok(())
+    }
+
+    /// Builds without the OpenCV windows (e.g. headless servers) only warn
+    #[cfg(not(feature = "highgui-preview"))]
+    pub fn show_image(_image: &Mat) -> Result<(), opencv::Error> {
+        log::warn!("The image cannot be shown, this build has no preview window (feature 'highgui-preview')");
         Ok(())
     }
 }
@@ -0,0 +1,463 @@
+use anyhow::Result;
+use opencv::{
+    core::{Mat, Size, Vector},
+    imgcodecs,
+};
+use std::path::Path;
+
+use crate::card_layout::CardLayout;
+use crate::image_io::{SourceExif, SourceMetadataPolicy};
+use crate::marker_utils::marker_processing::matrix_to_array;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+const XMP_NAMESPACE: &str = "http://ns.adobe.com/xap/1.0/\0";
+const IDMYBEE_XMP_NAMESPACE: &str = "https://idmybee.org/ns/crop/1.0/";
+/// TIFF tag of the XMP packet
+const TIFF_XMP_TAG: u16 = 700;
+
+/// Provenance and physical scale written into each output image
+#[derive(Debug, Clone)]
+pub struct OutputMetadata {
+    pub source_filename: String,
+    pub card_layout: String,
+    pub px_per_mm: (f64, f64),
+    pub homography: [[f64; 3]; 3],
+    pub tool_version: String,
+    /// Specimen ID of the QR code on the card
+    pub card_id: Option<String>,
+    /// Source EXIF fields kept by the `SourceMetadataPolicy`, with their XMP property name
+    pub source_fields: Vec<(&'static str, String)>,
+}
+
+impl OutputMetadata {
+    pub fn new(
+        input_path: &Path,
+        card_layout: &CardLayout,
+        out_size: &Size,
+        zoom: f32,
+        homography: &Mat,
+    ) -> Result<Self> {
+        Ok(OutputMetadata {
+            source_filename: input_path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string(),
+            card_layout: card_layout.name.clone(),
+            px_per_mm: card_layout.px_per_mm(out_size, zoom),
+            homography: matrix_to_array(homography)?,
+            tool_version: String::from(env!("CARGO_PKG_VERSION")),
+            card_id: None,
+            source_fields: Vec::new(),
+        })
+    }
+
+    pub fn with_source_exif(
+        mut self,
+        source_exif: &SourceExif,
+        policy: SourceMetadataPolicy,
+    ) -> Self {
+        self.source_fields = source_exif.filtered_entries(policy);
+        self
+    }
+
+    pub fn with_card_id(mut self, card_id: Option<String>) -> Self {
+        self.card_id = card_id;
+        self
+    }
+
+    fn entries(&self) -> Vec<(&'static str, String)> {
+        let mut entries = vec![
+            ("idmybee:SourceFile", self.source_filename.clone()),
+            ("idmybee:CardLayout", self.card_layout.clone()),
+            ("idmybee:PixelsPerMmX", format!("{:.4}", self.px_per_mm.0)),
+            ("idmybee:PixelsPerMmY", format!("{:.4}", self.px_per_mm.1)),
+            (
+                "idmybee:Homography",
+                serde_json::to_string(&self.homography).unwrap_or_default(),
+            ),
+            ("idmybee:Software", format!("IDMyBee {}", self.tool_version)),
+        ];
+        if let Some(card_id) = self.card_id.as_ref() {
+            entries.push(("idmybee:CardId", card_id.clone()));
+        }
+        entries.extend(self.source_fields.iter().cloned());
+        entries
+    }
+
+    /// Parameters for `imgcodecs::imencode`. Only the TIFF encoder of OpenCV can store the
+    /// resolution itself, the other formats and the TIFF XMP are patched afterwards by `embed`.
+    pub fn imwrite_params(&self, extension: &str) -> Vector<i32> {
+        match extension {
+            "tif" | "tiff" => Vector::from_slice(&[
+                imgcodecs::IMWRITE_TIFF_RESUNIT,
+                3, // centimeters
+                imgcodecs::IMWRITE_TIFF_XDPI,
+                (self.px_per_mm.0 * 10.).round() as i32,
+                imgcodecs::IMWRITE_TIFF_YDPI,
+                (self.px_per_mm.1 * 10.).round() as i32,
+            ]),
+            _ => Vector::new(),
+        }
+    }
+
+    /// Add the metadata to an image encoded by `imgcodecs::imencode` in the format of `extension`
+    pub fn embed(&self, bytes: Vec<u8>, extension: &str) -> Result<Vec<u8>> {
+        match extension {
+            "png" => self.embed_png(&bytes),
+            "jpg" | "jpeg" | "jpe" => self.embed_jpeg(&bytes),
+            "tif" | "tiff" => self.embed_tiff(&bytes),
+            ext => {
+                log::warn!(
+                    "Metadata cannot be embedded in {ext:?} files, only the pixels were saved. Write a JSON sidecar (--sidecar) to keep the provenance and scale of the crop."
+                );
+                Ok(bytes)
+            }
+        }
+    }
+
+    fn embed_png(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        if !bytes.starts_with(&PNG_SIGNATURE) {
+            return Err(anyhow::anyhow!("Invalid PNG signature"));
+        }
+        let mut out = Vec::with_capacity(bytes.len() + 1024);
+        out.extend_from_slice(&PNG_SIGNATURE);
+
+        let mut pos = PNG_SIGNATURE.len();
+        while pos + 12 <= bytes.len() {
+            let length = u32::from_be_bytes(bytes[pos..pos + 4].try_into()?) as usize;
+            let chunk_end = pos + 12 + length;
+            if chunk_end > bytes.len() {
+                return Err(anyhow::anyhow!("Truncated PNG chunk at byte {pos}"));
+            }
+            let chunk_type = &bytes[pos + 4..pos + 8];
+            // An existing resolution is replaced by ours
+            if chunk_type != b"pHYs" {
+                out.extend_from_slice(&bytes[pos..chunk_end]);
+            }
+            if chunk_type == b"IHDR" {
+                let px_per_meter_x = (self.px_per_mm.0 * 1000.).round() as u32;
+                let px_per_meter_y = (self.px_per_mm.1 * 1000.).round() as u32;
+                let mut phys = Vec::with_capacity(9);
+                phys.extend_from_slice(&px_per_meter_x.to_be_bytes());
+                phys.extend_from_slice(&px_per_meter_y.to_be_bytes());
+                phys.push(1); // meters
+                write_png_chunk(&mut out, b"pHYs", &phys);
+
+                for (key, value) in self.entries() {
+                    // iTXt: keyword, null, no compression, method, empty language and
+                    // translated keyword, UTF-8 text
+                    let mut itxt = Vec::new();
+                    itxt.extend_from_slice(key.as_bytes());
+                    itxt.extend_from_slice(&[0, 0, 0, 0, 0]);
+                    itxt.extend_from_slice(value.as_bytes());
+                    write_png_chunk(&mut out, b"iTXt", &itxt);
+                }
+            }
+            pos = chunk_end;
+        }
+        Ok(out)
+    }
+
+    fn embed_jpeg(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        if !bytes.starts_with(&[0xff, 0xd8]) {
+            return Err(anyhow::anyhow!("Invalid JPEG start of image marker"));
+        }
+        // JFIF densities are integers, dots per centimeter keeps more precision than per inch
+        let jfif_density = |px_per_mm: f64| (px_per_mm * 10.).round().min(65535.) as u16;
+        let mut jfif = Vec::from(*b"JFIF\0\x01\x02\x02");
+        jfif.extend_from_slice(&jfif_density(self.px_per_mm.0).to_be_bytes());
+        jfif.extend_from_slice(&jfif_density(self.px_per_mm.1).to_be_bytes());
+        jfif.extend_from_slice(&[0, 0]);
+
+        let mut xmp = Vec::from(XMP_NAMESPACE.as_bytes());
+        xmp.extend_from_slice(self.xmp_packet().as_bytes());
+
+        let mut out = Vec::with_capacity(bytes.len() + 2048);
+        out.extend_from_slice(&[0xff, 0xd8]);
+        write_jpeg_segment(&mut out, 0xe0, &jfif)?;
+        write_jpeg_segment(&mut out, 0xe1, &xmp)?;
+
+        // Skip the original JFIF segment, everything else is copied as is
+        let mut pos = 2;
+        if bytes.len() > pos + 4 && bytes[pos] == 0xff && bytes[pos + 1] == 0xe0 {
+            let length = u16::from_be_bytes([bytes[pos + 2], bytes[pos + 3]]) as usize;
+            if bytes[pos + 4..].starts_with(b"JFIF\0") {
+                pos += 2 + length;
+            }
+        }
+        out.extend_from_slice(&bytes[pos..]);
+        Ok(out)
+    }
+
+    /// XMP packet in tag 700 of the first IFD. The IFD is rewritten at the end of the file with
+    /// the new entry, the old one is left unreferenced.
+    fn embed_tiff(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        let little_endian = match bytes.get(..4) {
+            Some([b'I', b'I', 42, 0]) => true,
+            Some([b'M', b'M', 0, 42]) => false,
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "Invalid or BigTIFF header, only classic TIFF is supported"
+                ))
+            }
+        };
+        let field = |pos: usize, size: usize| {
+            bytes
+                .get(pos..pos + size)
+                .ok_or_else(|| anyhow::anyhow!("Truncated TIFF at byte {pos}"))
+        };
+        let read_u16 = |data: &[u8]| match little_endian {
+            true => u16::from_le_bytes([data[0], data[1]]),
+            false => u16::from_be_bytes([data[0], data[1]]),
+        };
+        let read_u32 = |data: &[u8]| match little_endian {
+            true => u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+            false => u32::from_be_bytes([data[0], data[1], data[2], data[3]]),
+        };
+        let u16_bytes = |value: u16| match little_endian {
+            true => value.to_le_bytes(),
+            false => value.to_be_bytes(),
+        };
+        let u32_bytes = |value: u32| match little_endian {
+            true => value.to_le_bytes(),
+            false => value.to_be_bytes(),
+        };
+        let offset = |pos: usize| {
+            u32::try_from(pos)
+                .map_err(|_| anyhow::anyhow!("TIFF larger than 4 GB, the XMP cannot be added"))
+        };
+
+        let ifd = read_u32(field(4, 4)?) as usize;
+        let count = read_u16(field(ifd, 2)?) as usize;
+        let mut entries = (0..count)
+            .map(|i| field(ifd + 2 + 12 * i, 12))
+            .collect::<Result<Vec<&[u8]>>>()?;
+        let next_ifd = read_u32(field(ifd + 2 + 12 * count, 4)?);
+
+        let xmp = self.xmp_packet().into_bytes();
+        let mut out = Vec::with_capacity(bytes.len() + xmp.len() + 12 * (count + 1) + 8);
+        out.extend_from_slice(bytes);
+        let xmp_offset = offset(out.len())?;
+        out.extend_from_slice(&xmp);
+        // IFDs start on a word boundary
+        if out.len() % 2 == 1 {
+            out.push(0);
+        }
+        let new_ifd = offset(out.len())?;
+
+        let mut xmp_entry = Vec::with_capacity(12);
+        xmp_entry.extend_from_slice(&u16_bytes(TIFF_XMP_TAG));
+        xmp_entry.extend_from_slice(&u16_bytes(1)); // BYTE
+        xmp_entry.extend_from_slice(&u32_bytes(offset(xmp.len())?));
+        xmp_entry.extend_from_slice(&u32_bytes(xmp_offset));
+        // Entries are sorted by tag, an existing packet is replaced
+        entries.retain(|entry| read_u16(entry) != TIFF_XMP_TAG);
+        entries.push(&xmp_entry);
+        entries.sort_by_key(|entry| read_u16(entry));
+
+        out.extend_from_slice(&u16_bytes(entries.len() as u16));
+        for entry in entries {
+            out.extend_from_slice(entry);
+        }
+        out.extend_from_slice(&u32_bytes(next_ifd));
+        out[4..8].copy_from_slice(&u32_bytes(new_ifd));
+        Ok(out)
+    }
+
+    fn xmp_packet(&self) -> String {
+        let properties: String = self
+            .entries()
+            .iter()
+            .map(|(key, value)| format!("   <{key}>{}</{key}>\n", xml_escape(value)))
+            .collect();
+        format!(
+            "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n\
+             <x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n\
+             <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n\
+             <rdf:Description rdf:about=\"\" xmlns:idmybee=\"{IDMYBEE_XMP_NAMESPACE}\"\n\
+             \x20xmlns:exif=\"http://ns.adobe.com/exif/1.0/\"\n\
+             \x20xmlns:tiff=\"http://ns.adobe.com/tiff/1.0/\">\n\
+             {properties}\
+             </rdf:Description>\n\
+             </rdf:RDF>\n\
+             </x:xmpmeta>\n\
+             <?xpacket end=\"w\"?>"
+        )
+    }
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn write_png_chunk(out: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(chunk_type);
+    out.extend_from_slice(data);
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(chunk_type);
+    hasher.update(data);
+    out.extend_from_slice(&hasher.finalize().to_be_bytes());
+}
+
+fn write_jpeg_segment(out: &mut Vec<u8>, marker: u8, data: &[u8]) -> Result<()> {
+    let length = u16::try_from(data.len() + 2)
+        .map_err(|_| anyhow::anyhow!("JPEG segment too large ({} bytes)", data.len()))?;
+    out.extend_from_slice(&[0xff, marker]);
+    out.extend_from_slice(&length.to_be_bytes());
+    out.extend_from_slice(data);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, ImageOutputFormat, RgbImage};
+    use std::io::Cursor;
+
+    fn metadata() -> OutputMetadata {
+        OutputMetadata {
+            source_filename: String::from("IMG_0042.jpg"),
+            card_layout: String::from("test_card_v4"),
+            px_per_mm: (33.18, 34.62),
+            homography: [[1.5, 0.1, -20.], [0.05, 1.4, -35.], [1e-4, 2e-5, 1.]],
+            tool_version: String::from("1.1.0"),
+            card_id: Some(String::from("A&B<12>")),
+            source_fields: vec![("exif:DateTimeOriginal", String::from("2023-08-05T23:16:19"))],
+        }
+    }
+
+    fn encoded(format: ImageOutputFormat) -> (RgbImage, Vec<u8>) {
+        let img = RgbImage::from_fn(32, 24, |x, y| {
+            image::Rgb([(x * 8) as u8, (y * 10) as u8, 128])
+        });
+        let mut bytes = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(img.clone())
+            .write_to(&mut bytes, format)
+            .unwrap();
+        (img, bytes.into_inner())
+    }
+
+    /// Type and data of every chunk after the signature
+    fn png_chunks(bytes: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+        let mut chunks = Vec::new();
+        let mut pos = PNG_SIGNATURE.len();
+        while pos + 12 <= bytes.len() {
+            let length = u32::from_be_bytes(bytes[pos..pos + 4].try_into().unwrap()) as usize;
+            let chunk_type = bytes[pos + 4..pos + 8].try_into().unwrap();
+            chunks.push((chunk_type, bytes[pos + 8..pos + 8 + length].to_vec()));
+            pos += 12 + length;
+        }
+        chunks
+    }
+
+    #[test]
+    fn png_round_trip() {
+        let (img, bytes) = encoded(ImageOutputFormat::Png);
+        let out = metadata().embed(bytes, "png").unwrap();
+        // Still a valid PNG with the same pixels, the CRCs are checked by the decoder
+        assert_eq!(image::load_from_memory(&out).unwrap().to_rgb8(), img);
+
+        let chunks = png_chunks(&out);
+        let phys: Vec<&Vec<u8>> = chunks
+            .iter()
+            .filter(|(chunk_type, _)| chunk_type == b"pHYs")
+            .map(|(_, data)| data)
+            .collect();
+        assert_eq!(phys.len(), 1);
+        assert_eq!(u32::from_be_bytes(phys[0][0..4].try_into().unwrap()), 33180);
+        assert_eq!(u32::from_be_bytes(phys[0][4..8].try_into().unwrap()), 34620);
+        assert_eq!(phys[0][8], 1);
+
+        let texts: Vec<(String, String)> = chunks
+            .iter()
+            .filter(|(chunk_type, _)| chunk_type == b"iTXt")
+            .map(|(_, data)| {
+                let key_end = data.iter().position(|byte| *byte == 0).unwrap();
+                let key = String::from_utf8(data[..key_end].to_vec()).unwrap();
+                (
+                    key,
+                    String::from_utf8(data[key_end + 5..].to_vec()).unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(texts.len(), metadata().entries().len());
+        assert!(texts.contains(&(
+            String::from("idmybee:PixelsPerMmX"),
+            String::from("33.1800")
+        )));
+        assert!(texts.contains(&(String::from("idmybee:CardId"), String::from("A&B<12>"))));
+        assert!(texts.contains(&(
+            String::from("exif:DateTimeOriginal"),
+            String::from("2023-08-05T23:16:19")
+        )));
+        let homography = texts
+            .iter()
+            .find(|(key, _)| key == "idmybee:Homography")
+            .unwrap();
+        assert_eq!(
+            serde_json::from_str::<[[f64; 3]; 3]>(&homography.1).unwrap(),
+            metadata().homography
+        );
+    }
+
+    #[test]
+    fn jpeg_round_trip() {
+        let (img, bytes) = encoded(ImageOutputFormat::Jpeg(95));
+        let out = metadata().embed(bytes, "jpg").unwrap();
+        let decoded = image::load_from_memory(&out).unwrap().to_rgb8();
+        assert_eq!(decoded.dimensions(), img.dimensions());
+
+        // JFIF first, with the density in dots per centimeter, then the XMP
+        assert_eq!(&out[..4], &[0xff, 0xd8, 0xff, 0xe0]);
+        assert_eq!(&out[6..11], b"JFIF\0");
+        assert_eq!(out[13], 2);
+        assert_eq!(u16::from_be_bytes([out[14], out[15]]), 332);
+        assert_eq!(u16::from_be_bytes([out[16], out[17]]), 346);
+        let app1 = 4 + u16::from_be_bytes([out[4], out[5]]) as usize;
+        assert_eq!(&out[app1..app1 + 2], &[0xff, 0xe1]);
+        let length = u16::from_be_bytes([out[app1 + 2], out[app1 + 3]]) as usize;
+        let xmp = std::str::from_utf8(&out[app1 + 4..app1 + 2 + length]).unwrap();
+        let packet = xmp.strip_prefix(XMP_NAMESPACE).unwrap();
+        assert_eq!(packet, metadata().xmp_packet());
+        assert!(packet.contains("<idmybee:CardId>A&amp;B&lt;12&gt;</idmybee:CardId>"));
+        assert!(packet.contains("<idmybee:SourceFile>IMG_0042.jpg</idmybee:SourceFile>"));
+        // A single JFIF segment is kept
+        assert_eq!(
+            out.windows(5).filter(|window| window == b"JFIF\0").count(),
+            1
+        );
+    }
+
+    #[test]
+    fn tiff_round_trip() {
+        let (img, bytes) = encoded(ImageOutputFormat::Tiff);
+        let out = metadata().embed(bytes, "tif").unwrap();
+        assert_eq!(image::load_from_memory(&out).unwrap().to_rgb8(), img);
+        let packet = metadata().xmp_packet();
+        assert!(out
+            .windows(packet.len())
+            .any(|window| window == packet.as_bytes()));
+        // Embedding again replaces the packet instead of adding a second tag
+        let again = metadata().embed(out.clone(), "tif").unwrap();
+        assert_eq!(image::load_from_memory(&again).unwrap().to_rgb8(), img);
+    }
+
+    #[test]
+    fn invalid_inputs_are_rejected() {
+        assert!(metadata().embed(vec![1, 2, 3], "png").is_err());
+        assert!(metadata().embed(vec![1, 2, 3], "jpg").is_err());
+        assert!(metadata()
+            .embed(vec![1, 2, 3, 4, 5, 6, 7, 8], "tif")
+            .is_err());
+        assert_eq!(
+            metadata().embed(vec![1, 2, 3], "bmp").unwrap(),
+            vec![1, 2, 3]
+        );
+    }
+}
@@ -0,0 +1,363 @@
+use anyhow::Result;
+use opencv::core::Size;
+use std::{
+    ffi::OsString,
+    fmt,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+
+pub const DEFAULT_CLI_TEMPLATE: &str = "{stem}_preproc_z{zoom}.{ext}";
+pub const DEFAULT_GUI_TEMPLATE: &str = "{stem}_crop.{ext}";
+pub const DEFAULT_OVERLAY_TEMPLATE: &str = "{stem}_overlay.{ext}";
+
+/// Shown in the help of the template options
+pub const PLACEHOLDERS_HELP: &str = "{stem} input filename without extension, {ext} output extension, {zoom} zoom with 2 decimals and '-' as separator (e.g. '1-20'), {region} cropped region in pixels (e.g. '600x400'), {date} capture date of the photo (EXIF, else modification date of the file) as YYYY-MM-DD, {card_id} specimen ID printed on the card ('unknown' when it is not read), {index} position of the input in the batch on 4 digits (e.g. '0001')";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Placeholder {
+    Stem,
+    Ext,
+    Zoom,
+    Region,
+    Date,
+    CardId,
+    Index,
+}
+
+impl FromStr for Placeholder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "stem" => Ok(Placeholder::Stem),
+            "ext" => Ok(Placeholder::Ext),
+            "zoom" => Ok(Placeholder::Zoom),
+            "region" => Ok(Placeholder::Region),
+            "date" => Ok(Placeholder::Date),
+            "card_id" => Ok(Placeholder::CardId),
+            "index" => Ok(Placeholder::Index),
+            _ => Err(anyhow::anyhow!(
+                "Unknown placeholder {{{s}}}, expected one of: {PLACEHOLDERS_HELP}"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Text(String),
+    Placeholder(Placeholder),
+}
+
+/// Values of the placeholders for one output
+#[derive(Debug, Clone, Default)]
+pub struct TemplateValues {
+    pub input_path: PathBuf,
+    pub extension: String,
+    pub zoom: f32,
+    pub out_size: Size,
+    pub date: String,
+    pub card_id: Option<String>,
+    /// Starts at 1
+    pub index: usize,
+}
+
+impl TemplateValues {
+    /// Capture date from the EXIF `DateTimeOriginal` ('2023:08:05 23:16:19'), else the
+    /// modification date of the input
+    pub fn input_date(input_path: &Path, capture_date: Option<&str>) -> String {
+        if let Some(date) = capture_date.and_then(|date| date.get(..10)) {
+            return date.replace(':', "-");
+        }
+        std::fs::metadata(input_path)
+            .and_then(|metadata| metadata.modified())
+            .map(|modified| {
+                chrono::DateTime::<chrono::Local>::from(modified)
+                    .format("%Y-%m-%d")
+                    .to_string()
+            })
+            .unwrap_or_else(|_| String::from("unknown"))
+    }
+}
+
+/// Pattern of the output filenames, e.g. `{stem}_preproc_z{zoom}.{ext}`. It may contain `/` to
+/// write the outputs into subdirectories, e.g. `{date}/{stem}.{ext}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputTemplate {
+    template: String,
+    segments: Vec<Segment>,
+}
+
+impl FromStr for OutputTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut segments = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                segments.push(Segment::Text(rest[..start].to_string()));
+            }
+            let Some(length) = rest[start..].find('}') else {
+                return Err(anyhow::anyhow!(
+                    "Unclosed '{{' in the output template {s:?}"
+                ));
+            };
+            segments.push(Segment::Placeholder(
+                rest[start + 1..start + length].parse()?,
+            ));
+            rest = &rest[start + length + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Text(rest.to_string()));
+        }
+
+        if segments.is_empty() || s.ends_with(['/', '\\']) {
+            return Err(anyhow::anyhow!(
+                "The output template {s:?} does not name a file"
+            ));
+        }
+        if Path::new(s)
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(anyhow::anyhow!(
+                "The output template {s:?} must be relative and stay in the output directory"
+            ));
+        }
+        Ok(OutputTemplate {
+            template: s.to_string(),
+            segments,
+        })
+    }
+}
+
+impl fmt::Display for OutputTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.template)
+    }
+}
+
+impl Default for OutputTemplate {
+    fn default() -> Self {
+        DEFAULT_CLI_TEMPLATE.parse().unwrap()
+    }
+}
+
+impl OutputTemplate {
+    /// Relative path of an output, values never add path separators. The stem of the input is
+    /// kept as is, even when it is not valid UTF-8.
+    pub fn render(&self, values: &TemplateValues) -> PathBuf {
+        let mut rendered = OsString::new();
+        for segment in self.segments.iter() {
+            let value = match segment {
+                Segment::Text(text) => {
+                    rendered.push(text);
+                    continue;
+                }
+                Segment::Placeholder(Placeholder::Stem) => {
+                    // A file stem never contains a separator
+                    rendered.push(values.input_path.file_stem().unwrap_or_default());
+                    continue;
+                }
+                Segment::Placeholder(Placeholder::Ext) => values.extension.clone(),
+                Segment::Placeholder(Placeholder::Zoom) => {
+                    format!("{:.2}", values.zoom).replace('.', "-")
+                }
+                Segment::Placeholder(Placeholder::Region) => {
+                    format!("{}x{}", values.out_size.width, values.out_size.height)
+                }
+                Segment::Placeholder(Placeholder::Date) => values.date.clone(),
+                Segment::Placeholder(Placeholder::CardId) => {
+                    values.card_id.clone().unwrap_or(String::from("unknown"))
+                }
+                Segment::Placeholder(Placeholder::Index) => format!("{:04}", values.index),
+            };
+            rendered.push(value.replace(['/', '\\'], "-"));
+        }
+        PathBuf::from(rendered)
+    }
+}
+
+/// Directories the inputs were collected from: directory arguments as is and the part of glob
+/// patterns before the first wildcard. Files given directly have no root.
+pub fn input_roots(args: &[PathBuf]) -> Vec<PathBuf> {
+    args.iter()
+        .filter_map(|path| {
+            if path.is_dir() {
+                return Some(path.clone());
+            }
+            if !path.to_string_lossy().contains(['*', '?', '[']) {
+                return None;
+            }
+            let root: PathBuf = path
+                .components()
+                .take_while(|component| {
+                    !component
+                        .as_os_str()
+                        .to_string_lossy()
+                        .contains(['*', '?', '['])
+                })
+                .collect();
+            Some(root)
+        })
+        .collect()
+}
+
+/// Subdirectory of the input below its root, so that the input tree can be mirrored in the
+/// output directory. Empty when the input is not below any root.
+pub fn mirrored_subdir(input_path: &Path, input_roots: &[PathBuf]) -> PathBuf {
+    input_roots
+        .iter()
+        .filter_map(|root| input_path.strip_prefix(root).ok())
+        // Deepest root first when roots are nested
+        .min_by_key(|relative| relative.components().count())
+        .and_then(|relative| relative.parent())
+        .map(Path::to_path_buf)
+        .unwrap_or_default()
+}
+
+/// Where the outputs without an explicit path are written
+#[derive(Debug, Clone, Default)]
+pub struct OutputNaming {
+    pub template: OutputTemplate,
+    /// Folder of each input when not set
+    pub out_dir: Option<PathBuf>,
+    /// Recreates the subdirectories of the inputs below their roots in `out_dir`
+    pub mirror_dirs: bool,
+    pub input_roots: Vec<PathBuf>,
+}
+
+impl OutputNaming {
+    pub fn output_path(&self, input_path: &Path, values: &TemplateValues) -> PathBuf {
+        let dir = match self.out_dir.as_ref() {
+            Some(out_dir) if self.mirror_dirs => {
+                out_dir.join(mirrored_subdir(input_path, &self.input_roots))
+            }
+            Some(out_dir) => out_dir.clone(),
+            None => input_path
+                .parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_default(),
+        };
+        dir.join(self.template.render(values))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values() -> TemplateValues {
+        TemplateValues {
+            input_path: PathBuf::from("photos/hive1/IMG_0042.jpg"),
+            extension: String::from("png"),
+            zoom: 1.2,
+            out_size: Size::new(600, 400),
+            date: String::from("2023-08-05"),
+            card_id: None,
+            index: 7,
+        }
+    }
+
+    fn render(template: &str, values: &TemplateValues) -> PathBuf {
+        template.parse::<OutputTemplate>().unwrap().render(values)
+    }
+
+    #[test]
+    fn default_templates() {
+        assert_eq!(
+            render(DEFAULT_CLI_TEMPLATE, &values()),
+            Path::new("IMG_0042_preproc_z1-20.png")
+        );
+        assert_eq!(
+            render(DEFAULT_GUI_TEMPLATE, &values()),
+            Path::new("IMG_0042_crop.png")
+        );
+        assert_eq!(OutputTemplate::default().to_string(), DEFAULT_CLI_TEMPLATE);
+    }
+
+    #[test]
+    fn every_placeholder() {
+        let template = "{date}/{card_id}_{region}_{index}_z{zoom}.{ext}";
+        assert_eq!(
+            render(template, &values()),
+            Path::new("2023-08-05/unknown_600x400_0007_z1-20.png")
+        );
+        let values = TemplateValues {
+            card_id: Some(String::from("AB/12")),
+            ..values()
+        };
+        // Values never add a directory
+        assert_eq!(render("{card_id}.{ext}", &values), Path::new("AB-12.png"));
+    }
+
+    #[test]
+    fn invalid_templates() {
+        for template in [
+            "",
+            "{stem",
+            "{name}.png",
+            "out/",
+            "../{stem}.png",
+            "/tmp/{stem}.png",
+            "a/../../b.png",
+        ] {
+            assert!(
+                template.parse::<OutputTemplate>().is_err(),
+                "{template:?} was accepted"
+            );
+        }
+        assert!("./{stem}.{ext}".parse::<OutputTemplate>().is_ok());
+    }
+
+    #[test]
+    fn capture_date_comes_first() {
+        let date =
+            TemplateValues::input_date(Path::new("missing.jpg"), Some("2023:08:05 23:16:19"));
+        assert_eq!(date, "2023-08-05");
+        assert_eq!(
+            TemplateValues::input_date(Path::new("missing.jpg"), None),
+            "unknown"
+        );
+    }
+
+    #[test]
+    fn mirrored_outputs() {
+        let roots = [PathBuf::from("photos"), PathBuf::from("photos/hive1")];
+        assert_eq!(
+            mirrored_subdir(Path::new("photos/hive2/a/b.jpg"), &roots),
+            Path::new("hive2/a")
+        );
+        // Deepest root first
+        assert_eq!(
+            mirrored_subdir(Path::new("photos/hive1/b.jpg"), &roots),
+            Path::new("")
+        );
+        assert_eq!(
+            mirrored_subdir(Path::new("elsewhere/b.jpg"), &roots),
+            Path::new("")
+        );
+
+        let naming = OutputNaming {
+            template: OutputTemplate::default(),
+            out_dir: Some(PathBuf::from("out")),
+            mirror_dirs: true,
+            input_roots: vec![PathBuf::from("photos")],
+        };
+        let input_path = Path::new("photos/hive1/IMG_0042.jpg");
+        assert_eq!(
+            naming.output_path(input_path, &values()),
+            Path::new("out/hive1/IMG_0042_preproc_z1-20.png")
+        );
+        let naming = OutputNaming {
+            out_dir: None,
+            ..naming
+        };
+        assert_eq!(
+            naming.output_path(input_path, &values()),
+            Path::new("photos/hive1/IMG_0042_preproc_z1-20.png")
+        );
+    }
+}
@@ -0,0 +1,94 @@
+use opencv::{
+    core::{Mat, Point, Point2f, Rect, Scalar, Vector, DECOMP_LU},
+    imgproc, objdetect,
+    prelude::*,
+    types::{VectorOfPoint, VectorOfPoint2f},
+};
+
+use idmybee::marker_utils::marker_processing::MarkersVec;
+
+fn draw_polygon(
+    img: &mut Mat,
+    points: &VectorOfPoint2f,
+    color: Scalar,
+    thickness: i32,
+) -> Result<(), opencv::Error> {
+    let polygon: VectorOfPoint = points
+        .iter()
+        .map(|p| Point::new(p.x.round() as i32, p.y.round() as i32))
+        .collect();
+    let polygons: Vector<VectorOfPoint> = Vector::from_iter([polygon]);
+    imgproc::polylines(img, &polygons, true, color, thickness, imgproc::LINE_AA, 0)
+}
+
+/// Corners of the crop window in the image, i.e. the output rectangle mapped back through the
+/// inverse of the correction matrix
+pub fn crop_outline(
+    perspective_transform: &Mat,
+    crop_window: &Rect,
+) -> Result<VectorOfPoint2f, opencv::Error> {
+    let (x, y) = (crop_window.x as f32, crop_window.y as f32);
+    let (w, h) = (crop_window.width as f32, crop_window.height as f32);
+    let corners = VectorOfPoint2f::from_slice(&[
+        Point2f::new(x, y),
+        Point2f::new(x + w, y),
+        Point2f::new(x + w, y + h),
+        Point2f::new(x, y + h),
+    ]);
+    let mut inverse = Mat::default();
+    opencv::core::invert(perspective_transform, &mut inverse, DECOMP_LU)?;
+    let mut outline = VectorOfPoint2f::new();
+    opencv::core::perspective_transform(&corners, &mut outline, &inverse)?;
+    Ok(outline)
+}
+
+/// Debug view of a detection: accepted markers in green with their ids, rejected candidates in
+/// red, the reference rectangle (outer corners of markers #0 to #3) in blue and the crop
+/// outlines in yellow
+pub fn render_overlay(
+    img: &Mat,
+    markers: &MarkersVec,
+    markers_id: &Vector<i32>,
+    rejected_markers: &MarkersVec,
+    reference_points: Option<&VectorOfPoint2f>,
+    crop_outlines: &[VectorOfPoint2f],
+) -> Result<Mat, opencv::Error> {
+    // Colors need a 3 channels image
+    let mut overlay = Mat::default();
+    match img.channels() {
+        1 => imgproc::cvt_color(img, &mut overlay, imgproc::COLOR_GRAY2BGR, 0)?,
+        4 => imgproc::cvt_color(img, &mut overlay, imgproc::COLOR_BGRA2BGR, 0)?,
+        _ => overlay = img.clone(),
+    }
+    let thickness = (overlay.cols().max(overlay.rows()) / 500).max(1);
+
+    objdetect::draw_detected_markers(
+        &mut overlay,
+        rejected_markers,
+        &Vector::<i32>::new(),
+        Scalar::new(0., 0., 255., 0.),
+    )?;
+    objdetect::draw_detected_markers(
+        &mut overlay,
+        markers,
+        markers_id,
+        Scalar::new(0., 255., 0., 0.),
+    )?;
+    if let Some(points) = reference_points {
+        draw_polygon(
+            &mut overlay,
+            points,
+            Scalar::new(255., 0., 0., 0.),
+            thickness,
+        )?;
+    }
+    for outline in crop_outlines {
+        draw_polygon(
+            &mut overlay,
+            outline,
+            Scalar::new(0., 255., 255., 0.),
+            thickness,
+        )?;
+    }
+    Ok(overlay)
+}
@@ -0,0 +1,94 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Failure classes of the crop pipeline, reported in the machine readable outputs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// Wrong arguments or settings
+    InvalidInput,
+    /// Input missing or not decodable
+    Read,
+    /// Less than the 4 markers needed for the correction
+    MarkersNotFound,
+    /// Sidecar unreadable or not matching its image
+    InvalidSidecar,
+    /// Output could not be encoded or written
+    Write,
+    Other,
+}
+
+impl ErrorKind {
+    /// Exit code of idmybee_cli when a run fails with this kind of error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ErrorKind::Other => 1,
+            ErrorKind::InvalidInput => 2,
+            ErrorKind::Read => 3,
+            ErrorKind::MarkersNotFound => 4,
+            ErrorKind::InvalidSidecar => 5,
+            ErrorKind::Write => 6,
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ErrorKind::InvalidInput => "invalid_input",
+            ErrorKind::Read => "read",
+            ErrorKind::MarkersNotFound => "markers_not_found",
+            ErrorKind::InvalidSidecar => "invalid_sidecar",
+            ErrorKind::Write => "write",
+            ErrorKind::Other => "other",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Error tagged with its `ErrorKind`, the message of the source error is kept as is
+#[derive(Debug)]
+pub struct PipelineError {
+    pub kind: ErrorKind,
+    pub error: anyhow::Error,
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl std::error::Error for PipelineError {}
+
+/// New error of the given kind, e.g. for arguments rejected after parsing
+pub fn kind_error(kind: ErrorKind, message: impl fmt::Display) -> anyhow::Error {
+    PipelineError {
+        kind,
+        error: anyhow::anyhow!("{message}"),
+    }
+    .into()
+}
+
+pub trait WithErrorKind<T> {
+    fn with_kind(self, kind: ErrorKind) -> anyhow::Result<T>;
+}
+
+impl<T, E: Into<anyhow::Error>> WithErrorKind<T> for Result<T, E> {
+    fn with_kind(self, kind: ErrorKind) -> anyhow::Result<T> {
+        self.map_err(|err| {
+            PipelineError {
+                kind,
+                error: err.into(),
+            }
+            .into()
+        })
+    }
+}
+
+/// Kind of an error returned by the pipeline, `Other` if it was never tagged
+pub fn error_kind(err: &anyhow::Error) -> ErrorKind {
+    err.downcast_ref::<PipelineError>()
+        .map(|err| err.kind)
+        .unwrap_or(ErrorKind::Other)
+}
@@ -0,0 +1,143 @@
+use anyhow::Result;
+use configparser::ini::Ini;
+use opencv::core::Rect;
+
+use crate::marker_utils::marker_processing::Interpolation;
+use crate::output_template::OutputTemplate;
+use crate::settings::Settings;
+
+/// Sections of the presets are named `[preset.<name>]`, e.g. `[preset.forewing]`
+pub const PRESET_SECTION_PREFIX: &str = "preset.";
+
+/// Crop window `x y width height` in pixels of the corrected image, e.g. '0 0 600 400'.
+/// Commas may be used as separators.
+pub fn parse_crop_window(value: &str) -> Result<Rect> {
+    let numbers = value
+        .split([' ', ',', '\t'])
+        .filter(|number| !number.is_empty())
+        .map(|number| number.parse::<i32>())
+        .collect::<Result<Vec<i32>, _>>()
+        .map_err(|_| {
+            anyhow::anyhow!("Invalid crop window {value:?}, expected 'x y width height' in pixels")
+        })?;
+    match numbers[..] {
+        [x, y, width, height] if x >= 0 && y >= 0 && width > 0 && height > 0 => Ok(Rect::new(x, y, width, height)),
+        _ => Err(anyhow::anyhow!(
+            "Invalid crop window {value:?}, expected 'x y width height' with a positive origin and size"
+        )),
+    }
+}
+
+/// Named set of processing parameters, e.g. a forewing close-up or a whole card overview.
+/// Keys missing from the preset keep the value of the other settings.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Preset {
+    pub name: String,
+    /// Shown next to the name in the GUI and in `--preset` help
+    pub description: Option<String>,
+    pub out_x: Option<i32>,
+    pub out_y: Option<i32>,
+    /// Output scale, sets the output dimensions from the card reference rectangle and the zoom.
+    /// Takes precedence over `out_x` and `out_y`.
+    pub px_per_mm: Option<f64>,
+    pub zoom: Option<f32>,
+    pub crop_window: Option<Rect>,
+    pub interpolation: Option<Interpolation>,
+    /// Image extension, or 'input' to keep the format of the input
+    pub format: Option<String>,
+    /// Used by both the CLI and the GUI
+    pub filename_template: Option<OutputTemplate>,
+}
+
+impl Preset {
+    /// Presets of every `[preset.<name>]` section, sorted by name
+    pub fn from_config(config: &Ini) -> Vec<Preset> {
+        let mut presets: Vec<Preset> = config
+            .sections()
+            .iter()
+            .filter_map(|section| section.strip_prefix(PRESET_SECTION_PREFIX))
+            .filter(|name| !name.is_empty())
+            .map(|name| Preset::from_section(config, name))
+            .collect();
+        presets.sort_by(|a, b| a.name.cmp(&b.name));
+        presets
+    }
+
+    fn from_section(config: &Ini, name: &str) -> Preset {
+        let section = format!("{PRESET_SECTION_PREFIX}{name}");
+        let get_int = |key: &str| {
+            config
+                .getint(&section, key)
+                .unwrap_or(None)
+                .map(|value| value as i32)
+        };
+        let get_float = |key: &str| config.getfloat(&section, key).unwrap_or(None);
+        Preset {
+            name: name.to_string(),
+            description: config
+                .get(&section, "description")
+                .filter(|description| !description.is_empty()),
+            out_x: get_int("out_x"),
+            out_y: get_int("out_y"),
+            px_per_mm: get_float("px_per_mm"),
+            zoom: get_float("zoom").map(|zoom| zoom as f32),
+            crop_window: config
+                .get(&section, "crop_window")
+                .and_then(|crop_window| parse_crop_window(&crop_window).ok()),
+            interpolation: config
+                .get(&section, "interpolation")
+                .and_then(|interpolation| interpolation.parse().ok()),
+            format: config
+                .get(&section, "format")
+                .map(|format| format.trim().trim_start_matches('.').to_lowercase())
+                .filter(|format| !format.is_empty()),
+            filename_template: config
+                .get(&section, "filename_template")
+                .and_then(|template| template.parse().ok()),
+        }
+    }
+
+    /// Name and description, e.g. 'forewing (forewing close-up)'
+    pub fn label(&self) -> String {
+        match self.description.as_ref() {
+            Some(description) => format!("{} ({})", self.name, description),
+            None => self.name.clone(),
+        }
+    }
+
+    /// Overrides the settings with the keys set in the preset
+    pub fn apply(&self, settings: &mut Settings) {
+        if let Some(zoom) = self.zoom {
+            settings.zoom = zoom;
+        }
+        if let Some(out_x) = self.out_x {
+            settings.out_size.width = out_x;
+            settings.px_per_mm = None;
+        }
+        if let Some(out_y) = self.out_y {
+            settings.out_size.height = out_y;
+            settings.px_per_mm = None;
+        }
+        if let Some(px_per_mm) = self.px_per_mm {
+            // Kept in the settings so that a later zoom override recomputes `out_size`
+            settings.px_per_mm = Some(px_per_mm);
+            settings.out_size = settings.out_size_for_zoom(settings.zoom);
+        }
+        if let Some(crop_window) = self.crop_window {
+            settings.crop_window = Some(crop_window);
+        }
+        if let Some(interpolation) = self.interpolation {
+            settings.interpolation = interpolation;
+        }
+        if let Some(format) = self.format.as_ref() {
+            settings.encoding.format = match format.as_str() {
+                "input" => None,
+                format => Some(format.to_string()),
+            };
+        }
+        if let Some(template) = self.filename_template.as_ref() {
+            settings.cli_filename_template = template.clone();
+            settings.gui_filename_template = template.clone();
+        }
+    }
+}
@@ -0,0 +1,428 @@
+//! Python extension module `idmybee`, built with maturin (`maturin develop --release`):
+//!
+//! ```python
+//! import idmybee
+//! cropper = idmybee.Cropper(preset="forewing", zoom=1.4)
+//! result = cropper.crop(image)  # NumPy array, e.g. from cv2.imread
+//! result.image, result.homography, result.px_per_mm, result.marker_corners
+//! ```
+use numpy::{Element, PyArray, PyArray2, PyReadonlyArrayDyn};
+use opencv::{
+    core::{DataType, Mat, CV_16U, CV_32F, CV_8U},
+    imgproc,
+    objdetect::ArucoDetector,
+    prelude::*,
+};
+use pyo3::{
+    create_exception, exceptions::PyException, exceptions::PyTypeError, prelude::*, types::PyDict,
+};
+use std::{path::PathBuf, time::Instant};
+
+use crate::crop::{crop_image, CropParameters};
+use crate::image_io::read_image;
+use crate::marker_utils::marker_processing::*;
+use crate::pipeline_error::{error_kind, kind_error, ErrorKind, WithErrorKind};
+use crate::settings::Settings;
+
+create_exception!(
+    idmybee,
+    IdMyBeeError,
+    PyException,
+    "Error of the crop engine"
+);
+create_exception!(
+    idmybee,
+    InvalidInputError,
+    IdMyBeeError,
+    "Wrong arguments, settings or image shape"
+);
+create_exception!(
+    idmybee,
+    ReadError,
+    IdMyBeeError,
+    "Image file missing or not decodable"
+);
+create_exception!(
+    idmybee,
+    MarkersNotFoundError,
+    IdMyBeeError,
+    "Less than the 4 markers needed for the correction"
+);
+
+fn py_err(err: impl Into<anyhow::Error>) -> PyErr {
+    let err = err.into();
+    let message = err.to_string();
+    match error_kind(&err) {
+        ErrorKind::InvalidInput => InvalidInputError::new_err(message),
+        ErrorKind::Read => ReadError::new_err(message),
+        ErrorKind::MarkersNotFound => MarkersNotFoundError::new_err(message),
+        _ => IdMyBeeError::new_err(message),
+    }
+}
+
+/// Copies a NumPy image into a Mat with 3 channels, gray images are expanded and the alpha
+/// channel is dropped. Channels keep their order.
+fn array_to_mat(image: &PyAny) -> PyResult<Mat> {
+    if let Ok(array) = image.extract::<PyReadonlyArrayDyn<u8>>() {
+        return typed_array_to_mat(&array).map_err(py_err);
+    }
+    if let Ok(array) = image.extract::<PyReadonlyArrayDyn<u16>>() {
+        return typed_array_to_mat(&array).map_err(py_err);
+    }
+    if let Ok(array) = image.extract::<PyReadonlyArrayDyn<f32>>() {
+        return typed_array_to_mat(&array).map_err(py_err);
+    }
+    Err(PyTypeError::new_err(
+        "Expected a NumPy array of uint8, uint16 or float32",
+    ))
+}
+
+fn typed_array_to_mat<T: Element + DataType + Clone>(
+    array: &PyReadonlyArrayDyn<T>,
+) -> anyhow::Result<Mat> {
+    let array = array.as_array();
+    let (rows, channels) = match array.shape() {
+        [rows, cols] if rows * cols > 0 => (*rows, 1),
+        [rows, cols, channels @ (1 | 3 | 4)] if rows * cols > 0 => (*rows, *channels),
+        shape => {
+            return Err(kind_error(
+                ErrorKind::InvalidInput,
+                format!("Expected a non empty image of shape (height, width) or (height, width, 1, 3 or 4 channels), got {shape:?}"),
+            ))
+        }
+    };
+    // Iterates in logical order, whatever the strides of the array
+    let data: Vec<T> = array.iter().cloned().collect();
+    let img = Mat::from_slice(&data)?
+        .reshape(channels as i32, rows as i32)?
+        .try_clone()?;
+    three_channels(img)
+}
+
+/// Expands gray images and drops the alpha channel, like the inputs of `crop`
+fn three_channels(img: Mat) -> anyhow::Result<Mat> {
+    let mut bgr = Mat::default();
+    match img.channels() {
+        1 => imgproc::cvt_color(&img, &mut bgr, imgproc::COLOR_GRAY2BGR, 0)?,
+        4 => imgproc::cvt_color(&img, &mut bgr, imgproc::COLOR_BGRA2BGR, 0)?,
+        _ => return Ok(img),
+    }
+    Ok(bgr)
+}
+
+/// NumPy array of shape (height, width, channels) with the dtype of the Mat depth
+fn mat_to_array(py: Python<'_>, mat: &Mat) -> PyResult<PyObject> {
+    match mat.depth() {
+        CV_8U => typed_mat_to_array::<u8>(py, mat),
+        CV_16U => typed_mat_to_array::<u16>(py, mat),
+        CV_32F => typed_mat_to_array::<f32>(py, mat),
+        depth => Err(IdMyBeeError::new_err(format!(
+            "Images of OpenCV depth {depth} cannot be converted to NumPy"
+        ))),
+    }
+}
+
+fn typed_mat_to_array<T: Element + DataType + Copy>(
+    py: Python<'_>,
+    mat: &Mat,
+) -> PyResult<PyObject> {
+    let shape = vec![
+        mat.rows() as usize,
+        mat.cols() as usize,
+        mat.channels() as usize,
+    ];
+    // `reshape` needs a continuous Mat, crops are copied out of the warped image
+    let single_channel = match mat.is_continuous() {
+        true => mat.reshape(1, 0),
+        false => mat.try_clone().and_then(|mat| mat.reshape(1, 0)),
+    }
+    .map_err(py_err)?;
+    let data = single_channel.data_typed::<T>().map_err(py_err)?.to_vec();
+    Ok(PyArray::from_vec(py, data).reshape(shape)?.to_object(py))
+}
+
+/// Keyword value as written in the config files, e.g. (0, 0, 600, 400) as '0 0 600 400'
+fn parameter_string(value: &PyAny) -> PyResult<String> {
+    // `str` is not extracted as a sequence
+    if let Ok(values) = value.extract::<Vec<&PyAny>>() {
+        let values = values
+            .iter()
+            .map(|value| Ok(value.str()?.to_string()))
+            .collect::<PyResult<Vec<String>>>()?;
+        return Ok(values.join(" "));
+    }
+    Ok(value.str()?.to_string())
+}
+
+/// Markers found in an image, corners in pixels of the image
+#[pyclass(get_all, module = "idmybee")]
+pub struct Markers {
+    marker_ids: Vec<i32>,
+    marker_corners: Vec<[[f32; 2]; 4]>,
+    rejected_count: usize,
+    /// Shortest side over longest side of the least square marker, `None` without markers
+    confidence: Option<f64>,
+}
+
+#[pymethods]
+impl Markers {
+    fn __repr__(&self) -> String {
+        format!(
+            "Markers(marker_ids={:?}, rejected_count={})",
+            self.marker_ids, self.rejected_count
+        )
+    }
+}
+
+/// Crop of an image and how it was made, the same fields as a 'crop --format json' record.
+/// Marker corners and source points are in pixels of the input image.
+#[pyclass(get_all, module = "idmybee")]
+pub struct CropResult {
+    /// NumPy array (height, width, 3), channels in the order of the input
+    image: PyObject,
+    marker_ids: Vec<i32>,
+    marker_corners: Vec<[[f32; 2]; 4]>,
+    rejected_count: usize,
+    confidence: Option<f64>,
+    /// Specimen ID of the QR code on the card, `None` when there is none
+    card_id: Option<String>,
+    /// Outer corners of markers #0 to #3
+    source_points: [[f32; 2]; 4],
+    /// 3 x 3 NumPy array, perspective transform from the working image to the corrected image
+    homography: PyObject,
+    /// Scale from the input image to the working image
+    working_scale: (f64, f64),
+    input_size: (i32, i32),
+    out_size: (i32, i32),
+    zoom: f32,
+    /// x, y, width, height in pixels of the corrected image
+    crop_window: (i32, i32, i32, i32),
+    interpolation: String,
+    px_per_mm: (f64, f64),
+    /// Variance of the Laplacian of the crop
+    sharpness: f64,
+    duration_ms: u64,
+}
+
+#[pymethods]
+impl CropResult {
+    fn __repr__(&self) -> String {
+        format!(
+            "CropResult(out_size={:?}, zoom={}, px_per_mm=({:.3}, {:.3}), marker_ids={:?})",
+            self.out_size, self.zoom, self.px_per_mm.0, self.px_per_mm.1, self.marker_ids
+        )
+    }
+}
+
+/// Crop engine with the settings of the config files, like idmybee_cli. The marker detector is
+/// created once, keep the cropper to process several images.
+///
+/// `config` is read after the other config files (like --config), `preset` names a
+/// `[preset.<name>]` section and the other keyword arguments override single settings: out_x,
+/// out_y, zoom, crop_window (x, y, width, height) and interpolation.
+#[pyclass(unsendable, module = "idmybee")]
+pub struct Cropper {
+    settings: Settings,
+    detector: ArucoDetector,
+}
+
+impl Cropper {
+    fn crop_mat(&self, py: Python<'_>, img: Mat) -> PyResult<CropResult> {
+        let start = Instant::now();
+        let settings = &self.settings;
+        let input_size = img.size().map_err(py_err)?;
+        let parameters = CropParameters {
+            out_size: settings.out_size,
+            zoom: settings.zoom,
+            crop_window: settings.crop_window,
+            interpolation: settings.interpolation,
+        };
+        let result = crop_image(&self.detector, img, &parameters).map_err(py_err)?;
+
+        let homography: Vec<Vec<f64>> = matrix_to_array(&result.homography)
+            .map_err(py_err)?
+            .iter()
+            .map(|row| row.to_vec())
+            .collect();
+        let crop_window = result.crop_window;
+        Ok(CropResult {
+            image: mat_to_array(py, &result.image)?,
+            confidence: markers_confidence(&result.marker_corners),
+            sharpness: sharpness(&result.image).map_err(py_err)?,
+            marker_ids: result.marker_ids,
+            marker_corners: result.marker_corners,
+            rejected_count: result.rejected_count,
+            card_id: result.card_id,
+            source_points: result.source_points,
+            homography: PyArray2::from_vec2(py, &homography)?.to_object(py),
+            working_scale: result.working_scale,
+            input_size: (input_size.width, input_size.height),
+            out_size: (settings.out_size.width, settings.out_size.height),
+            zoom: settings.zoom,
+            crop_window: (
+                crop_window.x,
+                crop_window.y,
+                crop_window.width,
+                crop_window.height,
+            ),
+            interpolation: settings.interpolation.to_string(),
+            px_per_mm: settings
+                .card_layout
+                .px_per_mm(&settings.out_size, settings.zoom),
+            duration_ms: start.elapsed().as_millis() as u64,
+        })
+    }
+}
+
+#[pymethods]
+impl Cropper {
+    #[new]
+    #[pyo3(signature = (config=None, preset=None, **parameters))]
+    fn new(
+        config: Option<PathBuf>,
+        preset: Option<&str>,
+        parameters: Option<&PyDict>,
+    ) -> PyResult<Self> {
+        let mut settings = Settings::load(config.as_deref())
+            .with_kind(ErrorKind::InvalidInput)
+            .map_err(py_err)?;
+        settings.log_issues();
+        if let Some(preset) = preset {
+            settings = settings
+                .with_preset(preset)
+                .with_kind(ErrorKind::InvalidInput)
+                .map_err(py_err)?;
+        }
+        for (key, value) in parameters.into_iter().flatten() {
+            settings
+                .set_parameter(key.extract::<&str>()?, &parameter_string(value)?)
+                .with_kind(ErrorKind::InvalidInput)
+                .map_err(py_err)?;
+        }
+        Ok(Cropper {
+            settings,
+            detector: new_marker_detector().map_err(py_err)?,
+        })
+    }
+
+    /// Detects the markers of an image (NumPy array) without requiring the 4 reference markers
+    fn detect(&self, image: &PyAny) -> PyResult<Markers> {
+        let img = array_to_mat(image)?;
+        let (markers_coor, markers_id, rejected_markers) =
+            detect_markers(&self.detector, &img).map_err(py_err)?;
+        let marker_corners = markers_to_arrays(&markers_coor, (1., 1.));
+        Ok(Markers {
+            marker_ids: markers_id.to_vec(),
+            confidence: markers_confidence(&marker_corners),
+            marker_corners,
+            rejected_count: rejected_markers.len(),
+        })
+    }
+
+    /// Corrects the perspective of an image (NumPy array) and crops it, raises
+    /// `MarkersNotFoundError` when markers #0 to #3 are not all found
+    fn crop(&self, py: Python<'_>, image: &PyAny) -> PyResult<CropResult> {
+        let img = array_to_mat(image)?;
+        self.crop_mat(py, img)
+    }
+
+    /// Same as `crop` for an image file, read like idmybee_cli does (EXIF orientation applied).
+    /// The crop has 3 channels in BGR order, gray files are expanded and the alpha channel is
+    /// dropped.
+    fn crop_file(&self, py: Python<'_>, path: PathBuf) -> PyResult<CropResult> {
+        let (img, _) = read_image(&path)
+            .with_kind(ErrorKind::Read)
+            .map_err(py_err)?;
+        self.crop_mat(py, three_channels(img).map_err(py_err)?)
+    }
+
+    #[getter]
+    fn out_size(&self) -> (i32, i32) {
+        (self.settings.out_size.width, self.settings.out_size.height)
+    }
+
+    #[getter]
+    fn zoom(&self) -> f32 {
+        self.settings.zoom
+    }
+
+    #[getter]
+    fn crop_window(&self) -> (i32, i32, i32, i32) {
+        let crop_window = self
+            .settings
+            .crop_window
+            .unwrap_or(get_crop_window(&self.settings.out_size));
+        (
+            crop_window.x,
+            crop_window.y,
+            crop_window.width,
+            crop_window.height,
+        )
+    }
+
+    #[getter]
+    fn interpolation(&self) -> String {
+        self.settings.interpolation.to_string()
+    }
+
+    #[getter]
+    fn px_per_mm(&self) -> (f64, f64) {
+        self.settings
+            .card_layout
+            .px_per_mm(&self.settings.out_size, self.settings.zoom)
+    }
+
+    /// Names of the presets of the config files
+    #[getter]
+    fn presets(&self) -> Vec<String> {
+        self.settings
+            .presets
+            .iter()
+            .map(|preset| preset.name.clone())
+            .collect()
+    }
+
+    /// Config files read, lowest priority first
+    #[getter]
+    fn sources(&self) -> Vec<PathBuf> {
+        self.settings.sources.clone()
+    }
+
+    fn __repr__(&self) -> String {
+        let (width, height) = self.out_size();
+        format!(
+            "Cropper(out_size=({width}, {height}), zoom={}, interpolation={:?})",
+            self.settings.zoom,
+            self.interpolation()
+        )
+    }
+}
+
+/// Reads an image file like idmybee_cli does: EXIF orientation applied, channels in BGR(A) order.
+/// The array has the channels (1, 3 or 4) and the depth (uint8 or uint16) of the file.
+#[pyfunction]
+#[pyo3(name = "read_image")]
+fn read_image_file(py: Python<'_>, path: PathBuf) -> PyResult<PyObject> {
+    let (img, _) = read_image(&path)
+        .with_kind(ErrorKind::Read)
+        .map_err(py_err)?;
+    mat_to_array(py, &img)
+}
+
+/// Marker detection, perspective correction and cropping of the ID My Bee photos
+#[pymodule]
+fn idmybee(py: Python<'_>, module: &PyModule) -> PyResult<()> {
+    module.add("__version__", env!("CARGO_PKG_VERSION"))?;
+    module.add_class::<Cropper>()?;
+    module.add_class::<CropResult>()?;
+    module.add_class::<Markers>()?;
+    module.add_function(wrap_pyfunction!(read_image_file, module)?)?;
+    module.add("IdMyBeeError", py.get_type::<IdMyBeeError>())?;
+    module.add("InvalidInputError", py.get_type::<InvalidInputError>())?;
+    module.add("ReadError", py.get_type::<ReadError>())?;
+    module.add(
+        "MarkersNotFoundError",
+        py.get_type::<MarkersNotFoundError>(),
+    )?;
+    Ok(())
+}
@@ -0,0 +1,84 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, path::Path};
+
+use idmybee::marker_utils::marker_processing::markers_confidence;
+use idmybee::pipeline_error::{error_kind, ErrorKind};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordStatus {
+    #[default]
+    Processed,
+    Skipped,
+    Failed,
+}
+
+/// Outcome of one input, emitted by `--format json` and used by the batch reports
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ImageRecord {
+    pub input: String,
+    pub status: RecordStatus,
+    pub outputs: Vec<String>,
+    pub marker_ids: Vec<i32>,
+    /// Corners of each detected marker in input image coordinates
+    pub marker_corners: Vec<[[f32; 2]; 4]>,
+    /// Horizontal and vertical scale of each output, in the order of `outputs`
+    pub px_per_mm: Vec<[f64; 2]>,
+    /// Variance of the Laplacian of the first output
+    pub sharpness: Option<f64>,
+    /// Regularity of the detected markers, see `markers_confidence`
+    pub confidence: Option<f64>,
+    /// Specimen ID of the QR code on the card
+    pub card_id: Option<String>,
+    pub duration_ms: u64,
+    pub error_kind: Option<ErrorKind>,
+    pub error_message: Option<String>,
+}
+
+impl ImageRecord {
+    pub fn new(input_path: &Path) -> Self {
+        ImageRecord {
+            input: input_path.display().to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn skipped(input_path: &Path) -> Self {
+        ImageRecord {
+            status: RecordStatus::Skipped,
+            ..ImageRecord::new(input_path)
+        }
+    }
+
+    pub fn failed(input_path: &Path, err: &anyhow::Error) -> Self {
+        ImageRecord {
+            status: RecordStatus::Failed,
+            error_kind: Some(error_kind(err)),
+            error_message: Some(err.to_string()),
+            ..ImageRecord::new(input_path)
+        }
+    }
+
+    /// Sets the detected markers and the detection confidence derived from them
+    pub fn set_markers(&mut self, marker_ids: Vec<i32>, marker_corners: Vec<[[f32; 2]; 4]>) {
+        self.confidence = markers_confidence(&marker_corners);
+        self.marker_ids = marker_ids;
+        self.marker_corners = marker_corners;
+    }
+}
+
+/// Inputs of each card ID read on more than one image, e.g. a card photographed twice or a
+/// wrong label
+pub fn duplicate_card_ids(records: &[ImageRecord]) -> BTreeMap<String, Vec<String>> {
+    let mut inputs: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for record in records.iter() {
+        if let Some(card_id) = record.card_id.as_ref() {
+            inputs
+                .entry(card_id.clone())
+                .or_default()
+                .push(record.input.clone());
+        }
+    }
+    inputs.retain(|_, inputs| inputs.len() > 1);
+    inputs
+}
@@ -0,0 +1,354 @@
+//! Perspective correction in pure Rust (nalgebra and image), without OpenCV. It gives the same
+//! geometry as `get_correction_matrix` and `warp_image` from corner points that were detected,
+//! stored in a sidecar or picked by hand.
+
+use anyhow::{anyhow, Result};
+use image::{DynamicImage, ImageBuffer, Pixel, Primitive};
+use nalgebra::{Matrix3, SMatrix, SVector, Vector3};
+use num_traits::NumCast;
+
+/// Image of the `image` crate with pixels `P`
+pub type Buffer<P> = ImageBuffer<P, Vec<<P as Pixel>::Subpixel>>;
+
+/// Resampling of the warp, the kernels are the ones of OpenCV
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Sampling {
+    Nearest,
+    Bilinear,
+    /// Keys kernel with a = -0.75, like `INTER_CUBIC`
+    Bicubic,
+    /// 8 x 8 Lanczos kernel, like `INTER_LANCZOS4`
+    #[default]
+    Lanczos,
+}
+
+impl Sampling {
+    /// Half width of the kernel in pixels
+    fn radius(&self) -> i64 {
+        match self {
+            Sampling::Nearest | Sampling::Bilinear => 1,
+            Sampling::Bicubic => 2,
+            Sampling::Lanczos => 4,
+        }
+    }
+
+    fn weight(&self, t: f64) -> f64 {
+        let t = t.abs();
+        match self {
+            Sampling::Nearest => (t < 0.5) as u8 as f64,
+            Sampling::Bilinear => (1. - t).max(0.),
+            Sampling::Bicubic => {
+                const A: f64 = -0.75;
+                if t <= 1. {
+                    ((A + 2.) * t - (A + 3.)) * t * t + 1.
+                } else if t < 2. {
+                    ((A * t - 5. * A) * t + 8. * A) * t - 4. * A
+                } else {
+                    0.
+                }
+            }
+            Sampling::Lanczos => {
+                if t < f64::EPSILON {
+                    1.
+                } else if t < 4. {
+                    let x = std::f64::consts::PI * t;
+                    4. * x.sin() * (x / 4.).sin() / (x * x)
+                } else {
+                    0.
+                }
+            }
+        }
+    }
+}
+
+/// Perspective transform mapping the 4 `source` points to the 4 `target` points, normalized so
+/// that its last coefficient is 1 like `imgproc::get_perspective_transform`
+pub fn perspective_transform(
+    source: &[[f64; 2]; 4],
+    target: &[[f64; 2]; 4],
+) -> Result<Matrix3<f64>> {
+    let mut a = SMatrix::<f64, 8, 8>::zeros();
+    let mut b = SVector::<f64, 8>::zeros();
+    for (i, (&[x, y], &[u, v])) in source.iter().zip(target.iter()).enumerate() {
+        let rows = [
+            [x, y, 1., 0., 0., 0., -x * u, -y * u],
+            [0., 0., 0., x, y, 1., -x * v, -y * v],
+        ];
+        for (j, (top, bottom)) in rows[0].iter().zip(rows[1].iter()).enumerate() {
+            a[(2 * i, j)] = *top;
+            a[(2 * i + 1, j)] = *bottom;
+        }
+        b[2 * i] = u;
+        b[2 * i + 1] = v;
+    }
+    let h = a.lu().solve(&b).ok_or_else(|| {
+        anyhow!("No perspective transform maps the points {source:?}, 3 of them are aligned")
+    })?;
+    Ok(Matrix3::new(
+        h[0], h[1], h[2], h[3], h[4], h[5], h[6], h[7], 1.,
+    ))
+}
+
+/// Same transform as `get_correction_matrix`: the outer corners of markers #0 to #3 go to the
+/// corners of the output, widened by `zoom`
+pub fn correction_transform(
+    source_points: &[[f32; 2]; 4],
+    out_size: (u32, u32),
+    zoom: f32,
+) -> Result<Matrix3<f64>> {
+    let (w, h, zoom) = (out_size.0 as f64, out_size.1 as f64, zoom as f64);
+    let top_y = -0.5 * h * (zoom - 1.);
+    let bot_y = h + 0.5 * h * (zoom - 1.);
+    let right_x = w * zoom;
+    let source = source_points.map(|[x, y]| [x as f64, y as f64]);
+    perspective_transform(
+        &source,
+        &[[0., top_y], [right_x, top_y], [right_x, bot_y], [0., bot_y]],
+    )
+}
+
+/// Pixels `origin` to `origin + size` of the corrected image: each one is mapped back to `img`
+/// with the inverse of `transform` and resampled. Outside of `img` is black, like `BORDER_CONSTANT`.
+pub fn warp_perspective<P: Pixel>(
+    img: &Buffer<P>,
+    transform: &Matrix3<f64>,
+    origin: (i32, i32),
+    size: (u32, u32),
+    sampling: Sampling,
+) -> Result<Buffer<P>> {
+    let inverse = transform
+        .try_inverse()
+        .ok_or_else(|| anyhow!("The perspective transform is not invertible"))?;
+    let max_value: f64 = NumCast::from(P::Subpixel::DEFAULT_MAX_VALUE).unwrap_or(1.);
+    // Float images (maximum of 1) are not rounded
+    let integer = max_value > 1.;
+    let mut out = Buffer::<P>::new(size.0, size.1);
+    let mut values = vec![0f64; P::CHANNEL_COUNT as usize];
+    for (x, y, pixel) in out.enumerate_pixels_mut() {
+        // Pixel centers at integer coordinates, as in OpenCV
+        let target = Vector3::new(x as f64 + origin.0 as f64, y as f64 + origin.1 as f64, 1.);
+        let source = inverse * target;
+        if source.z.abs() < f64::EPSILON {
+            continue;
+        }
+        sample(
+            img,
+            source.x / source.z,
+            source.y / source.z,
+            sampling,
+            &mut values,
+        );
+        for (channel, value) in pixel.channels_mut().iter_mut().zip(values.iter()) {
+            let value = value.clamp(0., max_value);
+            let value = if integer { value.round() } else { value };
+            *channel = NumCast::from(value).unwrap_or(P::Subpixel::DEFAULT_MIN_VALUE);
+        }
+    }
+    Ok(out)
+}
+
+/// Weighted sum of the pixels around (`x`, `y`) in `values`
+fn sample<P: Pixel>(img: &Buffer<P>, x: f64, y: f64, sampling: Sampling, values: &mut [f64]) {
+    values.fill(0.);
+    if sampling == Sampling::Nearest {
+        add_pixel(img, x.round() as i64, y.round() as i64, 1., values);
+        return;
+    }
+    let radius = sampling.radius();
+    let (x0, y0) = (x.floor() as i64, y.floor() as i64);
+    let taps = || (1 - radius)..=radius;
+    let weights_x: Vec<f64> = taps()
+        .map(|i| sampling.weight(x - (x0 + i) as f64))
+        .collect();
+    let weights_y: Vec<f64> = taps()
+        .map(|j| sampling.weight(y - (y0 + j) as f64))
+        .collect();
+    for (j, weight_y) in taps().zip(weights_y.iter()) {
+        for (i, weight_x) in taps().zip(weights_x.iter()) {
+            add_pixel(img, x0 + i, y0 + j, weight_x * weight_y, values);
+        }
+    }
+    // The Lanczos weights do not sum exactly to 1, the black border counts in the total
+    let total: f64 = weights_x.iter().sum::<f64>() * weights_y.iter().sum::<f64>();
+    if total.abs() > f64::EPSILON {
+        values.iter_mut().for_each(|value| *value /= total);
+    }
+}
+
+fn add_pixel<P: Pixel>(img: &Buffer<P>, x: i64, y: i64, weight: f64, values: &mut [f64]) {
+    if x < 0 || y < 0 || x >= img.width() as i64 || y >= img.height() as i64 || weight == 0. {
+        return;
+    }
+    let pixel = img.get_pixel(x as u32, y as u32);
+    for (value, channel) in values.iter_mut().zip(pixel.channels()) {
+        let channel: f64 = NumCast::from(*channel).unwrap_or(0.);
+        *value += weight * channel;
+    }
+}
+
+/// Crop of `img` from the outer corners of markers #0 to #3 in pixels of `img`. `crop_window`
+/// is (x, y, width, height) in the corrected image, the whole `out_size` when `None`. Returns
+/// the crop and the transform from `img` to the corrected image.
+pub fn rectify<P: Pixel>(
+    img: &Buffer<P>,
+    source_points: &[[f32; 2]; 4],
+    out_size: (u32, u32),
+    zoom: f32,
+    crop_window: Option<(i32, i32, u32, u32)>,
+    sampling: Sampling,
+) -> Result<(Buffer<P>, Matrix3<f64>)> {
+    let transform = correction_transform(source_points, out_size, zoom)?;
+    let (x, y, width, height) = crop_window.unwrap_or((0, 0, out_size.0, out_size.1));
+    let image = warp_perspective(img, &transform, (x, y), (width, height), sampling)?;
+    Ok((image, transform))
+}
+
+/// `rectify` for the images of `image::open`, e.g. to crop from manual points without OpenCV
+pub fn rectify_dynamic(
+    img: &DynamicImage,
+    source_points: &[[f32; 2]; 4],
+    out_size: (u32, u32),
+    zoom: f32,
+    crop_window: Option<(i32, i32, u32, u32)>,
+    sampling: Sampling,
+) -> Result<(DynamicImage, Matrix3<f64>)> {
+    macro_rules! rectify_as {
+        ($buffer:expr, $variant:path) => {{
+            let (image, transform) = rectify(
+                $buffer,
+                source_points,
+                out_size,
+                zoom,
+                crop_window,
+                sampling,
+            )?;
+            ($variant(image), transform)
+        }};
+    }
+    Ok(match img {
+        DynamicImage::ImageLuma8(buffer) => rectify_as!(buffer, DynamicImage::ImageLuma8),
+        DynamicImage::ImageLumaA8(buffer) => rectify_as!(buffer, DynamicImage::ImageLumaA8),
+        DynamicImage::ImageRgb8(buffer) => rectify_as!(buffer, DynamicImage::ImageRgb8),
+        DynamicImage::ImageRgba8(buffer) => rectify_as!(buffer, DynamicImage::ImageRgba8),
+        DynamicImage::ImageLuma16(buffer) => rectify_as!(buffer, DynamicImage::ImageLuma16),
+        DynamicImage::ImageLumaA16(buffer) => rectify_as!(buffer, DynamicImage::ImageLumaA16),
+        DynamicImage::ImageRgb16(buffer) => rectify_as!(buffer, DynamicImage::ImageRgb16),
+        DynamicImage::ImageRgba16(buffer) => rectify_as!(buffer, DynamicImage::ImageRgba16),
+        DynamicImage::ImageRgb32F(buffer) => rectify_as!(buffer, DynamicImage::ImageRgb32F),
+        DynamicImage::ImageRgba32F(buffer) => rectify_as!(buffer, DynamicImage::ImageRgba32F),
+        _ => return Err(anyhow!("Unsupported pixel format {:?}", img.color())),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayImage, Luma, Rgb, RgbImage};
+
+    /// Mild perspective with rotation, scale and translation
+    fn known_transform() -> Matrix3<f64> {
+        Matrix3::new(1.2, 0.15, 12., -0.1, 0.9, 30., 2e-4, -1e-4, 1.)
+    }
+
+    fn apply(transform: &Matrix3<f64>, [x, y]: [f64; 2]) -> [f64; 2] {
+        let point = transform * Vector3::new(x, y, 1.);
+        [point.x / point.z, point.y / point.z]
+    }
+
+    #[test]
+    fn perspective_transform_recovers_known_matrix() {
+        let transform = known_transform();
+        let source = [[10., 20.], [400., 35.], [380., 290.], [25., 310.]];
+        let target = source.map(|point| apply(&transform, point));
+        let solved = perspective_transform(&source, &target).unwrap();
+        assert!(
+            (solved - transform).abs().max() < 1e-9,
+            "{solved} != {transform}"
+        );
+    }
+
+    #[test]
+    fn perspective_transform_rejects_aligned_points() {
+        let source = [[0., 0.], [10., 10.], [20., 20.], [0., 10.]];
+        let target = [[0., 0.], [1., 0.], [1., 1.], [0., 1.]];
+        assert!(perspective_transform(&source, &target).is_err());
+    }
+
+    #[test]
+    fn correction_transform_maps_points_to_zoomed_corners() {
+        let source_points = [[102.5, 80.], [913., 95.5], [890., 701.], [90., 688.]];
+        let (out_size, zoom) = ((600, 400), 1.5);
+        let transform = correction_transform(&source_points, out_size, zoom).unwrap();
+        let expected = [[0., -100.], [900., -100.], [900., 500.], [0., 500.]];
+        for (point, expected) in source_points.iter().zip(expected.iter()) {
+            let [x, y] = apply(&transform, [point[0] as f64, point[1] as f64]);
+            assert!(
+                (x - expected[0]).abs() < 1e-6 && (y - expected[1]).abs() < 1e-6,
+                "{x} {y} != {expected:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn warp_matches_known_transform() {
+        // Bilinear sampling of a linear gradient is exact away from the borders
+        let gradient = |x: f64, y: f64| x * 0.4 + y * 0.3 + 10.;
+        let img = GrayImage::from_fn(400, 400, |x, y| {
+            Luma([gradient(x as f64, y as f64).round() as u8])
+        });
+        let transform = known_transform();
+        let inverse = transform.try_inverse().unwrap();
+        let warped =
+            warp_perspective(&img, &transform, (0, 0), (400, 400), Sampling::Bilinear).unwrap();
+        let mut checked = 0;
+        for (x, y, pixel) in warped.enumerate_pixels() {
+            let [source_x, source_y] = apply(&inverse, [x as f64, y as f64]);
+            if !(2. ..398.).contains(&source_x) || !(2. ..398.).contains(&source_y) {
+                continue;
+            }
+            let expected = gradient(source_x, source_y);
+            assert!(
+                (pixel[0] as f64 - expected).abs() <= 1.5,
+                "({x}, {y}): {} != {expected}",
+                pixel[0]
+            );
+            checked += 1;
+        }
+        assert!(checked > 10_000);
+    }
+
+    #[test]
+    fn rectify_straightens_a_quadrilateral() {
+        // White card on black, its corners are the source points
+        let source_points = [[60f32, 40.], [330., 70.], [310., 260.], [45., 230.]];
+        let corners = source_points.map(|[x, y]| [x as f64, y as f64]);
+        let unit = [[0., 0.], [1., 0.], [1., 1.], [0., 1.]];
+        let to_card = perspective_transform(&corners, &unit).unwrap();
+        let img = RgbImage::from_fn(400, 300, |x, y| {
+            let [u, v] = apply(&to_card, [x as f64, y as f64]);
+            match (0. ..=1.).contains(&u) && (0. ..=1.).contains(&v) {
+                true => Rgb([255, 255, 255]),
+                false => Rgb([0, 0, 0]),
+            }
+        });
+        let (out, _) = rectify_dynamic(
+            &DynamicImage::ImageRgb8(img),
+            &source_points,
+            (120, 80),
+            1.5,
+            None,
+            Sampling::Lanczos,
+        )
+        .unwrap();
+        let out = out.to_rgb8();
+        assert_eq!(out.dimensions(), (120, 80));
+        // The card spans x from 0 to 180 and y from -20 to 100 of the zoomed output
+        for (x, y) in [(5, 5), (60, 40), (110, 75)] {
+            assert!(
+                out.get_pixel(x, y)[0] > 240,
+                "({x}, {y}) is {:?}",
+                out.get_pixel(x, y)
+            );
+        }
+    }
+}
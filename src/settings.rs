@@ -0,0 +1,259 @@
+use anyhow::Result;
+use configparser::ini::Ini;
+use opencv::core::{Rect, Size};
+use std::path::{Path, PathBuf};
+
+use crate::card_layout::CardLayout;
+use crate::config_schema::{check_entry, load_validated, ConfigIssue, Severity};
+use crate::encoding::EncodingOptions;
+use crate::image_io::SourceMetadataPolicy;
+use crate::marker_utils::marker_processing::Interpolation;
+use crate::output_template::{OutputTemplate, DEFAULT_CLI_TEMPLATE, DEFAULT_GUI_TEMPLATE};
+use crate::presets::{parse_crop_window, Preset};
+
+/// Next to the executable, and in the `idmybee` folder of the user config directory
+pub const CONFIG_FILENAME: &str = "config.ini";
+/// Looked up in the current directory and its parents
+pub const PROJECT_CONFIG_FILENAME: &str = "idmybee.ini";
+
+/// Shown in the help of both binaries
+pub const SETTINGS_HELP: &str = "Settings are read from, by increasing priority: built-in defaults, 'config.ini' next to the executable, the user config ('$XDG_CONFIG_HOME/idmybee/config.ini', '%APPDATA%\\idmybee\\config.ini' on Windows), the first 'idmybee.ini' found in the current directory or its parents, the file given with --config, then the command line flags.";
+
+/// Settings shared by idmybee_cli and idmybee_gui, so that the same settings give the same
+/// crops in both tools. Each field documents its `section.key` and built-in default.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// `crop_parameters.out_x` and `crop_parameters.out_y`, 600 x 400 pixels
+    pub out_size: Size,
+    /// `crop_parameters.zoom`, 1.2
+    pub zoom: f32,
+    /// Scale of a preset with `px_per_mm`, `out_size` then follows the zoom. Cleared when the
+    /// output dimensions are set explicitly.
+    pub px_per_mm: Option<f64>,
+    /// `crop_parameters.crop_window`, the whole `out_size` from the origin when not set
+    pub crop_window: Option<Rect>,
+    /// `crop_parameters.interpolation`, lanczos4
+    pub interpolation: Interpolation,
+    /// `output.write_sidecar`, false
+    pub write_sidecar: bool,
+    /// `output.embed_metadata`, true
+    pub embed_metadata: bool,
+    /// `output.source_metadata`, capture
+    pub source_metadata: SourceMetadataPolicy,
+    /// `output.cli_filename_template`, `{stem}_preproc_z{zoom}.{ext}`
+    pub cli_filename_template: OutputTemplate,
+    /// `output.gui_filename_template`, `{stem}_crop.{ext}`
+    pub gui_filename_template: OutputTemplate,
+    /// `[encoding]` section
+    pub encoding: EncodingOptions,
+    /// `[card]` section
+    pub card_layout: CardLayout,
+    /// `[preset.<name>]` sections, sorted by name
+    pub presets: Vec<Preset>,
+    /// All the layers merged, for the settings of a single binary (e.g. the GUI shortcuts)
+    pub config: Ini,
+    /// Files read, lowest priority first
+    pub sources: Vec<PathBuf>,
+    /// Invalid values (replaced by their default) and unknown keys of the files read
+    pub issues: Vec<ConfigIssue>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings::from_config(Ini::new(), Vec::new())
+    }
+}
+
+impl Settings {
+    /// Config files that exist, lowest priority first, `extra_config` last
+    pub fn config_paths(extra_config: Option<&Path>) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        if let Ok(mut exe_dir) = std::env::current_exe() {
+            exe_dir.pop();
+            paths.push(exe_dir.join(CONFIG_FILENAME));
+        }
+        if let Some(user_dir) = dirs::config_dir() {
+            paths.push(user_dir.join("idmybee").join(CONFIG_FILENAME));
+        }
+        if let Ok(current_dir) = std::env::current_dir() {
+            if let Some(project_config) = current_dir
+                .ancestors()
+                .map(|dir| dir.join(PROJECT_CONFIG_FILENAME))
+                .find(|path| path.is_file())
+            {
+                paths.push(project_config);
+            }
+        }
+        paths.retain(|path| path.is_file());
+        // The same file may be found twice, e.g. when running from the executable folder
+        let mut seen = std::collections::HashSet::new();
+        paths.retain(|path| seen.insert(dunce::canonicalize(path).unwrap_or(path.clone())));
+
+        if let Some(extra_config) = extra_config {
+            paths.push(extra_config.to_path_buf());
+        }
+        paths
+    }
+
+    /// Reads every layer, a key of a file overrides the same key of the files read before.
+    /// Invalid entries are dropped so that they do not hide a valid value of a lower layer.
+    pub fn load(extra_config: Option<&Path>) -> Result<Self> {
+        let mut config = Ini::new();
+        let mut issues = Vec::new();
+        let sources = Settings::config_paths(extra_config);
+        for path in sources.iter() {
+            let (file_config, file_issues) = load_validated(path).map_err(|err| {
+                anyhow::anyhow!("Could not read the config file {:?}: {}", path, err)
+            })?;
+            for (section, entries) in file_config.get_map_ref() {
+                for (key, value) in entries {
+                    config.set(section, key, value.clone());
+                }
+            }
+            issues.extend(file_issues);
+        }
+        let mut settings = Settings::from_config(config, sources);
+        settings.issues = issues;
+        Ok(settings)
+    }
+
+    /// Logs the issues of the config files
+    pub fn log_issues(&self) {
+        for issue in self.issues.iter() {
+            match issue.severity {
+                Severity::Error => log::error!("{issue}"),
+                Severity::Warning => log::warn!("{issue}"),
+            }
+        }
+    }
+
+    pub fn from_config(config: Ini, sources: Vec<PathBuf>) -> Self {
+        let get_template = |key: &str, default: &str| {
+            config
+                .get("output", key)
+                .and_then(|template| match template.parse() {
+                    Ok(template) => Some(template),
+                    Err(err) => {
+                        log::warn!("Ignoring output.{key}: {err}");
+                        None
+                    }
+                })
+                .unwrap_or_else(|| default.parse().unwrap())
+        };
+
+        Settings {
+            out_size: Size::new(
+                config
+                    .getint("crop_parameters", "out_x")
+                    .unwrap_or(None)
+                    .unwrap_or(600) as i32,
+                config
+                    .getint("crop_parameters", "out_y")
+                    .unwrap_or(None)
+                    .unwrap_or(400) as i32,
+            ),
+            zoom: config
+                .getfloat("crop_parameters", "zoom")
+                .unwrap_or(None)
+                .unwrap_or(1.2) as f32,
+            px_per_mm: None,
+            crop_window: config
+                .get("crop_parameters", "crop_window")
+                .and_then(|crop_window| parse_crop_window(&crop_window).ok()),
+            interpolation: config
+                .get("crop_parameters", "interpolation")
+                .and_then(|interpolation| interpolation.parse().ok())
+                .unwrap_or_default(),
+            write_sidecar: config
+                .getbool("output", "write_sidecar")
+                .unwrap_or(None)
+                .unwrap_or(false),
+            embed_metadata: config
+                .getbool("output", "embed_metadata")
+                .unwrap_or(None)
+                .unwrap_or(true),
+            source_metadata: config
+                .get("output", "source_metadata")
+                .and_then(|policy| policy.parse().ok())
+                .unwrap_or_default(),
+            cli_filename_template: get_template("cli_filename_template", DEFAULT_CLI_TEMPLATE),
+            gui_filename_template: get_template("gui_filename_template", DEFAULT_GUI_TEMPLATE),
+            encoding: EncodingOptions::from_config(&config),
+            card_layout: CardLayout::from_config(&config),
+            presets: Preset::from_config(&config),
+            config,
+            sources,
+            issues: Vec::new(),
+        }
+    }
+
+    pub fn preset(&self, name: &str) -> Result<&Preset> {
+        self.presets
+            .iter()
+            // Section names are lowercased when the config files are read
+            .find(|preset| preset.name == name.to_lowercase())
+            .ok_or_else(|| match self.presets.is_empty() {
+                true => anyhow::anyhow!(
+                    "Unknown preset {name:?}, no preset is defined. Add a [preset.{name}] section to a config file."
+                ),
+                false => anyhow::anyhow!(
+                    "Unknown preset {name:?}, expected one of: {}",
+                    self.presets.iter().map(|preset| preset.name.as_str()).collect::<Vec<_>>().join(", ")
+                ),
+            })
+    }
+
+    /// Copy of the settings with a preset applied over them
+    pub fn with_preset(&self, name: &str) -> Result<Settings> {
+        let mut settings = self.clone();
+        self.preset(name)?.apply(&mut settings);
+        Ok(settings)
+    }
+
+    /// Output dimensions of a crop at `zoom`: the ones keeping the scale of a `px_per_mm` preset,
+    /// `out_size` otherwise
+    pub fn out_size_for_zoom(&self, zoom: f32) -> Size {
+        match self.px_per_mm {
+            Some(px_per_mm) => self.card_layout.out_size(px_per_mm, zoom),
+            None => self.out_size,
+        }
+    }
+
+    /// Overrides a crop or encoding setting by its key (e.g. 'zoom', 'jpeg_quality'), the value is
+    /// validated like in the config files
+    pub fn set_parameter(&mut self, key: &str, value: &str) -> Result<()> {
+        let section = match key {
+            "out_x" | "out_y" | "zoom" | "crop_window" | "interpolation" => "crop_parameters",
+            "format" | "jpeg_quality" | "jpeg_subsampling" | "png_compression"
+            | "tiff_compression" => "encoding",
+            _ => return Err(anyhow::anyhow!("Unknown parameter {key:?}")),
+        };
+        check_entry(section, key, value)
+            .map_err(|message| anyhow::anyhow!("Invalid parameter {key}: {message}"))?;
+        match key {
+            "out_x" => {
+                self.out_size.width = value.parse()?;
+                self.px_per_mm = None;
+            }
+            "out_y" => {
+                self.out_size.height = value.parse()?;
+                self.px_per_mm = None;
+            }
+            "zoom" => {
+                self.zoom = value.parse()?;
+                self.out_size = self.out_size_for_zoom(self.zoom);
+            }
+            "crop_window" => self.crop_window = Some(parse_crop_window(value)?),
+            "interpolation" => self.interpolation = value.parse()?,
+            "format" => {
+                self.encoding.format = Some(value.trim_start_matches('.').to_lowercase())
+                    .filter(|format| format != "input")
+            }
+            "jpeg_quality" => self.encoding.jpeg_quality = value.parse()?,
+            "jpeg_subsampling" => self.encoding.jpeg_subsampling = value.parse()?,
+            "png_compression" => self.encoding.png_compression = value.parse()?,
+            _ => self.encoding.tiff_compression = value.parse()?,
+        }
+        Ok(())
+    }
+}
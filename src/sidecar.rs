@@ -0,0 +1,212 @@
+use anyhow::Result;
+use opencv::{
+    core::{Mat, Rect, Size, Vector},
+    types::VectorOfPoint2f,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+use crate::crop::{CropParameters, CropResult};
+use crate::marker_utils::marker_processing::{
+    markers_to_arrays, matrix_to_array, points_to_working, Interpolation, MarkersVec,
+};
+
+/// Everything needed to trace back (and reproduce) how an output crop was made.
+/// Corners and source points are expressed in the coordinates of the original input
+/// image, before any upscaling done by `resize_if_larger_dims`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CropSidecar {
+    pub input_path: String,
+    pub input_sha256: String,
+    pub marker_ids: Vec<i32>,
+    pub marker_corners: Vec<[[f32; 2]; 4]>,
+    /// Specimen ID of the QR code on the card, missing in the sidecars of older versions
+    pub card_id: Option<String>,
+    /// Reference points (one corner per marker, ordered #0 to #3) used for the correction
+    pub source_points: [[f32; 2]; 4],
+    /// Width and height ratios applied to the input before detection and warping
+    pub working_scale: [f64; 2],
+    /// Perspective transform from the working image to the output image
+    pub homography: [[f64; 3]; 3],
+    pub out_size: [i32; 2],
+    pub zoom: f32,
+    /// Crop window (x, y, width, height) applied to the warped image
+    pub crop_window: [i32; 4],
+    pub interpolation: String,
+    pub tool_version: String,
+    pub timestamp: String,
+}
+
+impl CropSidecar {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        input_path: &Path,
+        markers_coor: &MarkersVec,
+        markers_id: &Vector<i32>,
+        ordered_points: &VectorOfPoint2f,
+        working_scale: (f64, f64),
+        homography: &Mat,
+        out_size: &Size,
+        zoom: f32,
+        crop_window: &Rect,
+        interpolation: Interpolation,
+    ) -> Result<Self> {
+        let (width_ratio, height_ratio) = working_scale;
+        let to_input_coor = |x: f32, y: f32| -> [f32; 2] {
+            [
+                (x as f64 / width_ratio) as f32,
+                (y as f64 / height_ratio) as f32,
+            ]
+        };
+
+        let marker_corners = markers_to_arrays(markers_coor, working_scale);
+
+        let mut source_points = [[0f32; 2]; 4];
+        for (source, point) in source_points.iter_mut().zip(ordered_points.iter()) {
+            *source = to_input_coor(point.x, point.y);
+        }
+
+        Ok(CropSidecar {
+            input_path: CropSidecar::stored_input_path(input_path)?,
+            input_sha256: CropSidecar::hash_file(input_path)?,
+            marker_ids: markers_id.to_vec(),
+            marker_corners,
+            card_id: None,
+            source_points,
+            working_scale: [width_ratio, height_ratio],
+            homography: matrix_to_array(homography)?,
+            out_size: [out_size.width, out_size.height],
+            zoom,
+            crop_window: [
+                crop_window.x,
+                crop_window.y,
+                crop_window.width,
+                crop_window.height,
+            ],
+            interpolation: interpolation.to_string(),
+            tool_version: String::from(env!("CARGO_PKG_VERSION")),
+            timestamp: chrono::Local::now().to_rfc3339(),
+        })
+    }
+
+    /// Sidecar of a crop made by `crop::crop_image`
+    pub fn from_crop(
+        input_path: &Path,
+        crop: &CropResult,
+        parameters: &CropParameters,
+    ) -> Result<Self> {
+        let crop_window = crop.crop_window;
+        Ok(CropSidecar {
+            input_path: CropSidecar::stored_input_path(input_path)?,
+            input_sha256: CropSidecar::hash_file(input_path)?,
+            marker_ids: crop.marker_ids.clone(),
+            marker_corners: crop.marker_corners.clone(),
+            card_id: crop.card_id.clone(),
+            source_points: crop.source_points,
+            working_scale: [crop.working_scale.0, crop.working_scale.1],
+            homography: matrix_to_array(&crop.homography)?,
+            out_size: [parameters.out_size.width, parameters.out_size.height],
+            zoom: parameters.zoom,
+            crop_window: [
+                crop_window.x,
+                crop_window.y,
+                crop_window.width,
+                crop_window.height,
+            ],
+            interpolation: parameters.interpolation.to_string(),
+            tool_version: String::from(env!("CARGO_PKG_VERSION")),
+            timestamp: chrono::Local::now().to_rfc3339(),
+        })
+    }
+
+    /// Stored as is so that rerender finds the same file, a lossy path would point elsewhere
+    fn stored_input_path(input_path: &Path) -> Result<String> {
+        Ok(input_path
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("The input path {input_path:?} is not valid UTF-8, it cannot be stored in a sidecar"))?
+            .to_string())
+    }
+
+    pub fn with_card_id(mut self, card_id: Option<String>) -> Self {
+        self.card_id = card_id;
+        self
+    }
+
+    /// Copy of a stored sidecar for a new rendering of the same input, reusing the stored
+    /// markers and source points instead of running the detection again.
+    pub fn rerendered(
+        &self,
+        working_scale: (f64, f64),
+        homography: &Mat,
+        out_size: &Size,
+        zoom: f32,
+        crop_window: &Rect,
+        interpolation: Interpolation,
+    ) -> Result<Self> {
+        let mut sidecar = self.clone();
+        sidecar.working_scale = [working_scale.0, working_scale.1];
+        sidecar.homography = matrix_to_array(homography)?;
+        sidecar.out_size = [out_size.width, out_size.height];
+        sidecar.zoom = zoom;
+        sidecar.crop_window = [
+            crop_window.x,
+            crop_window.y,
+            crop_window.width,
+            crop_window.height,
+        ];
+        sidecar.interpolation = interpolation.to_string();
+        sidecar.tool_version = String::from(env!("CARGO_PKG_VERSION"));
+        sidecar.timestamp = chrono::Local::now().to_rfc3339();
+        Ok(sidecar)
+    }
+
+    /// Stored source points scaled to a working image resized by `working_scale`
+    pub fn scaled_source_points(&self, working_scale: (f64, f64)) -> VectorOfPoint2f {
+        points_to_working(&self.source_points, working_scale)
+    }
+
+    /// Stored input path, looked up next to the sidecar if it does not exist as is
+    /// (e.g. relative path and sidecar read from another working directory)
+    pub fn resolve_input_path(&self, sidecar_path: &Path) -> PathBuf {
+        let input_path = PathBuf::from(&self.input_path);
+        if input_path.is_file() || input_path.is_absolute() {
+            return input_path;
+        }
+        match sidecar_path.parent() {
+            Some(sidecar_dir) if sidecar_dir.join(&input_path).is_file() => {
+                sidecar_dir.join(input_path)
+            }
+            Some(sidecar_dir) => match input_path.file_name() {
+                Some(filename) if sidecar_dir.join(filename).is_file() => {
+                    sidecar_dir.join(filename)
+                }
+                _ => input_path,
+            },
+            None => input_path,
+        }
+    }
+
+    pub fn hash_file(path: &Path) -> Result<String> {
+        let bytes = std::fs::read(path)?;
+        Ok(format!("{:x}", Sha256::digest(bytes)))
+    }
+
+    /// The sidecar of `crop.png` is `crop.png.json`
+    pub fn sidecar_path(output_path: &Path) -> PathBuf {
+        let mut sidecar_path = output_path.as_os_str().to_owned();
+        sidecar_path.push(".json");
+        PathBuf::from(sidecar_path)
+    }
+
+    pub fn write(&self, output_path: &Path) -> Result<PathBuf> {
+        let sidecar_path = CropSidecar::sidecar_path(output_path);
+        std::fs::write(&sidecar_path, serde_json::to_string_pretty(self)?)?;
+        Ok(sidecar_path)
+    }
+
+    pub fn read(sidecar_path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(sidecar_path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+}
@@ -0,0 +1,505 @@
+use anyhow::Result;
+use opencv::{
+    core::{
+        self, Mat, Point2f, Rect, Scalar, Size, Vector, BORDER_DEFAULT, BORDER_TRANSPARENT,
+        DECOMP_LU,
+    },
+    imgproc,
+    objdetect::ArucoDetector,
+    prelude::*,
+    types::VectorOfPoint2f,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::card_generator::{marker_rects, render_card};
+use idmybee::card_layout::CardLayout;
+use idmybee::crop::get_reference_points;
+use idmybee::marker_utils::marker_processing::{
+    detect_markers, get_correction_matrix, matrix_to_array,
+};
+
+/// Ranges of the distortions drawn for each synthetic photo, from none to the given maximum
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntheticOptions {
+    pub image_size: Size,
+    /// Resolution of the card before it is warped into the photo
+    pub card_dpi: f64,
+    pub margin_mm: f64,
+    pub max_rotation_deg: f64,
+    /// Card width over photo width, minimum and maximum
+    pub scale_range: (f64, f64),
+    /// Random shift of each card corner, as a fraction of the card width
+    pub max_perspective: f64,
+    /// Sigma of the Gaussian blur in pixels
+    pub max_blur: f64,
+    /// Sigma of the Gaussian noise in levels (0 to 255)
+    pub max_noise: f64,
+    pub max_glare_spots: usize,
+    /// Brightness change across the photo, e.g. 0.4 for a side 40 % darker than the other
+    pub max_gradient: f64,
+    /// Probability that a marker is partly hidden
+    pub occlusion_probability: f64,
+}
+
+impl Default for SyntheticOptions {
+    fn default() -> Self {
+        SyntheticOptions {
+            image_size: Size::new(1600, 1200),
+            card_dpi: 600.,
+            margin_mm: 2.,
+            max_rotation_deg: 180.,
+            scale_range: (0.3, 0.7),
+            max_perspective: 0.15,
+            max_blur: 2.,
+            max_noise: 8.,
+            max_glare_spots: 2,
+            max_gradient: 0.4,
+            occlusion_probability: 0.1,
+        }
+    }
+}
+
+/// Expected answers for a synthetic photo, written next to it as JSON. Points are in pixels of
+/// the photo with the pixel centers at integer coordinates, like the OpenCV detector.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SyntheticTruth {
+    pub image: String,
+    pub seed: u64,
+    pub card_layout: String,
+    /// Outer corners of markers #0 to #3, what `get_reference_points` should find (and what
+    /// 'crop --points' takes)
+    pub source_points: [[f32; 2]; 4],
+    pub marker_ids: Vec<i32>,
+    pub marker_corners: Vec<[[f32; 2]; 4]>,
+    /// Markers partly hidden, the detection may fail
+    pub occluded_ids: Vec<i32>,
+    /// Perspective transform from the card of `render_card` to the photo
+    pub card_to_image: [[f64; 3]; 3],
+    pub rotation_deg: f64,
+    pub scale: f64,
+    pub blur_sigma: f64,
+    pub noise_sigma: f64,
+    pub glare_spots: usize,
+    pub gradient: f64,
+}
+
+pub struct SyntheticCard {
+    pub image: Mat,
+    pub truth: SyntheticTruth,
+}
+
+/// Small deterministic generator (SplitMix64), the same seed gives the same photo whatever the
+/// OpenCV version
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn range(&mut self, min: f64, max: f64) -> f64 {
+        min + (max - min) * self.next_f64()
+    }
+
+    /// Standard normal, Box-Muller
+    fn gaussian(&mut self) -> f64 {
+        let u = 1. - self.next_f64();
+        let v = self.next_f64();
+        (-2. * u.ln()).sqrt() * (2. * std::f64::consts::PI * v).cos()
+    }
+}
+
+struct GlareSpot {
+    x: f64,
+    y: f64,
+    radius: f64,
+    intensity: f64,
+}
+
+/// Photo of the card of `layout` under random perspective, rotation, scale, occlusion,
+/// lighting gradient, glare, blur and noise, with the expected marker positions
+pub fn render_synthetic(
+    layout: &CardLayout,
+    options: &SyntheticOptions,
+    seed: u64,
+) -> Result<SyntheticCard> {
+    let mut rng = SplitMix64(seed);
+    let gray_card = render_card(layout, options.card_dpi, options.margin_mm)?;
+    let mut card = Mat::default();
+    imgproc::cvt_color(&gray_card, &mut card, imgproc::COLOR_GRAY2BGR, 0)?;
+    let rects = marker_rects(layout, options.card_dpi, options.margin_mm);
+
+    // Something lying on the card (a leg, a wing, a finger) hides a side of a marker
+    let mut occluded_ids = vec![];
+    for (id, rect) in rects.iter().enumerate() {
+        if rng.next_f64() >= options.occlusion_probability {
+            continue;
+        }
+        let depth = rng.range(0.3, 1.) * rect.width as f64;
+        let depth = depth.round().max(1.) as i32;
+        let hidden = match rng.next_u64() % 4 {
+            0 => Rect::new(rect.x, rect.y, depth, rect.height),
+            1 => Rect::new(rect.x + rect.width - depth, rect.y, depth, rect.height),
+            2 => Rect::new(rect.x, rect.y, rect.width, depth),
+            _ => Rect::new(rect.x, rect.y + rect.height - depth, rect.width, depth),
+        };
+        let shade = rng.range(0., 255.);
+        imgproc::rectangle(
+            &mut card,
+            hidden,
+            Scalar::new(shade, shade * 0.9, shade * 0.8, 0.),
+            imgproc::FILLED,
+            imgproc::LINE_8,
+            0,
+        )?;
+        occluded_ids.push(id as i32);
+    }
+
+    // Card corners rotated and scaled around the photo center, then moved apart independently
+    let (card_width, card_height) = (card.cols() as f64, card.rows() as f64);
+    let (image_width, image_height) = (
+        options.image_size.width as f64,
+        options.image_size.height as f64,
+    );
+    let scale = rng.range(options.scale_range.0, options.scale_range.1) * image_width / card_width;
+    let rotation_deg = rng.range(-options.max_rotation_deg, options.max_rotation_deg);
+    let (sin, cos) = rotation_deg.to_radians().sin_cos();
+    let jitter = options.max_perspective * card_width * scale;
+    let mut corners: Vec<(f64, f64)> = [
+        (0., 0.),
+        (card_width, 0.),
+        (card_width, card_height),
+        (0., card_height),
+    ]
+    .iter()
+    .map(|(x, y)| {
+        let (x, y) = (
+            (x - card_width / 2.) * scale,
+            (y - card_height / 2.) * scale,
+        );
+        (
+            image_width / 2. + x * cos - y * sin + rng.range(-jitter, jitter),
+            image_height / 2. + x * sin + y * cos + rng.range(-jitter, jitter),
+        )
+    })
+    .collect();
+    // Anywhere in the photo as long as the whole card is visible
+    let min_x = corners.iter().map(|c| c.0).fold(f64::INFINITY, f64::min);
+    let max_x = corners
+        .iter()
+        .map(|c| c.0)
+        .fold(f64::NEG_INFINITY, f64::max);
+    let min_y = corners.iter().map(|c| c.1).fold(f64::INFINITY, f64::min);
+    let max_y = corners
+        .iter()
+        .map(|c| c.1)
+        .fold(f64::NEG_INFINITY, f64::max);
+    let shift = |min: f64, max: f64, size: f64, rng: &mut SplitMix64| match max - min < size {
+        true => rng.range(-min, size - max),
+        false => (size - min - max) / 2.,
+    };
+    let (shift_x, shift_y) = (
+        shift(min_x, max_x, image_width, &mut rng),
+        shift(min_y, max_y, image_height, &mut rng),
+    );
+    corners
+        .iter_mut()
+        .for_each(|c| *c = (c.0 + shift_x, c.1 + shift_y));
+
+    // The card edges lie half a pixel outside its first and last pixel centers
+    let card_points: VectorOfPoint2f = [
+        (0., 0.),
+        (card_width, 0.),
+        (card_width, card_height),
+        (0., card_height),
+    ]
+    .iter()
+    .map(|(x, y)| Point2f::new(*x as f32 - 0.5, *y as f32 - 0.5))
+    .collect();
+    let image_points: VectorOfPoint2f = corners
+        .iter()
+        .map(|(x, y)| Point2f::new(*x as f32, *y as f32))
+        .collect();
+    let card_to_image = imgproc::get_perspective_transform(&card_points, &image_points, DECOMP_LU)?;
+
+    let background = Scalar::new(
+        rng.range(30., 200.),
+        rng.range(30., 200.),
+        rng.range(30., 200.),
+        0.,
+    );
+    let mut image = Mat::new_size_with_default(options.image_size, card.typ(), background)?;
+    imgproc::warp_perspective(
+        &card,
+        &mut image,
+        &card_to_image,
+        options.image_size,
+        imgproc::INTER_LINEAR,
+        BORDER_TRANSPARENT,
+        Scalar::default(),
+    )?;
+
+    // Lighting gradient and glare spots, before the blur of the lens
+    let gradient = rng.range(-options.max_gradient, options.max_gradient);
+    let (gradient_sin, gradient_cos) = rng.range(0., 2. * std::f64::consts::PI).sin_cos();
+    let diagonal = image_width.hypot(image_height);
+    let glare_count = (rng.next_u64() % (options.max_glare_spots as u64 + 1)) as usize;
+    let spots: Vec<GlareSpot> = (0..glare_count)
+        .map(|_| GlareSpot {
+            x: rng.range(min_x, max_x) + shift_x,
+            y: rng.range(min_y, max_y) + shift_y,
+            radius: rng.range(0.02, 0.1) * diagonal,
+            intensity: rng.range(0.3, 0.9),
+        })
+        .collect();
+    let cols = image.cols() as usize;
+    for (i, pixel) in image.data_bytes_mut()?.chunks_exact_mut(3).enumerate() {
+        let (x, y) = ((i % cols) as f64, (i / cols) as f64);
+        let along = (x - image_width / 2.) * gradient_cos + (y - image_height / 2.) * gradient_sin;
+        let gain = 1. + gradient * along / diagonal;
+        let glare: f64 = spots
+            .iter()
+            .map(|spot| {
+                spot.intensity
+                    * (-((x - spot.x).powi(2) + (y - spot.y).powi(2))
+                        / (2. * spot.radius * spot.radius))
+                        .exp()
+            })
+            .sum();
+        for channel in pixel.iter_mut() {
+            *channel = (*channel as f64 * gain + 255. * glare)
+                .round()
+                .clamp(0., 255.) as u8;
+        }
+    }
+
+    let blur_sigma = rng.range(0., options.max_blur);
+    if blur_sigma > 0.1 {
+        let mut blurred = Mat::default();
+        imgproc::gaussian_blur(
+            &image,
+            &mut blurred,
+            Size::default(),
+            blur_sigma,
+            blur_sigma,
+            BORDER_DEFAULT,
+        )?;
+        image = blurred;
+    }
+
+    // Sensor noise comes last
+    let noise_sigma = rng.range(0., options.max_noise);
+    if noise_sigma > 0. {
+        for channel in image.data_bytes_mut()?.iter_mut() {
+            *channel = (*channel as f64 + rng.gaussian() * noise_sigma)
+                .round()
+                .clamp(0., 255.) as u8;
+        }
+    }
+
+    // Corners in the order of the ArUco detector: top left, top right, bottom right, bottom left
+    let mut marker_corners = vec![];
+    for rect in rects.iter() {
+        let (x, y) = (rect.x as f32 - 0.5, rect.y as f32 - 0.5);
+        let (width, height) = (rect.width as f32, rect.height as f32);
+        let corners: VectorOfPoint2f = Vector::from_slice(&[
+            Point2f::new(x, y),
+            Point2f::new(x + width, y),
+            Point2f::new(x + width, y + height),
+            Point2f::new(x, y + height),
+        ]);
+        let mut transformed = VectorOfPoint2f::new();
+        core::perspective_transform(&corners, &mut transformed, &card_to_image)?;
+        let mut array = [[0f32; 2]; 4];
+        for (corner, point) in array.iter_mut().zip(transformed.iter()) {
+            *corner = [point.x, point.y];
+        }
+        marker_corners.push(array);
+    }
+    let source_points = [0, 1, 2, 3].map(|id| marker_corners[id][id]);
+
+    Ok(SyntheticCard {
+        image,
+        truth: SyntheticTruth {
+            image: String::new(),
+            seed,
+            card_layout: layout.name.clone(),
+            source_points,
+            marker_ids: vec![0, 1, 2, 3],
+            marker_corners,
+            occluded_ids,
+            card_to_image: matrix_to_array(&card_to_image)?,
+            rotation_deg,
+            scale,
+            blur_sigma,
+            noise_sigma,
+            glare_spots: glare_count,
+            gradient,
+        },
+    })
+}
+
+/// Result of the detection on a synthetic photo compared to its truth
+#[derive(Serialize, Debug, Clone)]
+pub struct SyntheticCheck {
+    pub image: String,
+    pub detected: bool,
+    /// Largest distance between a detected and an expected outer corner, in pixels of the photo
+    pub max_corner_error: Option<f64>,
+    /// Largest displacement of the expected outer corners in the crop made from the detected
+    /// ones, in pixels of the output
+    pub max_output_error: Option<f64>,
+    pub passed: bool,
+    pub error: Option<String>,
+}
+
+/// Detects the markers of `sample` like 'crop' does and measures how far the result is from the
+/// truth. Photos without an occluded marker must be detected, every detection must be within
+/// `tolerance_px` in the photo and in the output.
+pub fn check_synthetic(
+    detector: &ArucoDetector,
+    sample: &SyntheticCard,
+    out_size: &Size,
+    zoom: f32,
+    tolerance_px: f64,
+) -> Result<SyntheticCheck> {
+    let truth = &sample.truth;
+    let (markers_coor, markers_id, rejected_markers) = detect_markers(detector, &sample.image)?;
+    let detected = match get_reference_points(&markers_coor, &markers_id, &rejected_markers) {
+        Ok(points) => points,
+        Err(err) => {
+            return Ok(SyntheticCheck {
+                image: truth.image.clone(),
+                detected: false,
+                max_corner_error: None,
+                max_output_error: None,
+                passed: !truth.occluded_ids.is_empty(),
+                error: Some(format!("{err:#}")),
+            })
+        }
+    };
+    let expected: VectorOfPoint2f = truth
+        .source_points
+        .iter()
+        .map(|[x, y]| Point2f::new(*x, *y))
+        .collect();
+    let max_corner_error = detected
+        .iter()
+        .zip(expected.iter())
+        .map(|(a, b)| ((a.x - b.x) as f64).hypot((a.y - b.y) as f64))
+        .fold(0., f64::max);
+
+    // Where the expected corners land with the detected transform, against the exact transform
+    let detected_transform = get_correction_matrix(&detected, out_size, &zoom)?;
+    let expected_transform = get_correction_matrix(&expected, out_size, &zoom)?;
+    let mut with_detected = VectorOfPoint2f::new();
+    let mut with_expected = VectorOfPoint2f::new();
+    core::perspective_transform(&expected, &mut with_detected, &detected_transform)?;
+    core::perspective_transform(&expected, &mut with_expected, &expected_transform)?;
+    let max_output_error = with_detected
+        .iter()
+        .zip(with_expected.iter())
+        .map(|(a, b)| ((a.x - b.x) as f64).hypot((a.y - b.y) as f64))
+        .fold(0., f64::max);
+
+    Ok(SyntheticCheck {
+        image: truth.image.clone(),
+        detected: true,
+        max_corner_error: Some(max_corner_error),
+        max_output_error: Some(max_output_error),
+        passed: max_corner_error <= tolerance_px && max_output_error <= tolerance_px,
+        error: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use idmybee::crop::{crop_image, CropParameters};
+    use idmybee::marker_utils::marker_processing::{
+        new_marker_detector, points_to_working, Interpolation,
+    };
+
+    /// Moderate distortions, every marker visible: the detection must always succeed
+    fn clean_options() -> SyntheticOptions {
+        SyntheticOptions {
+            image_size: Size::new(1200, 900),
+            max_rotation_deg: 30.,
+            scale_range: (0.4, 0.6),
+            max_perspective: 0.08,
+            max_blur: 1.,
+            max_noise: 4.,
+            max_glare_spots: 0,
+            max_gradient: 0.2,
+            occlusion_probability: 0.,
+            ..SyntheticOptions::default()
+        }
+    }
+
+    #[test]
+    fn synthetic_cards_are_detected_within_tolerance() {
+        let detector = new_marker_detector().unwrap();
+        let layout = CardLayout::default();
+        let (out_size, zoom) = (Size::new(600, 400), 1.2);
+        for seed in 0..4 {
+            let sample = render_synthetic(&layout, &clean_options(), seed).unwrap();
+            let check = check_synthetic(&detector, &sample, &out_size, zoom, 1.5).unwrap();
+            assert!(check.detected, "seed {seed}: {:?}", check.error);
+            assert!(
+                check.passed,
+                "seed {seed}: corner error {:?}, output error {:?}",
+                check.max_corner_error, check.max_output_error
+            );
+        }
+    }
+
+    #[test]
+    fn synthetic_crop_has_the_card_scale() {
+        let detector = new_marker_detector().unwrap();
+        let layout = CardLayout::default();
+        let parameters = CropParameters {
+            out_size: Size::new(600, 400),
+            zoom: 1.2,
+            crop_window: None,
+            interpolation: Interpolation::Lanczos4,
+        };
+        let sample = render_synthetic(&layout, &clean_options(), 7).unwrap();
+        let truth = sample.truth.clone();
+        let result = crop_image(&detector, sample.image, &parameters).unwrap();
+
+        for (detected, expected) in result.source_points.iter().zip(truth.source_points.iter()) {
+            let error =
+                ((detected[0] - expected[0]) as f64).hypot((detected[1] - expected[1]) as f64);
+            assert!(
+                error <= 1.5,
+                "corner {detected:?} is {error:.2} px from {expected:?}"
+            );
+        }
+
+        // The exact outer corners of the card, in pixels of the crop
+        let mut in_crop = VectorOfPoint2f::new();
+        let expected_working = points_to_working(&truth.source_points, result.working_scale);
+        core::perspective_transform(&expected_working, &mut in_crop, &result.homography).unwrap();
+        let distance = |a: Point2f, b: Point2f| ((a.x - b.x) as f64).hypot((a.y - b.y) as f64);
+        let measured = (
+            distance(in_crop.get(0).unwrap(), in_crop.get(1).unwrap()) / layout.ref_width_mm,
+            distance(in_crop.get(0).unwrap(), in_crop.get(3).unwrap()) / layout.ref_height_mm,
+        );
+        let expected = layout.px_per_mm(&parameters.out_size, parameters.zoom);
+        for (measured, expected) in [(measured.0, expected.0), (measured.1, expected.1)] {
+            assert!(
+                (measured / expected - 1.).abs() < 0.01,
+                "{measured:.3} px/mm instead of {expected:.3}"
+            );
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use anyhow::Result;
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+
+pub const FAILED_DIRNAME: &str = "failed";
+
+/// Empty files must stay empty for this many settle durations before they are processed
+const EMPTY_FILE_SETTLE_FACTOR: u32 = 5;
+
+/// File seen by the watcher but maybe still being written (e.g. by a sync client)
+struct PendingFile {
+    size: u64,
+    last_change: Instant,
+}
+
+fn log_event(message: &str) {
+    log::info!(
+        "[{}] {}",
+        chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+        message
+    );
+}
+
+fn is_watched_image(path: &Path, extensions: &[&str], ignored_dirs: &[PathBuf]) -> bool {
+    let extension = path
+        .extension()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_lowercase();
+    extensions.contains(&extension.as_str())
+        && !ignored_dirs.iter().any(|dir| path.starts_with(dir))
+        // Temporary files of sync clients and editors
+        && !path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .starts_with('.')
+}
+
+/// Path in `dir` named like `path` that does not exist yet: 'IMG_1.jpg', then 'IMG_1_1.jpg',
+/// 'IMG_1_2.jpg'...
+fn unused_path(path: &Path, dir: &Path) -> PathBuf {
+    let destination = dir.join(path.file_name().unwrap_or_default());
+    if !destination.exists() {
+        return destination;
+    }
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = match path.extension() {
+        Some(extension) => format!(".{}", extension.to_string_lossy()),
+        None => String::new(),
+    };
+    (1..)
+        .map(|index| dir.join(format!("{stem}_{index}{extension}")))
+        .find(|destination| !destination.exists())
+        .unwrap_or(destination)
+}
+
+/// Moves a file that could not be processed into the `failed/` subfolder of the watched
+/// directory, without overwriting a previous failure with the same name (e.g. from a subfolder)
+fn move_to_failed(path: &Path, failed_dir: &Path) -> Result<PathBuf> {
+    std::fs::create_dir_all(failed_dir)?;
+    let destination = unused_path(path, failed_dir);
+    if std::fs::rename(path, &destination).is_err() {
+        // rename does not work across file systems
+        std::fs::copy(path, &destination)?;
+        std::fs::remove_file(path)?;
+    }
+    Ok(destination)
+}
+
+/// Calls `process` on every image created in `dir` once its size has not changed for `settle`.
+/// Images that fail are moved to `dir/failed/`. `ignored_dirs` (e.g. the output directory when
+/// it is inside `dir`) are not watched. Runs until the process is killed.
+pub fn watch_dir<F>(
+    dir: &Path,
+    recursive: bool,
+    extensions: &[&str],
+    ignored_dirs: &[PathBuf],
+    settle: Duration,
+    mut process: F,
+) -> Result<()>
+where
+    F: FnMut(&Path) -> Result<()>,
+{
+    let dir = dunce::canonicalize(dir)?;
+    let failed_dir = dir.join(FAILED_DIRNAME);
+    let mut ignored_dirs: Vec<PathBuf> = ignored_dirs
+        .iter()
+        .map(|ignored| dunce::canonicalize(ignored).unwrap_or(ignored.clone()))
+        .collect();
+    ignored_dirs.push(failed_dir.clone());
+
+    let (sender, receiver) = channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    watcher.watch(
+        &dir,
+        match recursive {
+            true => RecursiveMode::Recursive,
+            false => RecursiveMode::NonRecursive,
+        },
+    )?;
+    log_event(&format!(
+        "Watching {:?} for new images (Ctrl-C to stop)",
+        dir
+    ));
+
+    let mut pending: HashMap<PathBuf, PendingFile> = HashMap::new();
+    loop {
+        match receiver.recv_timeout(Duration::from_millis(500)) {
+            Ok(Ok(event)) => {
+                if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    for path in event.paths {
+                        if is_watched_image(&path, extensions, &ignored_dirs) {
+                            pending.insert(
+                                path,
+                                PendingFile {
+                                    size: 0,
+                                    last_change: Instant::now(),
+                                },
+                            );
+                        }
+                    }
+                }
+            }
+            Ok(Err(err)) => log_event(&format!("Watch error: {err}")),
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(anyhow::anyhow!("The file watcher stopped unexpectedly"))
+            }
+        }
+
+        // A file is considered complete once its size is stable for `settle`
+        let mut ready = Vec::new();
+        pending.retain(|path, file| {
+            let Ok(metadata) = std::fs::metadata(path) else {
+                // Removed or renamed before being complete
+                return false;
+            };
+            if metadata.len() != file.size {
+                file.size = metadata.len();
+                file.last_change = Instant::now();
+                return true;
+            }
+            // Files are often created empty and filled later, an empty one gets more time before
+            // it is processed (and fails to decode, which reports it)
+            let wait = match file.size {
+                0 => settle * EMPTY_FILE_SETTLE_FACTOR,
+                _ => settle,
+            };
+            if file.last_change.elapsed() < wait {
+                return true;
+            }
+            ready.push(path.clone());
+            false
+        });
+        ready.sort();
+
+        for path in ready {
+            match process(&path) {
+                Ok(()) => log_event(&format!("Processed {:?}", path)),
+                Err(err) => {
+                    let reason = err.to_string();
+                    log_event(&format!(
+                        "Failed to process {:?}: {}",
+                        path,
+                        reason.lines().next().unwrap_or_default()
+                    ));
+                    match move_to_failed(&path, &failed_dir) {
+                        Ok(destination) => log_event(&format!("Moved to {:?}", destination)),
+                        Err(err) => log_event(&format!(
+                            "Could not move {:?} to {:?}: {}",
+                            path, failed_dir, err
+                        )),
+                    }
+                }
+            }
+        }
+    }
+}